@@ -12,7 +12,7 @@ function generate_did() {
   candid-extractor "target/wasm32-unknown-unknown/release/$canister.wasm" > "$canister_root/$canister.did"
 }
 
-CANISTERS=pok_backend
+CANISTERS=pok_backend,mock_target
 
 for canister in $(echo $CANISTERS | sed "s/,/ /g")
 do
@@ -0,0 +1,61 @@
+#[macro_use]
+extern crate serde;
+use std::cell::RefCell;
+
+use candid::Principal;
+use ic_cdk::api::time;
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+struct Call {
+    caller: Principal,
+    agreement_id: u64,
+    action: String,
+    at: u64,
+}
+
+thread_local! {
+    static CALLS: RefCell<Vec<Call>> = RefCell::new(vec![]);
+    static FAILURES_LEFT: RefCell<u32> = RefCell::new(0);
+}
+
+// Mock functions here
+
+/// The method an agreement's execution hook calls. Rejects while failures are queued.
+#[ic_cdk::update]
+fn execute(agreement_id: u64, action: String) -> u64 {
+    let failing = FAILURES_LEFT.with(|failures| {
+        let mut failures = failures.borrow_mut();
+        if *failures > 0 {
+            *failures -= 1;
+            true
+        } else {
+            false
+        }
+    });
+    if failing {
+        ic_cdk::trap("mock_target was told to fail this call");
+    }
+    CALLS.with(|calls| {
+        let mut calls = calls.borrow_mut();
+        calls.push(Call {
+            caller: ic_cdk::caller(),
+            agreement_id,
+            action,
+            at: time(),
+        });
+        calls.len() as u64
+    })
+}
+
+/// Makes the next `count` calls to `execute` fail, to exercise retries.
+#[ic_cdk::update]
+fn fail_next(count: u32) {
+    FAILURES_LEFT.with(|failures| *failures.borrow_mut() = count);
+}
+
+#[ic_cdk::query]
+fn get_calls() -> Vec<Call> {
+    CALLS.with(|calls| calls.borrow().clone())
+}
+
+ic_cdk::export_candid!();
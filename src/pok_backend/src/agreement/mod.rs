@@ -1,11 +1,28 @@
 use std::borrow::Cow;
 
+use crate::attachment::AttachmentRef;
+use crate::commitment::Commitment;
+use crate::dispute::Dispute;
+use crate::escrow::{Payment, PaymentRequest};
+use crate::execution::{ExecutionHook, ExecutionRequest};
+use crate::history::{AgreementEvent, EventKind};
+use crate::lamport::{PublicKey, SecurityLevel};
+use crate::obligation::{Obligation, ObligationRequest};
+use crate::signature::{Signature, SignerMetadata};
+use crate::template::TemplateRef;
+use crate::timestamp::Timestamp;
 use crate::user::User;
-use crate::{lamport::PublicKey, signature::Signature};
+use crate::verification::CachedVerification;
+use crate::visibility::Visibility;
+use crate::witness::{Witness, WitnessRequest};
+use crate::Error;
 use candid::{Decode, Encode};
 use chrono::prelude::*;
 use ic_stable_structures::{BoundedStorable, Storable};
 
+pub const MAX_TAG_SIZE: usize = 64;
+const MAX_TAGS: usize = 16;
+
 #[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
 pub struct Agreement {
     pub terms: Vec<String>,
@@ -15,10 +32,274 @@ pub struct Agreement {
     pub proof_of_agreement: Option<ProofOfAgreement>,
     pub public_keys: Option<PublicKeys>,
     pub id: u64,
+    pub template: Option<TemplateRef>,
+    pub attachments: Option<Vec<AttachmentRef>>,
+    pub witnesses: Option<Vec<Witness>>,
+    pub tags: Option<Vec<String>>,
+    pub history: Option<Vec<AgreementEvent>>,
+    pub expires_at: Option<u64>,
+    pub execution: Option<ExecutionHook>,
+    pub tokens: Option<Vec<u64>>,
+    pub payment: Option<Payment>,
+    pub commitment: Option<Commitment>,
+    pub visibility: Option<Visibility>,
+    pub viewers: Option<Vec<User>>,
+    pub verification: Option<CachedVerification>,
+    /// Set on agreements with compact keys, which store a commitment to each
+    /// party's public key in place of `public_keys`.
+    pub key_commitments: Option<KeyCommitments>,
+    pub security_level: Option<SecurityLevel>,
+    /// When the agreement was drafted. `date` holds the same time as text on
+    /// every agreement, including those created before this field.
+    pub created_at: Option<Timestamp>,
+    /// Settles disputes once both parties signed. Named at creation, so the
+    /// parties sign who it is.
+    pub arbitrator: Option<User>,
+    pub dispute: Option<Dispute>,
+    pub obligations: Option<Vec<Obligation>>,
+}
+
+/// Orders pending agreements by the time they expire.
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, candid::CandidType, Serialize, Deserialize,
+)]
+pub struct ExpiryKey {
+    pub expires_at: u64,
+    pub agreement_id: u64,
+}
+
+/// Optional settings for a new agreement. Every field is optional so that callers
+/// which do not know about a setting can leave it out.
+#[derive(Clone, Debug, Default, candid::CandidType, Serialize, Deserialize)]
+pub struct InitiateOptions {
+    pub attachments: Option<Vec<u64>>,
+    pub witnesses: Option<Vec<WitnessRequest>>,
+    pub tags: Option<Vec<String>>,
+    pub expires_at: Option<u64>,
+    pub execution: Option<ExecutionRequest>,
+    pub payment: Option<PaymentRequest>,
+    /// Keeps the terms off-chain: the hex SHA-256 commitment the parties sign instead.
+    pub commitment: Option<String>,
+    pub visibility: Option<Visibility>,
+    pub viewers: Option<Vec<String>>,
+    /// Stores a 32-byte commitment to each party's public key instead of the key.
+    /// Defaults to true for 256-bit keys and false otherwise.
+    pub compact_keys: Option<bool>,
+    /// Defaults to 128 bits. 256-bit keys only fit as compact keys, and
+    /// passing `compact_keys: false` with them is rejected.
+    pub security_level: Option<SecurityLevel>,
+    /// Who rules on a dispute about the signed agreement. Cannot be a party or a witness.
+    pub arbitrator: Option<String>,
+    /// Deadlines the parties commit to, each tied to one of the terms.
+    pub obligations: Option<Vec<ObligationRequest>>,
 }
 
 pub type ProofOfAgreement = (Option<Signature>, Option<Signature>);
 pub type PublicKeys = (Option<PublicKey>, Option<PublicKey>);
+pub type KeyCommitments = (Option<String>, Option<String>);
+
+impl Agreement {
+    /// The message the parties sign: the terms (or the commitment to them), followed by the SHA-256 hash of
+    /// every attachment so the documents are bound into the signed digest, and
+    /// the execution hook, the escrowed payment, the arbitrator and the
+    /// obligations if there are any.
+    pub fn message(&self) -> String {
+        let mut message: String = String::new();
+        for term in self.terms.iter() {
+            message.push_str(term);
+        }
+        if let Some(commitment) = &self.commitment {
+            message.push_str(&commitment.value);
+        }
+        for attachment in self.attachments.iter().flatten() {
+            message.push_str(&attachment.sha256);
+        }
+        if let Some(execution) = &self.execution {
+            message.push_str(&execution.message());
+        }
+        if let Some(payment) = &self.payment {
+            message.push_str(&payment.message());
+        }
+        if let Some(arbitrator) = &self.arbitrator {
+            message.push_str(&arbitrator.identity);
+        }
+        for obligation in self.obligations.iter().flatten() {
+            message.push_str(&obligation.message());
+        }
+        message
+    }
+
+    pub fn is_initiator(&self, identity: &str) -> bool {
+        self.by_user.identity.trim() == identity.trim()
+    }
+
+    pub fn is_party(&self, identity: &str) -> bool {
+        self.is_initiator(identity) || self.with_user.identity.trim() == identity.trim()
+    }
+
+    /// Agreements without a visibility setting are only visible to their parties.
+    pub fn visibility(&self) -> Visibility {
+        self.visibility.clone().unwrap_or(Visibility::Parties)
+    }
+
+    pub fn can_read(&self, identity: &str) -> bool {
+        let identity = identity.trim();
+        if self.is_party(identity)
+            || self
+                .witnesses
+                .iter()
+                .flatten()
+                .any(|witness| witness.user.identity.trim() == identity)
+            || self
+                .arbitrator
+                .as_ref()
+                .map_or(false, |arbitrator| arbitrator.identity.trim() == identity)
+        {
+            return true;
+        }
+        match self.visibility() {
+            Visibility::Parties => false,
+            Visibility::PartiesAndViewers => self
+                .viewers
+                .iter()
+                .flatten()
+                .any(|viewer| viewer.identity.trim() == identity),
+            Visibility::Public => true,
+        }
+    }
+
+    pub fn security_level(&self) -> SecurityLevel {
+        self.security_level.clone().unwrap_or_default()
+    }
+
+    /// The agreement without its signatures and keys, as a signature embeds it
+    /// and as a counter-offer starts over.
+    pub fn unsigned(&self) -> Agreement {
+        Agreement {
+            proof_of_agreement: None,
+            public_keys: None,
+            key_commitments: self.key_commitments.as_ref().map(|_| (None, None)),
+            verification: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn has_compact_keys(&self) -> bool {
+        self.key_commitments.is_some()
+    }
+
+    pub fn is_fully_signed(&self) -> bool {
+        matches!(self.proof_of_agreement, Some((Some(_), Some(_))))
+    }
+
+    /// Both parties signed and every named witness attested.
+    pub fn is_complete(&self) -> bool {
+        self.is_fully_signed()
+            && self
+                .witnesses
+                .iter()
+                .flatten()
+                .all(|witness| witness.attestation.is_some())
+    }
+
+    /// An agreement expires when it is still missing a signature at its expiry time.
+    pub fn is_expired(&self, now: u64) -> bool {
+        !self.is_fully_signed()
+            && !self.is_terminated()
+            && self
+                .expires_at
+                .map_or(false, |expires_at| now >= expires_at)
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.history
+            .iter()
+            .flatten()
+            .any(|event| matches!(event.kind, EventKind::Terminated))
+    }
+
+    pub fn record(&mut self, event: AgreementEvent) {
+        self.history.get_or_insert_with(Vec::new).push(event);
+    }
+
+    /// Falls back to `date` for agreements created before `created_at`.
+    pub fn created_at_nanos(&self) -> Option<u64> {
+        match &self.created_at {
+            Some(created_at) => Some(created_at.nanos),
+            None => self.date.trim().parse().ok(),
+        }
+    }
+
+    /// When each party signed, in the order of `proof_of_agreement`.
+    pub fn signed_at(&self) -> (Option<Timestamp>, Option<Timestamp>) {
+        let (first, second) = self.proof_of_agreement.clone().unwrap_or((None, None));
+        (
+            first.and_then(|signature| signature.signed_at),
+            second.and_then(|signature| signature.signed_at),
+        )
+    }
+
+    /// Fails unless `party` is a party to the agreement whose signature is
+    /// still missing, so that nobody else can fill the counterparty's slot.
+    pub fn ensure_can_sign(&self, party: &str) -> Result<(), Error> {
+        if !self.is_party(party) {
+            return Err(Error::Unauthorized {
+                msg: format!("Only the parties to an agreement can sign it"),
+            });
+        }
+        let signed = match &self.proof_of_agreement {
+            Some((first, _)) if self.is_initiator(party) => first.is_some(),
+            Some((_, second)) => second.is_some(),
+            None => false,
+        };
+        if signed {
+            return Err(Error::InvalidInput {
+                msg: format!("{} has already signed that agreement", party.trim()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Records when and how `party` signed on the signature they just added.
+    pub fn stamp_signature(&mut self, party: &str, at: Timestamp, metadata: SignerMetadata) {
+        let initiator = self.is_initiator(party);
+        let signature = match self.proof_of_agreement.as_mut() {
+            Some((signature, _)) if initiator => signature.as_mut(),
+            Some((_, signature)) => signature.as_mut(),
+            None => None,
+        };
+        if let Some(signature) = signature {
+            signature.signed_at = Some(at);
+            signature.metadata = Some(metadata);
+        }
+    }
+}
+
+/// Trims and de-duplicates tags, rejecting empty or oversized ones.
+pub fn normalize_tags(tags: Vec<String>) -> Result<Option<Vec<String>>, Error> {
+    let mut normalized: Vec<String> = vec![];
+    for tag in tags.into_iter() {
+        let tag = tag.trim().to_string();
+        if tag.is_empty() || tag.len() > MAX_TAG_SIZE {
+            return Err(Error::InvalidInput {
+                msg: format!("A tag must be between 1 and {} bytes", MAX_TAG_SIZE),
+            });
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(Error::InvalidInput {
+            msg: format!("An agreement can have at most {} tags", MAX_TAGS),
+        });
+    }
+    Ok(if normalized.is_empty() {
+        None
+    } else {
+        Some(normalized)
+    })
+}
 
 impl Storable for Agreement {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
@@ -34,3 +315,51 @@ impl BoundedStorable for Agreement {
     const MAX_SIZE: u32 = 200000;
     const IS_FIXED_SIZE: bool = false;
 }
+
+impl Storable for ExpiryKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ExpiryKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::{Agree, CreateAgreement};
+
+    fn user(identity: &str) -> User {
+        User {
+            identity: identity.to_string(),
+        }
+    }
+
+    #[test]
+    fn only_a_party_who_has_not_signed_can_sign() {
+        let agreement = user("alice").new_agreement(
+            vec!["Alice sells Bob 10 chairs".to_string()],
+            String::from("0"),
+            user("bob"),
+            user("alice"),
+            1,
+        );
+        let agreement = user("alice").automatic_agreement(agreement);
+        assert!(matches!(
+            agreement.ensure_can_sign("mallory"),
+            Err(Error::Unauthorized { .. })
+        ));
+        assert!(agreement.ensure_can_sign("alice").is_err());
+        assert!(agreement.ensure_can_sign(" bob ").is_ok());
+
+        let agreement = user("bob").agree(agreement);
+        assert!(agreement.ensure_can_sign("bob").is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use std::borrow::Cow;
+
+use crate::user::User;
+use crate::Error;
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use sha2::{Digest, Sha256};
+
+pub const CHUNK_SIZE: u32 = 256 * 1024;
+pub const MAX_ATTACHMENT_SIZE: u64 = 32 * 1024 * 1024;
+const MAX_NAME_SIZE: usize = 256;
+const MAX_MIME_TYPE_SIZE: usize = 128;
+
+/// A document uploaded in chunks. The hash is only set once every chunk has
+/// been uploaded and the attachment is finalized, after which it can no longer change.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: u64,
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub sha256: Option<String>,
+    pub uploaded_by: User,
+}
+
+/// The part of an attachment that is embedded in an agreement and signed by the parties.
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub struct AttachmentRef {
+    pub id: u64,
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, candid::CandidType, Serialize, Deserialize,
+)]
+pub struct ChunkKey {
+    pub attachment_id: u64,
+    pub index: u32,
+}
+
+/// Records that an agreement embeds an attachment, so that reading the
+/// attachment can follow the visibility of those agreements.
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, candid::CandidType, Serialize, Deserialize,
+)]
+pub struct AttachmentUse {
+    pub attachment_id: u64,
+    pub agreement_id: u64,
+}
+
+/// Raw chunk bytes, stored without any encoding overhead.
+pub struct Chunk(pub Vec<u8>);
+
+impl Storable for Attachment {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Attachment {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ChunkKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ChunkKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for AttachmentUse {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AttachmentUse {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl AttachmentUse {
+    /// Every agreement that embeds the attachment.
+    pub fn range_of(attachment_id: u64) -> std::ops::RangeInclusive<AttachmentUse> {
+        AttachmentUse {
+            attachment_id,
+            agreement_id: 0,
+        }..=AttachmentUse {
+            attachment_id,
+            agreement_id: u64::MAX,
+        }
+    }
+}
+
+impl Storable for Chunk {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Chunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for Chunk {
+    const MAX_SIZE: u32 = CHUNK_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Attachment {
+    pub fn new(
+        id: u64,
+        name: String,
+        mime_type: String,
+        size: u64,
+        uploaded_by: User,
+    ) -> Result<Self, Error> {
+        if name.trim().is_empty() || name.len() > MAX_NAME_SIZE {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "An attachment name must be between 1 and {} bytes",
+                    MAX_NAME_SIZE
+                ),
+            });
+        }
+        if !is_valid_mime_type(&mime_type) {
+            return Err(Error::InvalidInput {
+                msg: format!("'{}' is not a valid MIME type", mime_type),
+            });
+        }
+        if size == 0 || size > MAX_ATTACHMENT_SIZE {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "An attachment must be between 1 and {} bytes",
+                    MAX_ATTACHMENT_SIZE
+                ),
+            });
+        }
+        Ok(Attachment {
+            id,
+            name,
+            mime_type,
+            size,
+            sha256: None,
+            uploaded_by,
+        })
+    }
+
+    pub fn chunk_count(&self) -> u32 {
+        self.size.div_ceil(CHUNK_SIZE as u64) as u32
+    }
+
+    /// The exact number of bytes the chunk at `index` must hold.
+    pub fn expected_chunk_size(&self, index: u32) -> Option<usize> {
+        let count = self.chunk_count();
+        if index >= count {
+            None
+        } else if index + 1 < count {
+            Some(CHUNK_SIZE as usize)
+        } else {
+            Some((self.size - (count as u64 - 1) * CHUNK_SIZE as u64) as usize)
+        }
+    }
+
+    pub fn is_finalized(&self) -> bool {
+        self.sha256.is_some()
+    }
+
+    pub fn reference(&self) -> Option<AttachmentRef> {
+        self.sha256.as_ref().map(|sha256| AttachmentRef {
+            id: self.id,
+            name: self.name.clone(),
+            mime_type: self.mime_type.clone(),
+            size: self.size,
+            sha256: sha256.clone(),
+        })
+    }
+}
+
+/// Hashes the chunks of an attachment in order. Returns `None` if a chunk is missing.
+pub fn hash_chunks<I>(attachment: &Attachment, chunks: I) -> Option<String>
+where
+    I: Fn(u32) -> Option<Chunk>,
+{
+    let mut hasher = Sha256::new();
+    for index in 0..attachment.chunk_count() {
+        let chunk = chunks(index)?;
+        hasher.update(&chunk.0);
+    }
+    Some(hex::encode(hasher.finalize()))
+}
+
+fn is_valid_mime_type(mime_type: &str) -> bool {
+    let mut parts = mime_type.splitn(2, '/');
+    let valid_token = |token: &str| {
+        !token.is_empty()
+            && token
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&^_.+-".contains(c))
+    };
+    match (parts.next(), parts.next()) {
+        (Some(kind), Some(subtype)) => {
+            mime_type.len() <= MAX_MIME_TYPE_SIZE && valid_token(kind) && valid_token(subtype)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attachment(size: u64) -> Attachment {
+        Attachment::new(
+            0,
+            String::from("spec.pdf"),
+            String::from("application/pdf"),
+            size,
+            User {
+                identity: String::from("amschel"),
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn splits_into_fixed_size_chunks() {
+        let single = attachment(10);
+        assert_eq!(single.chunk_count(), 1);
+        assert_eq!(single.expected_chunk_size(0), Some(10));
+        assert_eq!(single.expected_chunk_size(1), None);
+
+        let several = attachment(2 * CHUNK_SIZE as u64 + 5);
+        assert_eq!(several.chunk_count(), 3);
+        assert_eq!(several.expected_chunk_size(1), Some(CHUNK_SIZE as usize));
+        assert_eq!(several.expected_chunk_size(2), Some(5));
+    }
+
+    #[test]
+    fn hashes_chunks_in_order() {
+        let attachment = attachment(CHUNK_SIZE as u64 + 3);
+        let bytes: Vec<u8> = (0..attachment.size).map(|i| (i % 251) as u8).collect();
+        let chunks = |index: u32| {
+            let start = index as usize * CHUNK_SIZE as usize;
+            let end = (start + CHUNK_SIZE as usize).min(bytes.len());
+            Some(Chunk(bytes[start..end].to_vec()))
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(&bytes);
+        assert_eq!(
+            hash_chunks(&attachment, chunks),
+            Some(hex::encode(hasher.finalize()))
+        );
+        assert_eq!(hash_chunks(&attachment, |_| None), None);
+    }
+
+    #[test]
+    fn rejects_invalid_metadata() {
+        let user = User {
+            identity: String::from("amschel"),
+        };
+        let new = |mime: &str, size: u64| {
+            Attachment::new(0, String::from("a"), mime.to_string(), size, user.clone())
+        };
+        assert!(new("application/pdf", 0).is_err());
+        assert!(new("application/pdf", MAX_ATTACHMENT_SIZE + 1).is_err());
+        assert!(new("pdf", 10).is_err());
+        assert!(new("text/plain; charset=utf-8", 10).is_err());
+        assert!(new("text/markdown", 10).is_ok());
+    }
+}
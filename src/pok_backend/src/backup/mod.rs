@@ -0,0 +1,452 @@
+use crate::agreement::{Agreement, ExpiryKey};
+use crate::attachment::{Attachment, AttachmentUse, ChunkKey};
+use crate::config::{AdminAction, Config};
+use crate::delegation::Delegation;
+use crate::inbox::{InboxItem, InboxKey};
+use crate::limits::{RateKey, RateWindow};
+use crate::nft::{CollectionConfig, OwnerKey, Token};
+use crate::obligation::DeadlineKey;
+use crate::template::Template;
+use crate::user::User;
+use crate::Error;
+use candid::{Decode, Encode};
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::Memory;
+use sha2::{Digest, Sha256};
+
+/// Bumped whenever the layout of a snapshot changes, so that old snapshots are
+/// not restored into state they no longer describe.
+pub const BACKUP_VERSION: u32 = 3;
+/// Stays well below the 2 MiB limit on replies and ingress messages.
+pub const BACKUP_CHUNK_SIZE: usize = 1024 * 1024;
+/// Roughly how many bytes of entries one call to `create_backup` or
+/// `finish_restore` writes, so that neither runs out of instructions.
+pub const BACKUP_BATCH_SIZE: u64 = 8 * 1024 * 1024;
+
+/// One record of a snapshot. A snapshot is a sequence of entries, each encoded
+/// with Candid and prefixed with its length as a little-endian `u32`.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub enum Entry {
+    User(u64, User),
+    Agreement(u64, Box<Agreement>),
+    Template(Template),
+    Attachment(u64, Attachment),
+    AttachmentChunk(ChunkKey, Vec<u8>),
+    AttachmentUse(AttachmentUse, u64),
+    Delegation(u64, Delegation),
+    InboxItem(InboxKey, InboxItem),
+    Expiration(ExpiryKey, u64),
+    Deadline(DeadlineKey, u64),
+    Token(u64, Token),
+    TokenOwner(OwnerKey, u64),
+    RateLimit(RateKey, RateWindow),
+    AdminAction(u64, AdminAction),
+    /// Always the last entry, so that a restored canister only takes on the
+    /// configuration and the ID counters once everything else is in place.
+    Settings {
+        collection: CollectionConfig,
+        config: Config,
+        counters: Counters,
+    },
+}
+
+/// The stable structures in the order a snapshot holds them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Table {
+    Users,
+    Agreements,
+    Templates,
+    Attachments,
+    AttachmentChunks,
+    AttachmentUses,
+    Delegations,
+    Inbox,
+    Expirations,
+    Deadlines,
+    Tokens,
+    TokenOwners,
+    RateLimits,
+    AdminLog,
+    Settings,
+}
+
+impl Table {
+    const ALL: [Table; 15] = [
+        Table::Users,
+        Table::Agreements,
+        Table::Templates,
+        Table::Attachments,
+        Table::AttachmentChunks,
+        Table::AttachmentUses,
+        Table::Delegations,
+        Table::Inbox,
+        Table::Expirations,
+        Table::Deadlines,
+        Table::Tokens,
+        Table::TokenOwners,
+        Table::RateLimits,
+        Table::AdminLog,
+        Table::Settings,
+    ];
+
+    pub fn next(self) -> Option<Table> {
+        let index = Table::ALL.iter().position(|table| *table == self)?;
+        Table::ALL.get(index + 1).copied()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub struct Counters {
+    pub users: u64,
+    pub agreements: u64,
+    pub templates: u64,
+    pub attachments: u64,
+    pub delegations: u64,
+    pub tokens: u64,
+    pub transactions: u64,
+    pub admin_log: u64,
+}
+
+/// Describes a snapshot: download `chunk_count` chunks, concatenate them and
+/// check the SHA-256 before restoring.
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub version: u32,
+    pub taken_at: u64,
+    pub size: u64,
+    pub chunk_count: u32,
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum BackupProgress {
+    /// `size` bytes have been written. Call `create_backup` again to go on.
+    InProgress {
+        size: u64,
+    },
+    Ready(BackupManifest),
+}
+
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum RestoreProgress {
+    /// `applied` bytes of entries have been restored. Call `finish_restore`
+    /// again to go on.
+    InProgress {
+        applied: u64,
+    },
+    Done(BackupManifest),
+}
+
+/// A snapshot written to stable memory one batch of entries at a time, then
+/// held there until it is downloaded or replaced.
+pub struct Backup {
+    taken_at: u64,
+    size: u64,
+    hasher: Sha256,
+    /// The table to go on with and the key of the last entry written from it,
+    /// or `None` once every table has been written.
+    pub cursor: Option<(Table, Option<Vec<u8>>)>,
+    manifest: Option<BackupManifest>,
+}
+
+/// A snapshot being uploaded into a fresh canister, then restored one batch
+/// of entries at a time.
+pub struct Restore {
+    pub manifest: BackupManifest,
+    next_chunk: u32,
+    hasher: Sha256,
+    /// Where the next entry to restore starts, once every chunk has been
+    /// uploaded and the hash checked.
+    applied: Option<u64>,
+}
+
+impl Backup {
+    pub fn new(taken_at: u64) -> Backup {
+        Backup {
+            taken_at,
+            size: 0,
+            hasher: Sha256::new(),
+            cursor: Some((Table::Users, None)),
+            manifest: None,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn append<M: Memory>(&mut self, memory: &mut M, entry: &Entry) {
+        let bytes = Encode!(entry).expect("Cannot encode a snapshot entry");
+        let mut record = (bytes.len() as u32).to_le_bytes().to_vec();
+        record.extend_from_slice(&bytes);
+        Writer::new(memory, self.size)
+            .write(&record)
+            .expect("Cannot grow the backup memory");
+        self.hasher.update(&record);
+        self.size += record.len() as u64;
+    }
+
+    /// Seals the snapshot once every table has been written.
+    pub fn finish(&mut self) -> Option<&BackupManifest> {
+        if self.manifest.is_none() && self.cursor.is_none() {
+            self.manifest = Some(BackupManifest {
+                version: BACKUP_VERSION,
+                taken_at: self.taken_at,
+                size: self.size,
+                chunk_count: (self.size as usize).div_ceil(BACKUP_CHUNK_SIZE) as u32,
+                sha256: hex::encode(self.hasher.clone().finalize()),
+            });
+        }
+        self.manifest.as_ref()
+    }
+
+    pub fn manifest(&self) -> Option<&BackupManifest> {
+        self.manifest.as_ref()
+    }
+
+    pub fn progress(&self) -> BackupProgress {
+        match &self.manifest {
+            Some(manifest) => BackupProgress::Ready(manifest.clone()),
+            None => BackupProgress::InProgress { size: self.size },
+        }
+    }
+
+    pub fn chunk<M: Memory>(&self, memory: &M, index: u32) -> Option<Vec<u8>> {
+        let manifest = self.manifest.as_ref()?;
+        let offset = index as u64 * BACKUP_CHUNK_SIZE as u64;
+        if offset >= manifest.size {
+            return None;
+        }
+        let mut chunk = vec![0; (manifest.size - offset).min(BACKUP_CHUNK_SIZE as u64) as usize];
+        memory.read(offset, &mut chunk);
+        Some(chunk)
+    }
+}
+
+impl Restore {
+    pub fn new(manifest: BackupManifest) -> Result<Restore, Error> {
+        if manifest.version != BACKUP_VERSION {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Snapshots of version {} cannot be restored, only version {}",
+                    manifest.version, BACKUP_VERSION
+                ),
+            });
+        }
+        let expected_chunks = (manifest.size as usize).div_ceil(BACKUP_CHUNK_SIZE);
+        if manifest.chunk_count as usize != expected_chunks {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "A snapshot of {} bytes has {} chunks",
+                    manifest.size, expected_chunks
+                ),
+            });
+        }
+        Ok(Restore {
+            manifest,
+            next_chunk: 0,
+            hasher: Sha256::new(),
+            applied: None,
+        })
+    }
+
+    /// Chunks are uploaded in order, so that the hash is computed as they arrive.
+    pub fn add_chunk<M: Memory>(
+        &mut self,
+        memory: &mut M,
+        index: u32,
+        bytes: &[u8],
+    ) -> Result<(), Error> {
+        if index >= self.manifest.chunk_count {
+            return Err(Error::InvalidInput {
+                msg: format!("The snapshot only has {} chunks", self.manifest.chunk_count),
+            });
+        }
+        if index != self.next_chunk {
+            return Err(Error::InvalidInput {
+                msg: format!("Chunk {} is expected next", self.next_chunk),
+            });
+        }
+        let offset = index as u64 * BACKUP_CHUNK_SIZE as u64;
+        let expected_size = (self.manifest.size - offset).min(BACKUP_CHUNK_SIZE as u64) as usize;
+        if bytes.len() != expected_size {
+            return Err(Error::InvalidInput {
+                msg: format!("Chunk {} must be exactly {} bytes", index, expected_size),
+            });
+        }
+        Writer::new(memory, offset)
+            .write(bytes)
+            .expect("Cannot grow the backup memory");
+        self.hasher.update(bytes);
+        self.next_chunk += 1;
+        Ok(())
+    }
+
+    /// Whether restoring has begun, which only happens once the snapshot is
+    /// complete and matches its hash.
+    pub fn is_applying(&self) -> bool {
+        self.applied.is_some()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.applied == Some(self.manifest.size)
+    }
+
+    pub fn progress(&self) -> RestoreProgress {
+        if self.is_done() {
+            RestoreProgress::Done(self.manifest.clone())
+        } else {
+            RestoreProgress::InProgress {
+                applied: self.applied.unwrap_or(0),
+            }
+        }
+    }
+
+    /// Checks that every chunk is there and that together they match the hash.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.next_chunk < self.manifest.chunk_count {
+            return Err(Error::InvalidInput {
+                msg: format!("Chunk {} of the snapshot is missing", self.next_chunk),
+            });
+        }
+        if hex::encode(self.hasher.clone().finalize()) != self.manifest.sha256 {
+            return Err(Error::InvalidInput {
+                msg: "The snapshot does not match its SHA-256 hash".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Reads the entries that follow the last batch, about `budget` bytes of
+    /// them. The first call checks the snapshot before reading anything.
+    pub fn next_entries<M: Memory>(
+        &mut self,
+        memory: &M,
+        budget: u64,
+    ) -> Result<Vec<Entry>, Error> {
+        let mut offset = match self.applied {
+            Some(offset) => offset,
+            None => {
+                self.check()?;
+                0
+            }
+        };
+        let until = offset.saturating_add(budget);
+        let mut entries: Vec<Entry> = vec![];
+        while offset < self.manifest.size && (entries.is_empty() || offset < until) {
+            let truncated = || Error::InvalidInput {
+                msg: format!("The snapshot is truncated at byte {}", offset),
+            };
+            if offset + 4 > self.manifest.size {
+                return Err(truncated());
+            }
+            let mut length = [0; 4];
+            memory.read(offset, &mut length);
+            let length = u32::from_le_bytes(length) as u64;
+            if offset + 4 + length > self.manifest.size {
+                return Err(truncated());
+            }
+            let mut bytes = vec![0; length as usize];
+            memory.read(offset + 4, &mut bytes);
+            let entry = Decode!(&bytes, Entry).map_err(|err| Error::InvalidInput {
+                msg: format!("The snapshot could not be decoded: {}", err),
+            })?;
+            entries.push(entry);
+            offset += 4 + length;
+        }
+        self.applied = Some(offset);
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry::AttachmentChunk(
+                ChunkKey {
+                    attachment_id: 0,
+                    index: 0,
+                },
+                vec![7; BACKUP_CHUNK_SIZE + 10],
+            ),
+            Entry::Settings {
+                collection: CollectionConfig::default(),
+                config: Config::default(),
+                counters: Counters {
+                    agreements: 3,
+                    ..Counters::default()
+                },
+            },
+        ]
+    }
+
+    fn write(entries: &[Entry]) -> (Backup, VectorMemory) {
+        let mut memory = VectorMemory::default();
+        let mut backup = Backup::new(1);
+        for entry in entries.iter() {
+            backup.append(&mut memory, entry);
+        }
+        backup.cursor = None;
+        backup.finish();
+        (backup, memory)
+    }
+
+    fn upload(backup: &Backup, memory: &VectorMemory) -> (Restore, VectorMemory) {
+        let manifest = backup.manifest().unwrap().clone();
+        let mut restored = VectorMemory::default();
+        let mut restore = Restore::new(manifest.clone()).unwrap();
+        for index in 0..manifest.chunk_count {
+            let chunk = backup.chunk(memory, index).unwrap();
+            restore.add_chunk(&mut restored, index, &chunk).unwrap();
+        }
+        (restore, restored)
+    }
+
+    #[test]
+    fn snapshots_survive_chunking() {
+        let (backup, memory) = write(&entries());
+        let manifest = backup.manifest().unwrap();
+        assert_eq!(manifest.chunk_count, 2);
+        assert!(backup.chunk(&memory, 2).is_none());
+
+        let (mut restore, restored) = upload(&backup, &memory);
+        // A budget of one byte still makes progress, one entry per batch
+        let mut read: Vec<Entry> = vec![];
+        while !restore.is_done() {
+            read.extend(restore.next_entries(&restored, 1).unwrap());
+        }
+        assert_eq!(read.len(), 2);
+        assert_eq!(Encode!(&read).unwrap(), Encode!(&entries()).unwrap());
+        assert_eq!(restore.progress(), RestoreProgress::Done(manifest.clone()));
+    }
+
+    #[test]
+    fn rejects_tampered_or_incomplete_snapshots() {
+        let (backup, memory) = write(&entries());
+        let manifest = backup.manifest().unwrap().clone();
+        let mut restored = VectorMemory::default();
+        let mut incomplete = Restore::new(manifest.clone()).unwrap();
+        let first = backup.chunk(&memory, 0).unwrap();
+        assert!(incomplete.add_chunk(&mut restored, 1, &first).is_err());
+        incomplete.add_chunk(&mut restored, 0, &first).unwrap();
+        assert!(incomplete.next_entries(&restored, 1).is_err());
+        assert!(!incomplete.is_applying());
+
+        let mut tampered = Restore::new(manifest.clone()).unwrap();
+        tampered.add_chunk(&mut restored, 0, &first).unwrap();
+        let mut chunk = backup.chunk(&memory, 1).unwrap();
+        chunk[0] ^= 1;
+        tampered.add_chunk(&mut restored, 1, &chunk).unwrap();
+        assert!(tampered.next_entries(&restored, 1).is_err());
+
+        let old = BackupManifest {
+            version: BACKUP_VERSION + 1,
+            ..manifest
+        };
+        assert!(Restore::new(old).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+// Instruction counts of the signing hot paths, run with `canbench` from
+// `src/pok_backend`. Setup happens outside `bench_fn` so that only the
+// measured call is counted.
+use std::hint::black_box;
+
+use crate::agreement::Agreement;
+use crate::lamport::{self, PrivateKey};
+use crate::{_create_new_agreement, _draft_agreement, _sign_and_store};
+use canbench_rs::{bench, bench_fn, BenchResult};
+
+const INITIATOR: &str = "alice";
+const COUNTERPARTY: &str = "bob";
+
+fn terms() -> Vec<String> {
+    vec![
+        "Alice sells Bob 10 chairs".to_string(),
+        "Bob pays 1000 tokens on delivery".to_string(),
+        "Delivery is due within 30 days".to_string(),
+    ]
+}
+
+fn draft() -> Agreement {
+    _draft_agreement(
+        terms(),
+        String::from(COUNTERPARTY),
+        0,
+        String::from(INITIATOR),
+    )
+}
+
+fn private_key() -> PrivateKey {
+    lamport::random_private_key(String::from(INITIATOR), draft())
+}
+
+#[bench(raw)]
+fn create_public_key() -> BenchResult {
+    let private_key = private_key();
+    bench_fn(|| {
+        black_box(lamport::create_public_key(&private_key));
+    })
+}
+
+#[bench(raw)]
+fn sign() -> BenchResult {
+    let private_key = private_key();
+    let message = lamport::hash(&draft().message());
+    bench_fn(|| {
+        black_box(lamport::sign(message, &private_key));
+    })
+}
+
+#[bench(raw)]
+fn verify() -> BenchResult {
+    let private_key = private_key();
+    let public_key = lamport::create_public_key(&private_key);
+    let message = lamport::hash(&draft().message());
+    let signature = lamport::sign(message.clone(), &private_key);
+    bench_fn(|| {
+        assert!(black_box(lamport::verify(message, &signature, &public_key)));
+    })
+}
+
+/// The counterparty's signature on an offer the initiator already signed,
+/// through to the stored agreement. Publishing the certified data is left out
+/// since benchmarks do not run as update calls.
+#[bench(raw)]
+fn agree_to() -> BenchResult {
+    let agreement = _create_new_agreement(
+        terms(),
+        String::from(COUNTERPARTY),
+        0,
+        String::from(INITIATOR),
+    );
+    bench_fn(|| {
+        let signed = _sign_and_store(String::from(COUNTERPARTY), None, agreement);
+        assert!(black_box(signed).is_fully_signed());
+    })
+}
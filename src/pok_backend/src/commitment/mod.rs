@@ -0,0 +1,81 @@
+use crate::lamport::hash;
+use crate::user::User;
+use crate::Error;
+
+const COMMITMENT_SIZE: usize = 64;
+
+/// A salted SHA-256 commitment to terms that are kept off-chain. The parties
+/// sign the commitment in place of the terms.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Commitment {
+    pub value: String,
+    pub reveal: Option<Reveal>,
+}
+
+/// Terms and salt disclosed by a party after signing, checked against the commitment.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Reveal {
+    pub terms: Vec<String>,
+    pub salt: String,
+    pub by: User,
+    pub at: u64,
+}
+
+impl Commitment {
+    pub fn new(value: String) -> Result<Commitment, Error> {
+        let value = value.trim().to_lowercase();
+        if value.len() != COMMITMENT_SIZE || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::InvalidInput {
+                msg: format!("A commitment must be a hex-encoded SHA-256 hash"),
+            });
+        }
+        Ok(Commitment {
+            value,
+            reveal: None,
+        })
+    }
+
+    pub fn matches(&self, terms: &[String], salt: &str) -> bool {
+        commit(terms, salt) == self.value
+    }
+}
+
+/// The commitment to `terms` under `salt`: the hex SHA-256 of the salt and then
+/// each term, every one prefixed with its length in bytes and a colon, so that
+/// no two lists of terms share an encoding.
+pub fn commit(terms: &[String], salt: &str) -> String {
+    let mut encoded = format!("{}:{}", salt.len(), salt);
+    for term in terms.iter() {
+        encoded.push_str(&format!("{}:{}", term.len(), term));
+    }
+    hash(&encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms() -> Vec<String> {
+        vec![
+            "Alice sells Bob the patent".to_string(),
+            "for 1000 tokens".to_string(),
+        ]
+    }
+
+    #[test]
+    fn reveals_only_match_the_committed_terms_and_salt() {
+        let commitment = Commitment::new(commit(&terms(), "pepper")).unwrap();
+        assert!(commitment.matches(&terms(), "pepper"));
+        assert!(!commitment.matches(&terms(), "salt"));
+        assert!(!commitment.matches(&terms()[..1], "pepper"));
+        // Moving text between terms changes the commitment
+        let merged = vec!["Alice sells Bob the patentfor 1000 tokens".to_string()];
+        assert!(!commitment.matches(&merged, "pepper"));
+    }
+
+    #[test]
+    fn rejects_values_that_are_not_hashes() {
+        assert!(Commitment::new(String::from("terms in the clear")).is_err());
+        assert!(Commitment::new(commit(&terms(), "pepper").to_uppercase()).is_ok());
+    }
+}
@@ -0,0 +1,182 @@
+use std::borrow::Cow;
+
+use crate::limits::Limits;
+use crate::Error;
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::{BoundedStorable, Storable};
+
+const MAX_ADMINS: usize = 16;
+/// Update methods that stay available while the canister is paused.
+const ADMIN_METHODS: [&str; 8] = [
+    "configure_collection",
+    "create_backup",
+    "finish_restore",
+    "pause",
+    "resume",
+    "set_limits",
+    "start_restore",
+    "upload_restore_chunk",
+];
+
+#[derive(Clone, Debug, Default, candid::CandidType, Serialize, Deserialize)]
+pub struct Config {
+    pub admins: Vec<Principal>,
+    pub paused: bool,
+    pub limits: Limits,
+}
+
+/// Arguments to install and upgrade the canister with. Fields left out keep
+/// their current value.
+#[derive(Clone, Debug, Default, candid::CandidType, Serialize, Deserialize)]
+pub struct InitArgs {
+    pub admins: Option<Vec<Principal>>,
+    pub limits: Option<Limits>,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct AdminAction {
+    pub id: u64,
+    pub at: u64,
+    pub by: Principal,
+    pub kind: AdminActionKind,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub enum AdminActionKind {
+    Installed {
+        admins: Vec<Principal>,
+        limits: Limits,
+    },
+    Upgraded {
+        admins: Vec<Principal>,
+        limits: Limits,
+    },
+    Paused,
+    Resumed,
+    LimitsChanged {
+        limits: Limits,
+    },
+    CollectionConfigured {
+        enabled: bool,
+    },
+    BackupCreated {
+        sha256: String,
+    },
+    Restored {
+        sha256: String,
+    },
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for AdminAction {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AdminAction {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Config {
+    /// Controllers are always admins, so a canister installed without admins
+    /// can still be configured.
+    pub fn is_admin(&self, principal: &Principal, is_controller: bool) -> bool {
+        is_controller || self.admins.contains(principal)
+    }
+
+    /// Applies install or upgrade arguments.
+    pub fn apply(self, args: InitArgs) -> Result<Config, Error> {
+        let admins = match args.admins {
+            Some(admins) => normalize_admins(admins)?,
+            None => self.admins,
+        };
+        let limits = args.limits.unwrap_or(self.limits);
+        limits.validate()?;
+        Ok(Config {
+            admins,
+            limits,
+            ..self
+        })
+    }
+}
+
+pub fn is_admin_method(method: &str) -> bool {
+    ADMIN_METHODS.contains(&method)
+}
+
+fn normalize_admins(admins: Vec<Principal>) -> Result<Vec<Principal>, Error> {
+    let mut normalized: Vec<Principal> = vec![];
+    for admin in admins.into_iter() {
+        if admin == Principal::anonymous() {
+            return Err(Error::InvalidInput {
+                msg: format!("The anonymous principal cannot be an admin"),
+            });
+        }
+        if !normalized.contains(&admin) {
+            normalized.push(admin);
+        }
+    }
+    if normalized.len() > MAX_ADMINS {
+        return Err(Error::InvalidInput {
+            msg: format!("There can be at most {} admins", MAX_ADMINS),
+        });
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrade_arguments_only_replace_what_they_set() {
+        let admin = Principal::from_text("aaaaa-aa").unwrap();
+        let config = Config::default()
+            .apply(InitArgs {
+                admins: Some(vec![admin, admin]),
+                limits: None,
+            })
+            .unwrap();
+        assert_eq!(config.admins, vec![admin]);
+        assert_eq!(config.limits, Limits::default());
+
+        let limits = Limits {
+            max_terms: 5,
+            ..Limits::default()
+        };
+        let config = config
+            .apply(InitArgs {
+                admins: None,
+                limits: Some(limits.clone()),
+            })
+            .unwrap();
+        assert_eq!(config.admins, vec![admin]);
+        assert_eq!(config.limits, limits);
+        assert!(config.is_admin(&admin, false));
+        assert!(!config.is_admin(&Principal::anonymous(), false));
+    }
+
+    #[test]
+    fn rejects_anonymous_admins() {
+        let args = InitArgs {
+            admins: Some(vec![Principal::anonymous()]),
+            limits: None,
+        };
+        assert!(Config::default().apply(args).is_err());
+    }
+}
@@ -0,0 +1,386 @@
+use crate::agreement::Agreement;
+use candid::Nat;
+use chrono::{DateTime, FixedOffset};
+
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub struct ConsentMessageMetadata {
+    pub language: String,
+    pub utc_offset_minutes: Option<i16>,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub enum DisplayMessageType {
+    GenericDisplay,
+    LineDisplay {
+        characters_per_line: u16,
+        lines_per_page: u16,
+    },
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct ConsentMessageSpec {
+    pub metadata: ConsentMessageMetadata,
+    pub device_spec: Option<DisplayMessageType>,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct ConsentMessageRequest {
+    pub method: String,
+    pub arg: Vec<u8>,
+    pub user_preferences: ConsentMessageSpec,
+}
+
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub struct LineDisplayPage {
+    pub lines: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum ConsentMessage {
+    GenericDisplayMessage(String),
+    LineDisplayMessage { pages: Vec<LineDisplayPage> },
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct ConsentInfo {
+    pub consent_message: ConsentMessage,
+    pub metadata: ConsentMessageMetadata,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct ErrorInfo {
+    pub description: String,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub enum ConsentError {
+    UnsupportedCanisterCall(ErrorInfo),
+    ConsentMessageUnavailable(ErrorInfo),
+    InsufficientPayment(ErrorInfo),
+    GenericError {
+        error_code: Nat,
+        description: String,
+    },
+}
+
+/// What the user is about to do with the agreement the message describes.
+#[derive(Clone, Debug)]
+pub enum Action {
+    Initiate,
+    Agree,
+    AgreeOnBehalf { principal: String },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Language {
+    En,
+    De,
+}
+
+struct Labels {
+    code: &'static str,
+    initiate: &'static str,
+    agree: &'static str,
+    on_behalf: &'static str,
+    intro: &'static str,
+    initiator: &'static str,
+    counterparty: &'static str,
+    terms: &'static str,
+    deadline: &'static str,
+    no_deadline: &'static str,
+    digest: &'static str,
+    payment: &'static str,
+}
+
+const EN: Labels = Labels {
+    code: "en",
+    initiate: "Propose and sign an agreement",
+    agree: "Sign agreement",
+    on_behalf: "on behalf of",
+    intro: "By approving, you sign the terms below with a one-time Lamport signature. A signature cannot be withdrawn.",
+    initiator: "Initiator",
+    counterparty: "Counterparty",
+    terms: "Terms",
+    deadline: "Deadline",
+    no_deadline: "None",
+    digest: "Digest",
+    payment: "Payment",
+};
+
+const DE: Labels = Labels {
+    code: "de",
+    initiate: "Vereinbarung vorschlagen und unterzeichnen",
+    agree: "Vereinbarung unterzeichnen",
+    on_behalf: "im Namen von",
+    intro: "Mit Ihrer Zustimmung unterzeichnen Sie die folgenden Bedingungen mit einer einmaligen Lamport-Signatur. Eine Signatur kann nicht zurückgezogen werden.",
+    initiator: "Initiator",
+    counterparty: "Gegenpartei",
+    terms: "Bedingungen",
+    deadline: "Frist",
+    no_deadline: "Keine",
+    digest: "Prüfsumme",
+    payment: "Zahlung",
+};
+
+impl Language {
+    /// Picks a language from a BCP 47 tag such as `de-CH`, falling back to English.
+    fn from_tag(tag: &str) -> Language {
+        let primary = tag.split(['-', '_']).next().unwrap_or("").to_lowercase();
+        match primary.as_str() {
+            "de" => Language::De,
+            _ => Language::En,
+        }
+    }
+
+    fn labels(&self) -> &'static Labels {
+        match self {
+            Language::En => &EN,
+            Language::De => &DE,
+        }
+    }
+}
+
+pub fn unsupported(method: &str) -> ConsentError {
+    ConsentError::UnsupportedCanisterCall(ErrorInfo {
+        description: format!("No consent message is available for {}", method),
+    })
+}
+
+pub fn unavailable(description: &str) -> ConsentError {
+    ConsentError::ConsentMessageUnavailable(ErrorInfo {
+        description: description.to_string(),
+    })
+}
+
+/// Renders what the user consents to: the action, the parties, the terms, the
+/// deadline and the digest of the message that will be signed.
+pub fn consent_message(
+    action: &Action,
+    agreement: &Agreement,
+    spec: &ConsentMessageSpec,
+) -> Result<ConsentInfo, ConsentError> {
+    let labels = Language::from_tag(&spec.metadata.language).labels();
+    let offset_minutes = spec.metadata.utc_offset_minutes.unwrap_or(0);
+
+    let title = match action {
+        Action::Initiate => labels.initiate.to_string(),
+        Action::Agree => format!("{} #{}", labels.agree, agreement.id),
+        Action::AgreeOnBehalf { principal } => format!(
+            "{} #{} {} {}",
+            labels.agree, agreement.id, labels.on_behalf, principal
+        ),
+    };
+    let deadline = match agreement.expires_at {
+        Some(expires_at) => format_time(expires_at, offset_minutes)?,
+        None => labels.no_deadline.to_string(),
+    };
+    let mut fields = vec![
+        (
+            labels.initiator,
+            agreement.by_user.identity.trim().to_string(),
+        ),
+        (
+            labels.counterparty,
+            agreement.with_user.identity.trim().to_string(),
+        ),
+        (labels.deadline, deadline),
+        (
+            labels.digest,
+            agreement.security_level().digest(&agreement.message()),
+        ),
+    ];
+    if let Some(payment) = &agreement.payment {
+        fields.push((
+            labels.payment,
+            format!(
+                "{} -> {}: {} ({})",
+                payment.payer.identity.trim(),
+                payment.payee.identity.trim(),
+                payment.amount.0,
+                payment.ledger
+            ),
+        ));
+    }
+
+    let consent_message = match &spec.device_spec {
+        None | Some(DisplayMessageType::GenericDisplay) => {
+            let mut message = format!("# {}\n\n{}\n\n", title, labels.intro);
+            for (label, value) in fields.iter() {
+                message.push_str(&format!("**{}:** {}\n\n", label, value));
+            }
+            message.push_str(&format!("**{}:**\n", labels.terms));
+            for (i, term) in agreement.terms.iter().enumerate() {
+                message.push_str(&format!("{}. {}\n", i + 1, term));
+            }
+            ConsentMessage::GenericDisplayMessage(message)
+        }
+        Some(DisplayMessageType::LineDisplay {
+            characters_per_line,
+            lines_per_page,
+        }) => {
+            if *characters_per_line == 0 || *lines_per_page == 0 {
+                return Err(ConsentError::GenericError {
+                    error_code: Nat::from(1u64),
+                    description: String::from(
+                        "A line display needs at least one line of one character",
+                    ),
+                });
+            }
+            let mut paragraphs: Vec<String> = vec![title];
+            for (label, value) in fields.iter() {
+                paragraphs.push(format!("{}: {}", label, value));
+            }
+            paragraphs.push(format!("{}:", labels.terms));
+            for (i, term) in agreement.terms.iter().enumerate() {
+                paragraphs.push(format!("{}. {}", i + 1, term));
+            }
+            let lines: Vec<String> = paragraphs
+                .iter()
+                .flat_map(|paragraph| wrap(paragraph, *characters_per_line as usize))
+                .collect();
+            let pages = lines
+                .chunks(*lines_per_page as usize)
+                .map(|lines| LineDisplayPage {
+                    lines: lines.to_vec(),
+                })
+                .collect();
+            ConsentMessage::LineDisplayMessage { pages }
+        }
+    };
+
+    Ok(ConsentInfo {
+        consent_message,
+        metadata: ConsentMessageMetadata {
+            language: labels.code.to_string(),
+            utc_offset_minutes: spec.metadata.utc_offset_minutes,
+        },
+    })
+}
+
+fn format_time(nanos: u64, offset_minutes: i16) -> Result<String, ConsentError> {
+    let offset =
+        FixedOffset::east_opt(offset_minutes as i32 * 60).ok_or(ConsentError::GenericError {
+            error_code: Nat::from(2u64),
+            description: format!("{} minutes is not a valid UTC offset", offset_minutes),
+        })?;
+    let time = DateTime::from_timestamp_nanos(nanos.min(i64::MAX as u64) as i64);
+    Ok(time
+        .with_timezone(&offset)
+        .format("%Y-%m-%d %H:%M %:z")
+        .to_string())
+}
+
+/// Breaks text into lines of at most `width` characters, on word boundaries
+/// where possible. Words longer than a line are split.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let mut word: Vec<char> = word.chars().collect();
+        let line_len = line.chars().count();
+        if line_len > 0 && line_len + 1 + word.len() <= width {
+            line.push(' ');
+            line.extend(word.iter());
+            continue;
+        }
+        if line_len > 0 {
+            lines.push(std::mem::take(&mut line));
+        }
+        while word.len() > width {
+            let rest = word.split_off(width);
+            lines.push(word.into_iter().collect());
+            word = rest;
+        }
+        line = word.into_iter().collect();
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lamport::hash;
+    use crate::user::{CreateAgreement, User};
+
+    fn agreement() -> Agreement {
+        let alice = User {
+            identity: String::from("alice"),
+        };
+        let bob = User {
+            identity: String::from("bob"),
+        };
+        let agreement = alice.clone().new_agreement(
+            vec!["Bob delivers ten oak chairs to Alice by the end of the month".to_string()],
+            String::from("0"),
+            bob,
+            alice,
+            7,
+        );
+        Agreement {
+            expires_at: Some(1_700_000_000_000_000_000),
+            ..agreement
+        }
+    }
+
+    fn spec(language: &str, device_spec: Option<DisplayMessageType>) -> ConsentMessageSpec {
+        ConsentMessageSpec {
+            metadata: ConsentMessageMetadata {
+                language: language.to_string(),
+                utc_offset_minutes: Some(60),
+            },
+            device_spec,
+        }
+    }
+
+    #[test]
+    fn generic_display_names_parties_deadline_and_digest() {
+        let agreement = agreement();
+        let info = consent_message(&Action::Agree, &agreement, &spec("en-GB", None)).unwrap();
+        assert_eq!(info.metadata.language, "en");
+        match info.consent_message {
+            ConsentMessage::GenericDisplayMessage(message) => {
+                assert!(message.starts_with("# Sign agreement #7"));
+                assert!(message.contains("**Counterparty:** bob"));
+                assert!(message.contains("2023-11-14 23:13 +01:00"));
+                assert!(message.contains(&hash(&agreement.message())));
+                assert!(message.contains("1. Bob delivers ten oak chairs"));
+            }
+            _ => panic!("expected a generic display message"),
+        }
+    }
+
+    #[test]
+    fn line_display_respects_the_device_limits_in_german() {
+        let device = DisplayMessageType::LineDisplay {
+            characters_per_line: 20,
+            lines_per_page: 3,
+        };
+        let info = consent_message(
+            &Action::Initiate,
+            &agreement(),
+            &spec("de-CH", Some(device)),
+        )
+        .unwrap();
+        assert_eq!(info.metadata.language, "de");
+        match info.consent_message {
+            ConsentMessage::LineDisplayMessage { pages } => {
+                assert!(pages.len() > 1);
+                assert_eq!(pages[0].lines[0], "Vereinbarung");
+                for page in pages.iter() {
+                    assert!(page.lines.len() <= 3);
+                    assert!(page.lines.iter().all(|line| line.chars().count() <= 20));
+                }
+            }
+            _ => panic!("expected a line display message"),
+        }
+    }
+
+    #[test]
+    fn unknown_languages_fall_back_to_english() {
+        let info = consent_message(&Action::Agree, &agreement(), &spec("fr", None)).unwrap();
+        assert_eq!(info.metadata.language, "en");
+    }
+}
@@ -0,0 +1,285 @@
+use std::borrow::Cow;
+
+use crate::agreement::{Agreement, MAX_TAG_SIZE};
+use crate::lamport::{
+    context_private_key, create_public_key, hash, sign, verify, PublicKey, Signature,
+};
+use crate::user::User;
+use crate::Error;
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+
+/// Which agreements a delegate may sign.
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum DelegationScope {
+    /// Tags are not part of the signed message of an agreement. They are set
+    /// when it is created and never change, and they only narrow which
+    /// agreements the delegate may sign; the signature still covers the terms.
+    Tag(String),
+    Template(u64),
+}
+
+/// Authority granted by `grantor` to `delegate` to sign agreements on their behalf.
+/// The grant is signed by the grantor so it can be checked independently of the canister.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Delegation {
+    pub id: u64,
+    pub grantor: User,
+    pub delegate: User,
+    pub scope: DelegationScope,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub revoked_at: Option<u64>,
+    pub signature: Signature,
+    pub public_key: PublicKey,
+}
+
+/// Recorded on a signature that a delegate produced on behalf of a party.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct DelegatedSignature {
+    pub principal: User,
+    pub delegate: User,
+    pub delegation_id: u64,
+}
+
+impl Storable for Delegation {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Delegation {
+    const MAX_SIZE: u32 = 100000;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Delegation {
+    /// Creates and signs a grant. The signature covers everything except the revocation.
+    pub fn grant(
+        id: u64,
+        grantor: User,
+        delegate: User,
+        scope: DelegationScope,
+        created_at: u64,
+        expires_at: u64,
+    ) -> Result<Delegation, Error> {
+        if grantor.identity == delegate.identity {
+            return Err(Error::InvalidInput {
+                msg: format!("You cannot delegate to yourself"),
+            });
+        }
+        if expires_at <= created_at {
+            return Err(Error::InvalidInput {
+                msg: format!("A delegation must expire in the future"),
+            });
+        }
+        // Agreement tags are stored trimmed, so the scope is too or it would never match
+        let scope = match scope {
+            DelegationScope::Tag(tag) => {
+                let tag = tag.trim().to_string();
+                if tag.is_empty() || tag.len() > MAX_TAG_SIZE {
+                    return Err(Error::InvalidInput {
+                        msg: format!("A tag must be between 1 and {} bytes", MAX_TAG_SIZE),
+                    });
+                }
+                DelegationScope::Tag(tag)
+            }
+            scope => scope,
+        };
+        let message = grant_message(id, &grantor, &delegate, &scope, created_at, expires_at);
+        let private_key = context_private_key(grantor.identity.clone(), &message);
+        Ok(Delegation {
+            id,
+            signature: sign(hash(&message), &private_key),
+            public_key: create_public_key(&private_key),
+            grantor,
+            delegate,
+            scope,
+            created_at,
+            expires_at,
+            revoked_at: None,
+        })
+    }
+
+    pub fn is_authentic(&self) -> bool {
+        let message = grant_message(
+            self.id,
+            &self.grantor,
+            &self.delegate,
+            &self.scope,
+            self.created_at,
+            self.expires_at,
+        );
+        verify(hash(&message), &self.signature, &self.public_key)
+    }
+
+    pub fn is_active(&self, now: u64) -> bool {
+        self.revoked_at.is_none() && now < self.expires_at
+    }
+
+    pub fn covers(&self, agreement: &Agreement) -> bool {
+        match &self.scope {
+            DelegationScope::Tag(tag) => agreement.tags.iter().flatten().any(|t| t == tag),
+            DelegationScope::Template(template_id) => agreement
+                .template
+                .as_ref()
+                .map_or(false, |template| template.id == *template_id),
+        }
+    }
+
+    /// Checks that `delegate` may sign `agreement` for the grantor at time `now`.
+    pub fn authorize(&self, delegate: &str, agreement: &Agreement, now: u64) -> Result<(), Error> {
+        if self.delegate.identity != delegate {
+            return Err(Error::Unauthorized {
+                msg: format!("Delegation {} was not granted to you", self.id),
+            });
+        }
+        if !self.is_active(now) {
+            return Err(Error::Unauthorized {
+                msg: format!("Delegation {} has expired or was revoked", self.id),
+            });
+        }
+        if !agreement.is_party(&self.grantor.identity) {
+            return Err(Error::Unauthorized {
+                msg: format!(
+                    "Delegation {} is for {}, who is not a party to this agreement",
+                    self.id, self.grantor.identity
+                ),
+            });
+        }
+        if !self.covers(agreement) {
+            return Err(Error::Unauthorized {
+                msg: format!("Delegation {} does not cover this agreement", self.id),
+            });
+        }
+        if !self.is_authentic() {
+            return Err(Error::Unauthorized {
+                msg: format!("Delegation {} has an invalid signature", self.id),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn grant_message(
+    id: u64,
+    grantor: &User,
+    delegate: &User,
+    scope: &DelegationScope,
+    created_at: u64,
+    expires_at: u64,
+) -> String {
+    let scope = match scope {
+        DelegationScope::Tag(tag) => format!("tag={}", tag),
+        DelegationScope::Template(template_id) => format!("template={}", template_id),
+    };
+    format!(
+        "delegation:{}:{}:{}:{}:{}:{}",
+        id, grantor.identity, delegate.identity, scope, created_at, expires_at
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::CreateAgreement;
+
+    fn user(identity: &str) -> User {
+        User {
+            identity: identity.to_string(),
+        }
+    }
+
+    fn tagged_agreement(tag: &str) -> Agreement {
+        let agreement = user("amschel").new_agreement(
+            vec!["Routine purchase".to_string()],
+            String::from("0"),
+            user("treasury"),
+            user("vendor"),
+            1,
+        );
+        Agreement {
+            tags: Some(vec![tag.to_string()]),
+            ..agreement
+        }
+    }
+
+    #[test]
+    fn authorizes_only_within_scope_and_time() {
+        let delegation = Delegation::grant(
+            7,
+            user("treasury"),
+            user("treasurer"),
+            DelegationScope::Tag("purchases".to_string()),
+            100,
+            200,
+        )
+        .unwrap();
+        assert!(delegation.is_authentic());
+
+        let agreement = tagged_agreement("purchases");
+        assert!(delegation.authorize("treasurer", &agreement, 150).is_ok());
+        assert!(delegation.authorize("mallory", &agreement, 150).is_err());
+        assert!(delegation.authorize("treasurer", &agreement, 200).is_err());
+        assert!(delegation
+            .authorize("treasurer", &tagged_agreement("grants"), 150)
+            .is_err());
+
+        let revoked = Delegation {
+            revoked_at: Some(120),
+            ..delegation.clone()
+        };
+        assert!(revoked.authorize("treasurer", &agreement, 150).is_err());
+
+        let widened = Delegation {
+            expires_at: 10_000,
+            ..delegation
+        };
+        assert!(!widened.is_authentic());
+    }
+
+    #[test]
+    fn tag_scopes_are_trimmed_like_agreement_tags() {
+        let delegation = Delegation::grant(
+            7,
+            user("treasury"),
+            user("treasurer"),
+            DelegationScope::Tag("  purchases ".to_string()),
+            100,
+            200,
+        )
+        .unwrap();
+        assert_eq!(
+            delegation.scope,
+            DelegationScope::Tag("purchases".to_string())
+        );
+        assert!(delegation.is_authentic());
+        assert!(delegation
+            .authorize("treasurer", &tagged_agreement("purchases"), 150)
+            .is_ok());
+
+        let padded = format!(" {} ", "t".repeat(MAX_TAG_SIZE));
+        assert!(Delegation::grant(
+            8,
+            user("treasury"),
+            user("treasurer"),
+            DelegationScope::Tag(padded),
+            100,
+            200
+        )
+        .is_ok());
+        assert!(Delegation::grant(
+            9,
+            user("treasury"),
+            user("treasurer"),
+            DelegationScope::Tag("   ".to_string()),
+            100,
+            200
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,323 @@
+use crate::agreement::Agreement;
+use crate::attachment::AttachmentRef;
+use crate::history::{AgreementEvent, EventKind};
+use crate::lamport::{
+    create_public_key, hash, random_private_key, sign, verify, PublicKey, Signature,
+};
+use crate::timestamp::Timestamp;
+use crate::user::User;
+use crate::Error;
+
+/// Prefix mixed into the arbitrator's key derivation, so that a ruling key never
+/// coincides with a key the same principal signs with as a party or witness.
+const ARBITRATOR_KEY_DOMAIN: &str = "arbitrator:";
+
+/// A disagreement about a signed agreement. Each agreement can be disputed
+/// once, since the arbitrator's one-time key only signs one ruling.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Dispute {
+    pub claim: Statement,
+    pub response: Option<Statement>,
+    pub ruling: Option<Ruling>,
+}
+
+/// What a party puts forward, with the documents backing it.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Statement {
+    pub by: User,
+    pub at: Timestamp,
+    pub text: String,
+    pub evidence: Vec<AttachmentRef>,
+}
+
+/// The arbitrator's decision, signed over the agreement digest, both
+/// statements and the decision itself.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Ruling {
+    pub by: User,
+    pub at: Timestamp,
+    pub decision: String,
+    pub value: Signature,
+    pub public_key: PublicKey,
+}
+
+impl Dispute {
+    pub fn is_open(&self) -> bool {
+        self.ruling.is_none()
+    }
+}
+
+/// Checks that the arbitrator is neither a party nor a witness.
+pub fn arbitrator_from_request(identity: String, agreement: &Agreement) -> Result<User, Error> {
+    let identity = identity.trim().to_string();
+    if identity.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: format!("The arbitrator must be named"),
+        });
+    }
+    if agreement.is_party(&identity) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "{} is a party and cannot also arbitrate the agreement",
+                identity
+            ),
+        });
+    }
+    if agreement
+        .witnesses
+        .iter()
+        .flatten()
+        .any(|witness| witness.user.identity.trim() == identity)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "{} is a witness and cannot also arbitrate the agreement",
+                identity
+            ),
+        });
+    }
+    Ok(User { identity })
+}
+
+pub fn open(agreement: &mut Agreement, claim: Statement) -> Result<(), Error> {
+    if !agreement.is_party(&claim.by.identity) {
+        return Err(Error::Unauthorized {
+            msg: format!("Only a party can dispute the agreement"),
+        });
+    }
+    if agreement.arbitrator.is_none() {
+        return Err(Error::InvalidInput {
+            msg: format!("The agreement names no arbitrator to settle a dispute"),
+        });
+    }
+    if !agreement.is_fully_signed() {
+        return Err(Error::InvalidInput {
+            msg: format!("Only an agreement both parties signed can be disputed"),
+        });
+    }
+    if agreement.dispute.is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("The agreement has already been disputed"),
+        });
+    }
+    agreement.record(AgreementEvent::new(
+        claim.at.nanos,
+        claim.by.clone(),
+        EventKind::DisputeOpened,
+    ));
+    agreement.dispute = Some(Dispute {
+        claim,
+        response: None,
+        ruling: None,
+    });
+    Ok(())
+}
+
+/// Adds the other party's answer to the claim.
+pub fn respond(agreement: &mut Agreement, response: Statement) -> Result<(), Error> {
+    let is_party = agreement.is_party(&response.by.identity);
+    let dispute = match agreement.dispute.as_mut() {
+        Some(dispute) if dispute.is_open() => dispute,
+        _ => {
+            return Err(Error::InvalidInput {
+                msg: format!("The agreement has no open dispute"),
+            })
+        }
+    };
+    if !is_party || dispute.claim.by.identity.trim() == response.by.identity.trim() {
+        return Err(Error::Unauthorized {
+            msg: format!("Only the other party can respond to the dispute"),
+        });
+    }
+    if dispute.response.is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("The dispute has already been answered"),
+        });
+    }
+    let event = AgreementEvent::new(
+        response.at.nanos,
+        response.by.clone(),
+        EventKind::DisputeAnswered,
+    );
+    dispute.response = Some(response);
+    agreement.record(event);
+    Ok(())
+}
+
+/// Signs the arbitrator's decision and closes the dispute. The arbitrator can
+/// rule without waiting for a response.
+pub fn rule(
+    agreement: &mut Agreement,
+    arbitrator: &str,
+    decision: String,
+    at: Timestamp,
+) -> Result<(), Error> {
+    let by = match &agreement.arbitrator {
+        Some(by) if by.identity.trim() == arbitrator.trim() => by.clone(),
+        _ => {
+            return Err(Error::Unauthorized {
+                msg: format!("You are not the arbitrator of this agreement"),
+            })
+        }
+    };
+    let message = match &agreement.dispute {
+        Some(dispute) if dispute.is_open() => ruling_message(agreement, dispute, &decision),
+        _ => {
+            return Err(Error::InvalidInput {
+                msg: format!("The agreement has no open dispute"),
+            })
+        }
+    };
+    let private_key = random_private_key(
+        format!("{}{}", ARBITRATOR_KEY_DOMAIN, by.identity),
+        agreement.clone(),
+    );
+    let ruling = Ruling {
+        by: by.clone(),
+        at: at.clone(),
+        decision,
+        value: sign(agreement.security_level().digest(&message), &private_key),
+        public_key: create_public_key(&private_key),
+    };
+    agreement.record(AgreementEvent::new(at.nanos, by, EventKind::RulingIssued));
+    if let Some(dispute) = agreement.dispute.as_mut() {
+        dispute.ruling = Some(ruling);
+    }
+    Ok(())
+}
+
+/// The message the arbitrator signs: the agreement digest, then each statement
+/// with the hashes of its evidence, then the decision.
+fn ruling_message(agreement: &Agreement, dispute: &Dispute, decision: &str) -> String {
+    let mut message = hash(&agreement.message());
+    for statement in [Some(&dispute.claim), dispute.response.as_ref()]
+        .into_iter()
+        .flatten()
+    {
+        message.push_str(&statement.by.identity);
+        message.push_str(&statement.text);
+        for evidence in statement.evidence.iter() {
+            message.push_str(&evidence.sha256);
+        }
+    }
+    message.push_str(decision);
+    message
+}
+
+/// Whether the ruling verifies, or `None` while there is none.
+pub fn verify_ruling(agreement: &Agreement) -> Option<bool> {
+    let dispute = agreement.dispute.as_ref()?;
+    let ruling = dispute.ruling.as_ref()?;
+    let message = ruling_message(agreement, dispute, &ruling.decision);
+    Some(verify(
+        agreement.security_level().digest(&message),
+        &ruling.value,
+        &ruling.public_key,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::{Agree, CreateAgreement};
+
+    fn user(identity: &str) -> User {
+        User {
+            identity: identity.to_string(),
+        }
+    }
+
+    fn statement(by: &str, text: &str) -> Statement {
+        Statement {
+            by: user(by),
+            at: Timestamp::new(10),
+            text: text.to_string(),
+            evidence: vec![],
+        }
+    }
+
+    fn signed_agreement() -> Agreement {
+        let agreement = user("alice").new_agreement(
+            vec!["Alice delivers 10 chairs to Bob".to_string()],
+            String::from("0"),
+            user("bob"),
+            user("alice"),
+            1,
+        );
+        let agreement = Agreement {
+            arbitrator: Some(arbitrator_from_request(String::from(" carol "), &agreement).unwrap()),
+            ..agreement
+        };
+        user("bob").agree(user("alice").automatic_agreement(agreement))
+    }
+
+    #[test]
+    fn a_dispute_is_answered_and_ruled_on() {
+        let mut agreement = signed_agreement();
+        assert!(open(&mut agreement, statement("carol", "Not a party")).is_err());
+        open(&mut agreement, statement("bob", "Only 9 chairs arrived")).unwrap();
+        assert!(open(&mut agreement, statement("alice", "Again")).is_err());
+
+        assert!(respond(&mut agreement, statement("bob", "Myself")).is_err());
+        respond(&mut agreement, statement("alice", "I sent 10")).unwrap();
+        assert!(respond(&mut agreement, statement("alice", "Twice")).is_err());
+
+        let decision = String::from("Alice delivers one more chair");
+        assert!(rule(&mut agreement, "bob", decision.clone(), Timestamp::new(20)).is_err());
+        assert_eq!(verify_ruling(&agreement), None);
+        rule(
+            &mut agreement,
+            "carol",
+            decision.clone(),
+            Timestamp::new(20),
+        )
+        .unwrap();
+        assert!(!agreement.dispute.as_ref().unwrap().is_open());
+        assert_eq!(verify_ruling(&agreement), Some(true));
+        assert!(rule(&mut agreement, "carol", decision, Timestamp::new(30)).is_err());
+
+        let kinds: Vec<EventKind> = agreement
+            .history
+            .iter()
+            .flatten()
+            .map(|event| event.kind.clone())
+            .collect();
+        assert!(matches!(
+            kinds.as_slice(),
+            [
+                EventKind::DisputeOpened,
+                EventKind::DisputeAnswered,
+                EventKind::RulingIssued
+            ]
+        ));
+
+        let mut tampered = agreement.clone();
+        tampered
+            .dispute
+            .as_mut()
+            .unwrap()
+            .ruling
+            .as_mut()
+            .unwrap()
+            .decision = String::from("Bob keeps the chairs for free");
+        assert_eq!(verify_ruling(&tampered), Some(false));
+        let mut tampered = agreement;
+        tampered.dispute.as_mut().unwrap().claim.text = String::from("No chairs arrived");
+        assert_eq!(verify_ruling(&tampered), Some(false));
+    }
+
+    #[test]
+    fn only_outsiders_arbitrate_signed_agreements() {
+        let agreement = signed_agreement();
+        assert!(arbitrator_from_request(String::from("alice"), &agreement).is_err());
+        assert!(arbitrator_from_request(String::from(" "), &agreement).is_err());
+
+        let mut unsigned = agreement.unsigned();
+        assert!(open(&mut unsigned, statement("bob", "Late")).is_err());
+        let mut without_arbitrator = Agreement {
+            arbitrator: None,
+            ..agreement
+        };
+        assert!(open(&mut without_arbitrator, statement("bob", "Late")).is_err());
+    }
+}
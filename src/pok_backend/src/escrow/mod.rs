@@ -0,0 +1,296 @@
+use crate::agreement::Agreement;
+use crate::icrc::{Account, Subaccount};
+use crate::user::User;
+use crate::Error;
+use candid::{Nat, Principal};
+
+/// Releases and refunds that keep failing are given up after this many tries.
+pub const MAX_SETTLEMENT_ATTEMPTS: u32 = 5;
+const ESCROW_DOMAIN: &[u8] = b"escrow";
+
+/// A payment declared in the terms: `payer` pays `amount` of the ledger's token
+/// to the other party.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct PaymentRequest {
+    pub ledger: Principal,
+    pub payer: String,
+    pub amount: Nat,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Payment {
+    pub ledger: Principal,
+    pub payer: User,
+    pub payee: User,
+    pub amount: Nat,
+    /// The ledger fee at deposit time. The payer deposits it on top of the
+    /// amount so that the release or refund transfer is covered.
+    pub fee: Option<Nat>,
+    pub status: PaymentStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum PaymentStatus {
+    AwaitingDeposit,
+    Deposited { block: Nat, at: u64 },
+    Released { block: Nat, at: u64 },
+    Refunded { block: Nat, at: u64 },
+}
+
+/// Where escrowed funds go once the agreement is settled.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Settlement {
+    Release,
+    Refund,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Icrc1TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub enum Icrc1TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+impl Payment {
+    pub fn from_request(request: PaymentRequest, agreement: &Agreement) -> Result<Payment, Error> {
+        let payer = request.payer.trim();
+        let (payer, payee) = if agreement.is_initiator(payer) {
+            (agreement.by_user.clone(), agreement.with_user.clone())
+        } else if agreement.is_party(payer) {
+            (agreement.with_user.clone(), agreement.by_user.clone())
+        } else {
+            return Err(Error::InvalidInput {
+                msg: format!("The payer must be a party to the agreement"),
+            });
+        };
+        for party in [&payer, &payee] {
+            if Principal::from_text(party.identity.trim()).is_err() {
+                return Err(Error::InvalidInput {
+                    msg: format!("{} cannot hold tokens on a ledger", party.identity),
+                });
+            }
+        }
+        if request.amount == Nat::from(0u64) {
+            return Err(Error::InvalidInput {
+                msg: format!("A payment must be for more than zero tokens"),
+            });
+        }
+        Ok(Payment {
+            ledger: request.ledger,
+            payer,
+            payee,
+            amount: request.amount,
+            fee: None,
+            status: PaymentStatus::AwaitingDeposit,
+            attempts: 0,
+            last_error: None,
+        })
+    }
+
+    /// What the parties sign about the payment: the ledger, who pays whom and how much.
+    pub fn message(&self) -> String {
+        format!(
+            "{}{}{}{}",
+            self.ledger,
+            self.payer.identity.trim(),
+            self.payee.identity.trim(),
+            self.amount.0
+        )
+    }
+
+    pub fn needs_deposit_from(&self, identity: &str) -> bool {
+        self.status == PaymentStatus::AwaitingDeposit
+            && self.payer.identity.trim() == identity.trim()
+    }
+
+    pub fn is_deposited(&self) -> bool {
+        matches!(self.status, PaymentStatus::Deposited { .. })
+    }
+
+    /// Whether the escrowed funds should now be released or refunded. Releases
+    /// also need the signatures to verify, which is checked by the caller.
+    pub fn settlement(&self, agreement: &Agreement, now: u64) -> Option<Settlement> {
+        if !self.is_deposited() || self.attempts >= MAX_SETTLEMENT_ATTEMPTS {
+            return None;
+        }
+        if agreement.is_terminated() || agreement.is_expired(now) {
+            Some(Settlement::Refund)
+        } else if agreement.is_complete() {
+            Some(Settlement::Release)
+        } else {
+            None
+        }
+    }
+
+    /// The account the funds go to for a settlement.
+    pub fn recipient(&self, settlement: &Settlement) -> Account {
+        let user = match settlement {
+            Settlement::Release => &self.payee,
+            Settlement::Refund => &self.payer,
+        };
+        Account {
+            owner: Principal::from_text(user.identity.trim()).unwrap_or(Principal::anonymous()),
+            subaccount: None,
+        }
+    }
+}
+
+/// Each agreement holds its deposit in its own subaccount of this canister.
+pub fn escrow_subaccount(agreement_id: u64) -> Subaccount {
+    let mut subaccount = vec![0; 32];
+    subaccount[..ESCROW_DOMAIN.len()].copy_from_slice(ESCROW_DOMAIN);
+    subaccount[24..].copy_from_slice(&agreement_id.to_be_bytes());
+    subaccount
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{AgreementEvent, EventKind};
+    use crate::user::{Agree, CreateAgreement};
+
+    const ALICE: &str = "2vxsx-fae";
+    const BOB: &str = "aaaaa-aa";
+
+    fn agreement() -> Agreement {
+        let alice = User {
+            identity: ALICE.to_string(),
+        };
+        let bob = User {
+            identity: BOB.to_string(),
+        };
+        alice.clone().new_agreement(
+            vec!["Alice pays Bob 100 tokens for the chairs".to_string()],
+            String::from("0"),
+            bob,
+            alice,
+            9,
+        )
+    }
+
+    fn deposited(agreement: &Agreement) -> Payment {
+        let request = PaymentRequest {
+            ledger: Principal::anonymous(),
+            payer: ALICE.to_string(),
+            amount: Nat::from(100u64),
+        };
+        Payment {
+            status: PaymentStatus::Deposited {
+                block: Nat::from(1u64),
+                at: 0,
+            },
+            ..Payment::from_request(request, agreement).unwrap()
+        }
+    }
+
+    #[test]
+    fn payer_must_be_a_party_and_pay_something() {
+        let agreement = agreement();
+        let payment = deposited(&agreement);
+        assert_eq!(payment.payee.identity, BOB);
+        assert!(Payment::from_request(
+            PaymentRequest {
+                ledger: Principal::anonymous(),
+                payer: String::from("carol"),
+                amount: Nat::from(100u64),
+            },
+            &agreement
+        )
+        .is_err());
+        assert!(Payment::from_request(
+            PaymentRequest {
+                ledger: Principal::anonymous(),
+                payer: BOB.to_string(),
+                amount: Nat::from(0u64),
+            },
+            &agreement
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn releases_when_complete_and_refunds_when_expired_or_terminated() {
+        let offer = User {
+            identity: ALICE.to_string(),
+        }
+        .automatic_agreement(agreement());
+        let payment = deposited(&offer);
+        assert_eq!(payment.settlement(&offer, 0), None);
+        assert_eq!(
+            payment.recipient(&Settlement::Release).owner,
+            Principal::from_text(BOB).unwrap()
+        );
+
+        let expiring = Agreement {
+            expires_at: Some(10),
+            ..offer.clone()
+        };
+        assert_eq!(payment.settlement(&expiring, 10), Some(Settlement::Refund));
+
+        let mut terminated = offer.clone();
+        terminated.record(AgreementEvent::new(
+            5,
+            offer.with_user.clone(),
+            EventKind::Terminated,
+        ));
+        assert_eq!(payment.settlement(&terminated, 5), Some(Settlement::Refund));
+
+        let signed = User {
+            identity: BOB.to_string(),
+        }
+        .agree(offer);
+        assert_eq!(payment.settlement(&signed, 0), Some(Settlement::Release));
+        let exhausted = Payment {
+            attempts: MAX_SETTLEMENT_ATTEMPTS,
+            ..payment
+        };
+        assert_eq!(exhausted.settlement(&signed, 0), None);
+    }
+
+    #[test]
+    fn every_agreement_has_its_own_escrow() {
+        assert_ne!(escrow_subaccount(1), escrow_subaccount(2));
+        assert_eq!(escrow_subaccount(1).len(), 32);
+    }
+}
@@ -0,0 +1,319 @@
+use std::time::Duration;
+
+use crate::lamport::hash;
+use crate::Error;
+use candid::Principal;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const MAX_ATTEMPTS: u32 = 10;
+const MAX_METHOD_SIZE: usize = 128;
+const MAX_ARG_SIZE: usize = 16 * 1024;
+/// Replies are kept for the record but truncated so they cannot blow up the agreement.
+const MAX_REPLY_SIZE: usize = 1024;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+/// Methods that move or approve tokens on ICRC ledgers and the ICP ledger. Hooks
+/// are called as this canister, which owns every escrow subaccount and holds the
+/// payers' approvals, so no hook may make these calls on any ledger.
+const LEDGER_METHODS: [&str; 5] = [
+    "icrc1_transfer",
+    "icrc2_approve",
+    "icrc2_transfer_from",
+    "send_dfx",
+    "transfer",
+];
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct ExecutionRequest {
+    pub canister_id: Principal,
+    pub method: String,
+    pub arg: Vec<u8>,
+    pub max_attempts: Option<u32>,
+}
+
+/// An inter-canister call made once every required signature on the agreement has verified.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct ExecutionHook {
+    pub canister_id: Principal,
+    pub method: String,
+    pub arg: Vec<u8>,
+    pub max_attempts: u32,
+    pub attempts: Vec<ExecutionAttempt>,
+    pub next_attempt_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct ExecutionAttempt {
+    pub attempt: u32,
+    pub at: u64,
+    pub outcome: ExecutionOutcome,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub enum ExecutionOutcome {
+    Succeeded { reply: Vec<u8> },
+    Failed { reason: String },
+}
+
+impl ExecutionHook {
+    /// Validates a request for a hook on an agreement held by `this_canister`.
+    pub fn from_request(
+        request: ExecutionRequest,
+        this_canister: Principal,
+    ) -> Result<ExecutionHook, Error> {
+        if request.method.is_empty() || request.method.len() > MAX_METHOD_SIZE {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "A method name must be between 1 and {} bytes",
+                    MAX_METHOD_SIZE
+                ),
+            });
+        }
+        if request.arg.len() > MAX_ARG_SIZE {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "An execution argument can be at most {} bytes",
+                    MAX_ARG_SIZE
+                ),
+            });
+        }
+        let max_attempts = request.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS);
+        if max_attempts == 0 || max_attempts > MAX_ATTEMPTS {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "An execution can be attempted between 1 and {} times",
+                    MAX_ATTEMPTS
+                ),
+            });
+        }
+        let hook = ExecutionHook {
+            canister_id: request.canister_id,
+            method: request.method,
+            arg: request.arg,
+            max_attempts,
+            attempts: vec![],
+            next_attempt_at: None,
+        };
+        hook.ensure_safe_target(this_canister, None)?;
+        Ok(hook)
+    }
+
+    /// Refuses hooks that would use the canister's own authority: calls to the
+    /// canister itself or to the management canister, any call to the ledger
+    /// holding the agreement's escrow, and token transfers or approvals on any
+    /// ledger.
+    pub fn ensure_safe_target(
+        &self,
+        this_canister: Principal,
+        escrow_ledger: Option<Principal>,
+    ) -> Result<(), Error> {
+        if escrow_ledger == Some(self.canister_id) {
+            return Err(Error::InvalidInput {
+                msg: format!("An execution cannot call the ledger that holds the escrow"),
+            });
+        }
+        if self.canister_id == this_canister || self.canister_id == Principal::management_canister()
+        {
+            return Err(Error::InvalidInput {
+                msg: format!("An execution cannot call this canister or the management canister"),
+            });
+        }
+        if LEDGER_METHODS.contains(&self.method.as_str()) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "An execution cannot call {}, which moves tokens held by this canister",
+                    self.method
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// What the parties sign about the hook: the target, the method and a hash of the argument.
+    pub fn message(&self) -> String {
+        format!(
+            "{}{}{}",
+            self.canister_id,
+            self.method,
+            hash(&hex::encode(&self.arg))
+        )
+    }
+
+    pub fn has_succeeded(&self) -> bool {
+        self.attempts
+            .iter()
+            .any(|attempt| matches!(attempt.outcome, ExecutionOutcome::Succeeded { .. }))
+    }
+
+    pub fn can_attempt(&self) -> bool {
+        !self.has_succeeded() && (self.attempts.len() as u32) < self.max_attempts
+    }
+
+    /// Whether a failed call is waiting for its scheduled retry.
+    pub fn is_retry_pending(&self, now: u64) -> bool {
+        self.next_attempt_at.map_or(false, |at| at > now)
+    }
+
+    /// How long until the scheduled retry, which is due at once if its time has
+    /// passed. Used to schedule retries again after an upgrade drops the timers.
+    pub fn retry_delay(&self, now: u64) -> Option<Duration> {
+        match self.next_attempt_at {
+            Some(at) if self.can_attempt() => Some(Duration::from_nanos(at.saturating_sub(now))),
+            _ => None,
+        }
+    }
+
+    /// Records a call that was not made because the hook is not allowed, and
+    /// gives up on it.
+    pub fn refuse(&mut self, at: u64, reason: String) {
+        self.record(at, ExecutionOutcome::Failed { reason });
+        self.max_attempts = self.attempts.len() as u32;
+        self.next_attempt_at = None;
+    }
+
+    /// Records the outcome of a call and, if it failed and attempts remain,
+    /// returns how long to wait before retrying.
+    pub fn record(&mut self, at: u64, outcome: ExecutionOutcome) -> Option<Duration> {
+        let outcome = match outcome {
+            ExecutionOutcome::Succeeded { mut reply } => {
+                reply.truncate(MAX_REPLY_SIZE);
+                ExecutionOutcome::Succeeded { reply }
+            }
+            ExecutionOutcome::Failed { mut reason } => {
+                let mut end = reason.len().min(MAX_REPLY_SIZE);
+                while !reason.is_char_boundary(end) {
+                    end -= 1;
+                }
+                reason.truncate(end);
+                ExecutionOutcome::Failed { reason }
+            }
+        };
+        self.attempts.push(ExecutionAttempt {
+            attempt: self.attempts.len() as u32 + 1,
+            at,
+            outcome,
+        });
+        if !self.can_attempt() {
+            self.next_attempt_at = None;
+            return None;
+        }
+        let delay = FIRST_RETRY_DELAY
+            .saturating_mul(1 << (self.attempts.len() as u32 - 1).min(16))
+            .min(MAX_RETRY_DELAY);
+        self.next_attempt_at = Some(at.saturating_add(delay.as_nanos() as u64));
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn this_canister() -> Principal {
+        Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+    }
+
+    fn request(canister_id: Principal, method: &str) -> ExecutionRequest {
+        ExecutionRequest {
+            canister_id,
+            method: method.to_string(),
+            arg: vec![],
+            max_attempts: None,
+        }
+    }
+
+    fn hook(max_attempts: u32) -> ExecutionHook {
+        ExecutionHook::from_request(
+            ExecutionRequest {
+                max_attempts: Some(max_attempts),
+                ..request(Principal::anonymous(), "execute")
+            },
+            this_canister(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn refuses_hooks_that_act_with_the_canisters_authority() {
+        let ledger = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        for method in LEDGER_METHODS.iter() {
+            assert!(ExecutionHook::from_request(request(ledger, method), this_canister()).is_err());
+        }
+        assert!(
+            ExecutionHook::from_request(request(this_canister(), "execute"), this_canister())
+                .is_err()
+        );
+        assert!(ExecutionHook::from_request(
+            request(Principal::management_canister(), "stop_canister"),
+            this_canister()
+        )
+        .is_err());
+        let balance =
+            ExecutionHook::from_request(request(ledger, "icrc1_balance_of"), this_canister())
+                .unwrap();
+        assert!(balance.ensure_safe_target(this_canister(), None).is_ok());
+        // Nothing may be called on the ledger that holds the agreement's escrow
+        assert!(balance
+            .ensure_safe_target(this_canister(), Some(ledger))
+            .is_err());
+    }
+
+    #[test]
+    fn refused_hooks_are_not_retried() {
+        let mut hook = hook(3);
+        hook.refuse(0, String::from("Not allowed"));
+        assert!(!hook.can_attempt());
+        assert_eq!(hook.retry_delay(0), None);
+        assert_eq!(hook.attempts.len(), 1);
+    }
+
+    fn failure() -> ExecutionOutcome {
+        ExecutionOutcome::Failed {
+            reason: String::from("CanisterReject: not today"),
+        }
+    }
+
+    #[test]
+    fn retries_with_backoff_until_attempts_run_out() {
+        let mut hook = hook(3);
+        assert_eq!(hook.record(0, failure()), Some(FIRST_RETRY_DELAY));
+        assert_eq!(hook.record(1, failure()), Some(FIRST_RETRY_DELAY * 2));
+        assert_eq!(hook.record(2, failure()), None);
+        assert!(!hook.can_attempt());
+        assert_eq!(hook.next_attempt_at, None);
+        assert_eq!(hook.attempts.last().unwrap().attempt, 3);
+    }
+
+    #[test]
+    fn reports_the_pending_retry() {
+        let mut hook = hook(2);
+        assert!(!hook.is_retry_pending(0));
+        assert_eq!(hook.retry_delay(0), None);
+
+        hook.record(0, failure());
+        let due = FIRST_RETRY_DELAY.as_nanos() as u64;
+        assert!(hook.is_retry_pending(due - 1));
+        assert!(!hook.is_retry_pending(due));
+        assert_eq!(hook.retry_delay(due - 10), Some(Duration::from_nanos(10)));
+        assert_eq!(hook.retry_delay(due + 10), Some(Duration::ZERO));
+
+        hook.record(due, failure());
+        assert_eq!(hook.retry_delay(due), None);
+    }
+
+    #[test]
+    fn stops_after_success() {
+        let mut hook = hook(3);
+        hook.record(0, failure());
+        let reply = ExecutionOutcome::Succeeded {
+            reply: vec![0; 4096],
+        };
+        assert_eq!(hook.record(1, reply), None);
+        assert!(hook.has_succeeded());
+        assert!(!hook.can_attempt());
+        match &hook.attempts[1].outcome {
+            ExecutionOutcome::Succeeded { reply } => assert_eq!(reply.len(), MAX_REPLY_SIZE),
+            _ => panic!("expected a successful attempt"),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::timestamp;
+use crate::user::User;
+
+/// Something that happened to an agreement after it was created.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct AgreementEvent {
+    pub at: u64,
+    pub by: User,
+    pub kind: EventKind,
+    /// `at` in RFC 3339, missing on events recorded before it was added.
+    pub at_rfc3339: Option<String>,
+}
+
+impl AgreementEvent {
+    pub fn new(at: u64, by: User, kind: EventKind) -> AgreementEvent {
+        AgreementEvent {
+            at,
+            by,
+            kind,
+            at_rfc3339: Some(timestamp::rfc3339(at)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub enum EventKind {
+    /// A party replaced the terms under negotiation. Any signatures on the
+    /// previous terms were discarded.
+    CounterOffer {
+        previous_terms: Vec<String>,
+        terms: Vec<String>,
+        note: String,
+    },
+    /// The agreement reached its expiry time before every party had signed.
+    Expired,
+    /// A party called the agreement off before it was complete.
+    Terminated,
+    /// A party disputed the signed agreement.
+    DisputeOpened,
+    /// The other party answered the dispute.
+    DisputeAnswered,
+    /// The arbitrator ruled on the dispute.
+    RulingIssued,
+    /// The responsible party says the obligation is fulfilled.
+    ObligationClaimed { obligation_id: u32 },
+    /// The counterparty confirmed the claim.
+    ObligationConfirmed { obligation_id: u32 },
+    /// The counterparty rejected the claim.
+    ObligationContested { obligation_id: u32 },
+    /// The deadline passed without a claim or a confirmation.
+    ObligationMissed { obligation_id: u32 },
+}
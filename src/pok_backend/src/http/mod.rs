@@ -0,0 +1,349 @@
+use crate::agreement::Agreement;
+use crate::history::EventKind;
+use crate::verification::VerificationReport;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash, RbTree};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// The label the HTTP gateway looks up certified response hashes under.
+const HTTP_ASSETS_LABEL: &[u8] = b"http_assets";
+
+#[derive(Clone, Debug, candid::CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Every page served for an agreement.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Route {
+    AgreementJson(u64),
+    AgreementPage(u64),
+    Verify(u64),
+}
+
+/// What `/verify/{id}` reports. The report is missing until both parties have signed.
+#[derive(Clone, Debug, Serialize)]
+struct VerificationPage<'a> {
+    agreement_id: u64,
+    digest: String,
+    fully_signed: bool,
+    report: Option<&'a VerificationReport>,
+    error: Option<&'a str>,
+}
+
+impl Route {
+    pub fn parse(url: &str) -> Option<Route> {
+        let path = url.split(['?', '#']).next().unwrap_or("");
+        let path = path.trim_end_matches('/');
+        if let Some(id) = path.strip_prefix("/verify/") {
+            return id.parse().ok().map(Route::Verify);
+        }
+        let rest = path.strip_prefix("/agreements/")?;
+        match rest.strip_suffix(".json") {
+            Some(id) => id.parse().ok().map(Route::AgreementJson),
+            None => rest.parse().ok().map(Route::AgreementPage),
+        }
+    }
+
+    pub fn all(agreement_id: u64) -> [Route; 3] {
+        [
+            Route::AgreementJson(agreement_id),
+            Route::AgreementPage(agreement_id),
+            Route::Verify(agreement_id),
+        ]
+    }
+
+    pub fn agreement_id(&self) -> u64 {
+        match self {
+            Route::AgreementJson(id) | Route::AgreementPage(id) | Route::Verify(id) => *id,
+        }
+    }
+
+    /// The path the response is certified under.
+    pub fn path(&self) -> String {
+        match self {
+            Route::AgreementJson(id) => format!("/agreements/{}.json", id),
+            Route::AgreementPage(id) => format!("/agreements/{}", id),
+            Route::Verify(id) => format!("/verify/{}", id),
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Route::AgreementPage(_) => "text/html; charset=utf-8",
+            Route::AgreementJson(_) | Route::Verify(_) => "application/json",
+        }
+    }
+
+    /// Renders the page. The body only depends on the stored agreement, so the
+    /// hash certified when it was saved still matches when it is served.
+    pub fn render(
+        &self,
+        agreement: &Agreement,
+        verification: &Result<VerificationReport, String>,
+    ) -> Vec<u8> {
+        match self {
+            Route::AgreementJson(_) => serde_json::to_vec_pretty(agreement).unwrap_or_default(),
+            Route::AgreementPage(_) => render_page(agreement, verification).into_bytes(),
+            Route::Verify(_) => {
+                let page = VerificationPage {
+                    agreement_id: agreement.id,
+                    digest: agreement.security_level().digest(&agreement.message()),
+                    fully_signed: agreement.is_fully_signed(),
+                    report: verification.as_ref().ok(),
+                    error: verification.as_ref().err().map(|err| err.as_str()),
+                };
+                serde_json::to_vec_pretty(&page).unwrap_or_default()
+            }
+        }
+    }
+}
+
+pub fn body_hash(body: &[u8]) -> Hash {
+    Sha256::digest(body).into()
+}
+
+/// The root hash to store as the canister's certified data.
+pub fn certified_data(tree: &RbTree<String, Hash>) -> Hash {
+    labeled_hash(HTTP_ASSETS_LABEL, &tree.root_hash())
+}
+
+pub fn response(
+    route: &Route,
+    body: Vec<u8>,
+    tree: &RbTree<String, Hash>,
+    certificate: Option<Vec<u8>>,
+) -> HttpResponse {
+    let mut headers = vec![(
+        String::from("Content-Type"),
+        route.content_type().to_string(),
+    )];
+    if let Some(certificate) = certificate {
+        let witness = labeled(HTTP_ASSETS_LABEL, tree.witness(route.path().as_bytes()));
+        let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+        serializer.self_describe().unwrap();
+        witness.serialize(&mut serializer).unwrap();
+        headers.push((
+            String::from("IC-Certificate"),
+            format!(
+                "certificate=:{}:, tree=:{}:",
+                STANDARD.encode(certificate),
+                STANDARD.encode(serializer.into_inner())
+            ),
+        ));
+    }
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body,
+    }
+}
+
+pub fn not_found() -> HttpResponse {
+    HttpResponse {
+        status_code: 404,
+        headers: vec![(
+            String::from("Content-Type"),
+            String::from("text/plain; charset=utf-8"),
+        )],
+        body: b"Not found".to_vec(),
+    }
+}
+
+fn status(agreement: &Agreement) -> &'static str {
+    if agreement.is_terminated() {
+        "Terminated"
+    } else if agreement.is_complete() {
+        "Complete"
+    } else if agreement.is_fully_signed() {
+        "Signed, awaiting attestations"
+    } else if agreement
+        .history
+        .iter()
+        .flatten()
+        .any(|event| matches!(event.kind, EventKind::Expired))
+    {
+        "Expired"
+    } else {
+        "Awaiting signatures"
+    }
+}
+
+fn render_page(agreement: &Agreement, verification: &Result<VerificationReport, String>) -> String {
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>Agreement #{id}</title>\n</head>\n<body>\n<h1>Agreement #{id}</h1>\n<p>Status: {status}</p>\n<h2>Parties</h2>\n<ul>\n<li>Initiator: {by}</li>\n<li>Counterparty: {with}</li>\n</ul>\n<h2>Terms</h2>\n<ol>\n",
+        id = agreement.id,
+        status = status(agreement),
+        by = escape(agreement.by_user.identity.trim()),
+        with = escape(agreement.with_user.identity.trim()),
+    );
+    let revealed = agreement
+        .commitment
+        .as_ref()
+        .and_then(|commitment| commitment.reveal.as_ref());
+    let terms = revealed.map_or(&agreement.terms, |reveal| &reveal.terms);
+    for term in terms.iter() {
+        page.push_str(&format!("<li>{}</li>\n", escape(term)));
+    }
+    page.push_str("</ol>\n");
+    if let Some(commitment) = &agreement.commitment {
+        let note = match revealed {
+            Some(reveal) => format!("Revealed by {}", escape(reveal.by.identity.trim())),
+            None => String::from("The terms are confidential until a party reveals them"),
+        };
+        page.push_str(&format!(
+            "<p>{}. Commitment: <code>{}</code></p>\n",
+            note, commitment.value
+        ));
+    }
+    page.push_str("<h2>Verification</h2>\n");
+    page.push_str(&format!(
+        "<p>Digest: <code>{}</code></p>\n",
+        agreement.security_level().digest(&agreement.message())
+    ));
+    match verification {
+        Ok(report) => {
+            page.push_str(&format!(
+                "<p>Party signatures: {}</p>\n",
+                if report.parties_valid {
+                    "valid"
+                } else {
+                    "INVALID"
+                }
+            ));
+            if let Some(matches) = report.terms_match_commitment {
+                page.push_str(&format!(
+                    "<p>Revealed terms: {}</p>\n",
+                    if matches {
+                        "match the commitment"
+                    } else {
+                        "DO NOT match the commitment"
+                    }
+                ));
+            }
+            if !report.witnesses.is_empty() {
+                page.push_str("<ul>\n");
+                for witness in report.witnesses.iter() {
+                    let state = match (witness.attested, witness.valid) {
+                        (false, _) => "not attested yet",
+                        (true, true) => "valid",
+                        (true, false) => "INVALID",
+                    };
+                    page.push_str(&format!(
+                        "<li>{:?} {}: {}</li>\n",
+                        witness.role,
+                        escape(&witness.identity),
+                        state
+                    ));
+                }
+                page.push_str("</ul>\n");
+            }
+        }
+        Err(err) => page.push_str(&format!("<p>Not verifiable yet: {}</p>\n", escape(err))),
+    }
+    page.push_str(&format!(
+        "<p><a href=\"/agreements/{id}.json\">JSON export</a> &middot; <a href=\"/verify/{id}\">Verification</a></p>\n</body>\n</html>\n",
+        id = agreement.id
+    ));
+    page
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lamport::SecurityLevel;
+    use crate::user::{CreateAgreement, User};
+
+    #[test]
+    fn parses_routes() {
+        assert_eq!(
+            Route::parse("/agreements/12.json"),
+            Some(Route::AgreementJson(12))
+        );
+        assert_eq!(
+            Route::parse("/agreements/12?lang=en"),
+            Some(Route::AgreementPage(12))
+        );
+        assert_eq!(Route::parse("/verify/3/"), Some(Route::Verify(3)));
+        assert_eq!(Route::parse("/agreements/x"), None);
+        assert_eq!(Route::parse("/"), None);
+        for route in Route::all(7).iter() {
+            assert_eq!(Route::parse(&route.path()).as_ref(), Some(route));
+        }
+    }
+
+    #[test]
+    fn page_escapes_terms() {
+        let user = User {
+            identity: String::from("alice"),
+        };
+        let agreement = user.clone().new_agreement(
+            vec!["<script>alert('x')</script>".to_string()],
+            String::from("0"),
+            User {
+                identity: String::from("bob"),
+            },
+            user,
+            1,
+        );
+        let page = String::from_utf8(
+            Route::AgreementPage(1).render(&agreement, &Err(String::from("unsigned"))),
+        )
+        .unwrap();
+        assert!(!page.contains("<script>"));
+        assert!(page.contains("&lt;script&gt;"));
+        assert!(page.contains("Awaiting signatures"));
+    }
+
+    #[test]
+    fn pages_show_the_digest_the_parties_sign() {
+        let user = User {
+            identity: String::from("alice"),
+        };
+        let agreement = user.clone().new_agreement(
+            vec!["Alice sells Bob 10 chairs".to_string()],
+            String::from("0"),
+            User {
+                identity: String::from("bob"),
+            },
+            user,
+            1,
+        );
+        let agreement = Agreement {
+            security_level: Some(SecurityLevel::Bits256),
+            ..agreement
+        };
+        let digest = SecurityLevel::Bits256.digest(&agreement.message());
+        let unsigned = Err(String::from("unsigned"));
+        for route in [Route::AgreementPage(1), Route::Verify(1)] {
+            let body = String::from_utf8(route.render(&agreement, &unsigned)).unwrap();
+            assert!(body.contains(&digest));
+        }
+    }
+}
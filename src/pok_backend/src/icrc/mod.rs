@@ -0,0 +1,70 @@
+use candid::{Int, Nat, Principal};
+
+pub type Subaccount = Vec<u8>;
+
+const SUBACCOUNT_SIZE: usize = 32;
+
+/// An ICRC-1 account, also used by ICRC-7 for token owners.
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    /// The subaccount with the implicit all-zero default filled in, so that
+    /// `None` and `Some([0; 32])` compare equal.
+    pub fn effective_subaccount(&self) -> [u8; SUBACCOUNT_SIZE] {
+        let mut subaccount = [0; SUBACCOUNT_SIZE];
+        if let Some(bytes) = &self.subaccount {
+            let len = bytes.len().min(SUBACCOUNT_SIZE);
+            subaccount[..len].copy_from_slice(&bytes[..len]);
+        }
+        subaccount
+    }
+
+    pub fn has_valid_subaccount(&self) -> bool {
+        self.subaccount
+            .as_ref()
+            .map_or(true, |bytes| bytes.len() == SUBACCOUNT_SIZE)
+    }
+
+    pub fn same_as(&self, other: &Account) -> bool {
+        self.owner == other.owner && self.effective_subaccount() == other.effective_subaccount()
+    }
+}
+
+/// The generic ICRC-3 value used for metadata.
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+/// Every ICRC standard this canister implements.
+pub fn supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: String::from("ICRC-7"),
+            url: String::from("https://github.com/dfinity/ICRC/ICRCs/ICRC-7"),
+        },
+        SupportedStandard {
+            name: String::from("ICRC-21"),
+            url: String::from("https://github.com/dfinity/wg-identity-authentication/blob/main/topics/ICRC-21/icrc_21_consent_msg.md"),
+        },
+        SupportedStandard {
+            name: String::from("ICRC-10"),
+            url: String::from("https://github.com/dfinity/ICRC/ICRCs/ICRC-10"),
+        },
+    ]
+}
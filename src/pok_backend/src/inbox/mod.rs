@@ -0,0 +1,312 @@
+use std::borrow::Cow;
+
+use crate::agreement::Agreement;
+use crate::history::EventKind;
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum InboxCategory {
+    /// The agreement is waiting for your signature.
+    AwaitingSignature,
+    /// The other party changed the terms and the new version needs your signature.
+    CounterOffer,
+    /// You signed and the other party has not yet.
+    AwaitingCounterparty,
+    /// Both parties signed and you are named as a witness who has not attested yet.
+    AwaitingAttestation,
+    Signed,
+    Expired,
+    /// A party called the agreement off.
+    Terminated,
+    /// The other party disputed the agreement and your response is missing.
+    AwaitingResponse,
+    /// You are the arbitrator of an open dispute.
+    AwaitingRuling,
+    /// A dispute is open and waiting on someone else.
+    Disputed,
+}
+
+impl InboxCategory {
+    pub const ALL: [InboxCategory; 10] = [
+        InboxCategory::AwaitingSignature,
+        InboxCategory::CounterOffer,
+        InboxCategory::AwaitingCounterparty,
+        InboxCategory::AwaitingAttestation,
+        InboxCategory::Signed,
+        InboxCategory::Expired,
+        InboxCategory::Terminated,
+        InboxCategory::AwaitingResponse,
+        InboxCategory::AwaitingRuling,
+        InboxCategory::Disputed,
+    ];
+
+    /// Whether the owner of the inbox has to do something about the agreement.
+    pub fn needs_action(&self) -> bool {
+        matches!(
+            self,
+            InboxCategory::AwaitingSignature
+                | InboxCategory::CounterOffer
+                | InboxCategory::AwaitingAttestation
+                | InboxCategory::AwaitingResponse
+                | InboxCategory::AwaitingRuling
+        )
+    }
+}
+
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, candid::CandidType, Serialize, Deserialize,
+)]
+pub struct InboxKey {
+    pub identity: String,
+    pub agreement_id: u64,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct InboxItem {
+    pub agreement_id: u64,
+    pub category: InboxCategory,
+    pub read: bool,
+    pub updated_at: u64,
+}
+
+impl Storable for InboxKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for InboxKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for InboxItem {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for InboxItem {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl InboxKey {
+    /// The range of keys holding every item of one user's inbox.
+    pub fn range_of(identity: &str) -> std::ops::RangeInclusive<InboxKey> {
+        InboxKey {
+            identity: identity.to_string(),
+            agreement_id: 0,
+        }..=InboxKey {
+            identity: identity.to_string(),
+            agreement_id: u64::MAX,
+        }
+    }
+}
+
+/// Works out which inbox category the agreement belongs in for every party and
+/// witness, and for the arbitrator once the agreement is disputed.
+pub fn categorize(agreement: &Agreement, now: u64) -> Vec<(String, InboxCategory)> {
+    let parties = [
+        agreement.by_user.identity.trim().to_string(),
+        agreement.with_user.identity.trim().to_string(),
+    ];
+    let mut entries: Vec<(String, InboxCategory)> = vec![];
+
+    if agreement.is_terminated() {
+        for party in parties.into_iter() {
+            entries.push((party, InboxCategory::Terminated));
+        }
+        for witness in agreement.witnesses.iter().flatten() {
+            entries.push((witness.user.identity.clone(), InboxCategory::Terminated));
+        }
+        return entries;
+    }
+
+    if agreement.is_expired(now) {
+        for party in parties.into_iter() {
+            entries.push((party, InboxCategory::Expired));
+        }
+        return entries;
+    }
+
+    if agreement.is_fully_signed() {
+        for party in parties.into_iter() {
+            let category = match &agreement.dispute {
+                Some(dispute) if dispute.is_open() => {
+                    if dispute.response.is_none() && dispute.claim.by.identity.trim() != party {
+                        InboxCategory::AwaitingResponse
+                    } else {
+                        InboxCategory::Disputed
+                    }
+                }
+                _ => InboxCategory::Signed,
+            };
+            entries.push((party, category));
+        }
+        if let (Some(arbitrator), Some(dispute)) = (&agreement.arbitrator, &agreement.dispute) {
+            let category = if dispute.is_open() {
+                InboxCategory::AwaitingRuling
+            } else {
+                InboxCategory::Signed
+            };
+            entries.push((arbitrator.identity.trim().to_string(), category));
+        }
+        for witness in agreement.witnesses.iter().flatten() {
+            let category = if witness.attestation.is_some() {
+                InboxCategory::Signed
+            } else {
+                InboxCategory::AwaitingAttestation
+            };
+            entries.push((witness.user.identity.clone(), category));
+        }
+        return entries;
+    }
+
+    let (first_signed, second_signed) = match &agreement.proof_of_agreement {
+        Some((first, second)) => (first.is_some(), second.is_some()),
+        None => (false, false),
+    };
+    let last_counter_offer_by = agreement
+        .history
+        .iter()
+        .flatten()
+        .rev()
+        .find(|event| matches!(event.kind, EventKind::CounterOffer { .. }))
+        .map(|event| event.by.identity.trim().to_string());
+    for (party, signed) in parties.into_iter().zip([first_signed, second_signed]) {
+        let category = if signed {
+            InboxCategory::AwaitingCounterparty
+        } else if last_counter_offer_by
+            .as_ref()
+            .map_or(false, |by| by != &party)
+        {
+            InboxCategory::CounterOffer
+        } else {
+            InboxCategory::AwaitingSignature
+        };
+        entries.push((party, category));
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispute::{self, Statement};
+    use crate::history::AgreementEvent;
+    use crate::timestamp::Timestamp;
+    use crate::user::{Agree, CreateAgreement, User};
+
+    fn user(identity: &str) -> User {
+        User {
+            identity: identity.to_string(),
+        }
+    }
+
+    fn offer(expires_at: Option<u64>) -> Agreement {
+        let agreement = user("amschel").new_agreement(
+            vec!["Alice pays Bob".to_string()],
+            String::from("0"),
+            user("bob"),
+            user("alice"),
+            3,
+        );
+        user("amschel").automatic_agreement(Agreement {
+            expires_at,
+            ..agreement
+        })
+    }
+
+    #[test]
+    fn follows_the_agreement_through_its_lifecycle() {
+        let agreement = offer(Some(100));
+        assert_eq!(
+            categorize(&agreement, 50),
+            vec![
+                ("alice".to_string(), InboxCategory::AwaitingCounterparty),
+                ("bob".to_string(), InboxCategory::AwaitingSignature),
+            ]
+        );
+        assert_eq!(
+            categorize(&agreement, 100),
+            vec![
+                ("alice".to_string(), InboxCategory::Expired),
+                ("bob".to_string(), InboxCategory::Expired),
+            ]
+        );
+
+        let signed = user("bob").agree(agreement);
+        assert_eq!(
+            categorize(&signed, 100),
+            vec![
+                ("alice".to_string(), InboxCategory::Signed),
+                ("bob".to_string(), InboxCategory::Signed),
+            ]
+        );
+    }
+
+    #[test]
+    fn disputes_wait_on_the_respondent_and_the_arbitrator() {
+        let statement = |by: &str| Statement {
+            by: user(by),
+            at: Timestamp::new(10),
+            text: String::from("Bob was not paid"),
+            evidence: vec![],
+        };
+        let mut agreement = user("bob").agree(offer(None));
+        agreement.arbitrator = Some(user("carol"));
+        dispute::open(&mut agreement, statement("bob")).unwrap();
+        assert_eq!(
+            categorize(&agreement, 20),
+            vec![
+                ("alice".to_string(), InboxCategory::AwaitingResponse),
+                ("bob".to_string(), InboxCategory::Disputed),
+                ("carol".to_string(), InboxCategory::AwaitingRuling),
+            ]
+        );
+
+        dispute::respond(&mut agreement, statement("alice")).unwrap();
+        assert_eq!(categorize(&agreement, 20)[0].1, InboxCategory::Disputed);
+
+        let decision = String::from("Alice pays Bob");
+        dispute::rule(&mut agreement, "carol", decision, Timestamp::new(30)).unwrap();
+        assert!(categorize(&agreement, 40)
+            .iter()
+            .all(|(_, category)| *category == InboxCategory::Signed));
+    }
+
+    #[test]
+    fn counter_offers_land_with_the_other_party() {
+        let mut agreement = offer(None);
+        agreement.terms = vec!["Alice pays Bob twice".to_string()];
+        agreement.proof_of_agreement = None;
+        agreement.public_keys = None;
+        agreement.record(AgreementEvent::new(
+            10,
+            user("bob"),
+            EventKind::CounterOffer {
+                previous_terms: vec!["Alice pays Bob".to_string()],
+                terms: vec!["Alice pays Bob twice".to_string()],
+                note: String::from("Inflation"),
+            },
+        ));
+        let agreement = user("bob").agree(agreement);
+        assert_eq!(
+            categorize(&agreement, 20),
+            vec![
+                ("alice".to_string(), InboxCategory::CounterOffer),
+                ("bob".to_string(), InboxCategory::AwaitingCounterparty),
+            ]
+        );
+    }
+}
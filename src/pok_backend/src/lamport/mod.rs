@@ -1,40 +1,91 @@
-use sha2::{Digest, Sha256};
+use sha2::{Digest, Sha256, Sha512};
 
 use crate::agreement::{self, Agreement};
 
+/// Key pairs, one per bit of the message digest, at the 128-bit level.
 const KEY_SIZE: usize = 256;
+const MAX_KEY_SIZE: usize = 2 * KEY_SIZE;
 const KEY_ELEMENT_SIZE: usize = 32;
 
+/// The preimage resistance of a key. At 128 bits the private key elements are
+/// 16 bytes and SHA-256 digests are signed, at 256 bits they are 32 bytes and
+/// SHA-512 digests are signed with twice as many key pairs.
+#[derive(Clone, Debug, Default, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum SecurityLevel {
+    #[default]
+    Bits128,
+    Bits256,
+}
+
+impl SecurityLevel {
+    pub fn key_size(&self) -> usize {
+        match self {
+            SecurityLevel::Bits128 => KEY_SIZE,
+            SecurityLevel::Bits256 => MAX_KEY_SIZE,
+        }
+    }
+
+    /// The hex digest of a message, with one bit per key pair.
+    pub fn digest(&self, message: &str) -> String {
+        match self {
+            SecurityLevel::Bits128 => hash(message),
+            SecurityLevel::Bits256 => hex::encode(Sha512::digest(message.as_bytes())),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PrivateKey {
     key_pairs: Vec<(String, String)>,
+    security_level: SecurityLevel,
 }
 #[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
 pub struct PublicKey {
     key_pairs: Vec<(String, String)>,
+    /// Missing on keys created before levels could be chosen, which are 128-bit.
+    security_level: Option<SecurityLevel>,
 }
 
 #[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
 
 pub struct Signature {
     signatures: Vec<String>,
+    /// The public key halves the signature does not reveal, carried by
+    /// signatures made against a key commitment.
+    unrevealed: Option<Vec<String>>,
+    security_level: Option<SecurityLevel>,
 }
 
 impl PrivateKey {
-    pub fn get_key(&self, i: usize) -> (String, String) {
-        self.key_pairs[i].clone()
+    #[cfg(test)]
+    pub fn get_key(&self, i: usize) -> Option<(String, String)> {
+        self.key_pairs.get(i).cloned()
     }
 }
 
 impl PublicKey {
-    pub fn get_key(&self, i: usize) -> (String, String) {
-        self.key_pairs[i].clone()
+    #[cfg(test)]
+    pub fn get_key(&self, i: usize) -> Option<(String, String)> {
+        self.key_pairs.get(i).cloned()
+    }
+
+    pub fn security_level(&self) -> SecurityLevel {
+        self.security_level.clone().unwrap_or_default()
     }
 }
 
 impl Signature {
-    pub fn get_key(&self, i: usize) -> String {
-        self.signatures[i].clone()
+    #[cfg(test)]
+    pub fn get_key(&self, i: usize) -> Option<String> {
+        self.signatures.get(i).cloned()
+    }
+
+    pub fn security_level(&self) -> SecurityLevel {
+        self.security_level.clone().unwrap_or_default()
+    }
+
+    pub fn parts(&self) -> &[String] {
+        &self.signatures
     }
 }
 /// Generates a random but cryptographically secure private key
@@ -57,13 +108,18 @@ impl Signature {
 //     }
 // }
 
+/// Derives the key at the security level of the agreement. Every pair is seeded
+/// with its index as 4 big-endian bytes, the width of `usize` in the canister,
+/// so native builds derive the same keys as the canister and the regression
+/// vectors in `vectors.json`.
 pub fn random_private_key(principal: String, agreement: Agreement) -> PrivateKey {
     let hashed_principal = hash(&principal);
+    let security_level = agreement.security_level();
 
-    let mut private_key: Vec<(String, String)> = Vec::with_capacity(KEY_SIZE);
+    let mut private_key: Vec<(String, String)> = Vec::with_capacity(security_level.key_size());
     let mut hasher = Sha256::new();
 
-    for i in 0..KEY_SIZE {
+    for i in 0..security_level.key_size() {
         hasher.update(&hashed_principal);
 
         hasher.update(agreement.by_user.clone().identity);
@@ -72,15 +128,63 @@ pub fn random_private_key(principal: String, agreement: Agreement) -> PrivateKey
         for term in agreement.terms.iter() {
             hasher.update(term);
         }
-        hasher.update(&i.to_be_bytes());
-        let hash_output = hasher.finalize_reset();
-        let key_str1 = hex::encode(&hash_output[0..KEY_ELEMENT_SIZE / 2]);
-        let key_str2 = hex::encode(&hash_output[KEY_ELEMENT_SIZE / 2..KEY_ELEMENT_SIZE]);
-        private_key.push((key_str1, key_str2));
+        if let Some(commitment) = &agreement.commitment {
+            hasher.update(&commitment.value);
+        }
+        for attachment in agreement.attachments.iter().flatten() {
+            hasher.update(&attachment.sha256);
+        }
+        hasher.update((i as u32).to_be_bytes());
+        private_key.push(key_pair(&mut hasher, &security_level));
     }
 
     PrivateKey {
         key_pairs: private_key,
+        security_level,
+    }
+}
+
+/// Derives a private key from a principal and an arbitrary context string, for
+/// records that are signed outside of an agreement such as delegation grants.
+pub fn context_private_key(principal: String, context: &str) -> PrivateKey {
+    let hashed_principal = hash(&principal);
+
+    let mut private_key: Vec<(String, String)> = Vec::with_capacity(KEY_SIZE);
+    let mut hasher = Sha256::new();
+
+    for i in 0..KEY_SIZE {
+        hasher.update(&hashed_principal);
+        hasher.update(context);
+        hasher.update((i as u32).to_be_bytes());
+        private_key.push(key_pair(&mut hasher, &SecurityLevel::Bits128));
+    }
+
+    PrivateKey {
+        key_pairs: private_key,
+        security_level: SecurityLevel::Bits128,
+    }
+}
+
+/// Finishes the key pair seeded into `hasher` and resets it. At 128 bits the
+/// two elements are the halves of one SHA-256 output, at 256 bits each one is a
+/// whole output.
+fn key_pair(hasher: &mut Sha256, security_level: &SecurityLevel) -> (String, String) {
+    match security_level {
+        SecurityLevel::Bits128 => {
+            let hash_output = hasher.finalize_reset();
+            let key_str1 = hex::encode(&hash_output[0..KEY_ELEMENT_SIZE / 2]);
+            let key_str2 = hex::encode(&hash_output[KEY_ELEMENT_SIZE / 2..KEY_ELEMENT_SIZE]);
+            (key_str1, key_str2)
+        }
+        SecurityLevel::Bits256 => {
+            let mut second_hasher = hasher.clone();
+            hasher.update([0u8]);
+            second_hasher.update([1u8]);
+            (
+                hex::encode(hasher.finalize_reset()),
+                hex::encode(second_hasher.finalize()),
+            )
+        }
     }
 }
 
@@ -106,6 +210,7 @@ pub fn create_public_key(private_key: &PrivateKey) -> PublicKey {
     }
     PublicKey {
         key_pairs: public_key,
+        security_level: Some(private_key.security_level.clone()),
     }
 }
 
@@ -138,20 +243,65 @@ fn hash_to_binary_array(hash_string: String) -> Vec<u8> {
 
 pub fn sign(message_hash: String, private_key: &PrivateKey) -> Signature {
     let message_binary_array = hash_to_binary_array(message_hash);
-    let mut signature_array: Vec<String> = Vec::with_capacity(KEY_SIZE);
-    for (index, item) in message_binary_array.iter().enumerate() {
-        let (first_key, second_key) = private_key.get_key(index);
+    assert_eq!(
+        message_binary_array.len(),
+        private_key.security_level.key_size(),
+        "The message digest does not match the security level of the key"
+    );
+    let mut signature_array: Vec<String> = Vec::with_capacity(message_binary_array.len());
+    for (item, (first_key, second_key)) in message_binary_array
+        .iter()
+        .zip(private_key.key_pairs.iter())
+    {
         if *item == 0 {
-            signature_array.push(first_key);
+            signature_array.push(first_key.clone());
         } else {
-            signature_array.push(second_key);
+            signature_array.push(second_key.clone());
         }
     }
     Signature {
         signatures: signature_array,
+        unrevealed: None,
+        security_level: Some(private_key.security_level.clone()),
     }
 }
 
+/// Signs like `sign` and adds the halves of the public key that the signature
+/// does not reveal, so that only a commitment to the key has to be stored.
+pub fn sign_compact(
+    message_hash: String,
+    private_key: &PrivateKey,
+    public_key: &PublicKey,
+) -> Signature {
+    let message_binary_array = hash_to_binary_array(message_hash.clone());
+    let unrevealed = message_binary_array
+        .iter()
+        .zip(public_key.key_pairs.iter())
+        .map(|(item, (first_pub_key_hash, second_pub_key_hash))| {
+            if *item == 0 {
+                second_pub_key_hash.clone()
+            } else {
+                first_pub_key_hash.clone()
+            }
+        })
+        .collect();
+    Signature {
+        unrevealed: Some(unrevealed),
+        ..sign(message_hash, private_key)
+    }
+}
+
+/// The 32-byte commitment stored in place of a public key: the hex SHA-256 of
+/// every hash of the key, pair by pair.
+pub fn key_commitment(public_key: &PublicKey) -> String {
+    let mut hasher = Sha256::new();
+    for (first_pub_key_hash, second_pub_key_hash) in public_key.key_pairs.iter() {
+        hasher.update(first_pub_key_hash);
+        hasher.update(second_pub_key_hash);
+    }
+    hex::encode(hasher.finalize())
+}
+
 /// Verify a message using the the signature and the public key
 /// # Example
 /// ```rust
@@ -169,27 +319,284 @@ pub fn sign(message_hash: String, private_key: &PrivateKey) -> Signature {
 /// ```
 
 pub fn verify(message_hash: String, signature: &Signature, public_key: &PublicKey) -> bool {
-    let message_binary_array = hash_to_binary_array(message_hash);
-    for (index, item) in message_binary_array.iter().enumerate() {
-        let sig = signature.get_key(index);
-        let private_key_hash = hash(&sig);
-        let (first_pub_key_hash, second_pub_key_hash) = public_key.get_key(index);
-        if *item == 0 {
-            if private_key_hash != first_pub_key_hash {
-                return false;
-            }
-        } else if private_key_hash != second_pub_key_hash {
+    let security_level = public_key.security_level();
+    if signature.security_level() != security_level {
+        return false;
+    }
+    // Only digests of the level's length are signed, one bit per key pair
+    let key_size = security_level.key_size();
+    let mut message = [0u8; MAX_KEY_SIZE / 8];
+    let message = &mut message[..key_size / 8];
+    if hex::decode_to_slice(&message_hash, message).is_err()
+        || signature.signatures.len() < key_size
+        || public_key.key_pairs.len() < key_size
+    {
+        return false;
+    }
+    for index in 0..key_size {
+        let bit = (message[index / 8] >> (7 - index % 8)) & 1;
+        let (first_pub_key_hash, second_pub_key_hash) = &public_key.key_pairs[index];
+        let expected = if bit == 0 {
+            first_pub_key_hash
+        } else {
+            second_pub_key_hash
+        };
+        let private_key_hash = Sha256::digest(signature.signatures[index].as_bytes());
+        if !is_hex_of(&private_key_hash, expected) {
             return false;
         }
     }
     true
 }
+
+/// Verify a signature made with `sign_compact` against a key commitment. The
+/// hashes of the revealed halves and the unrevealed halves carried by the
+/// signature rebuild the public key, which must match the commitment.
+///
+/// The rebuilt key has as many pairs as the signature's level, so a signature
+/// cannot claim another level than the committed key.
+pub fn verify_compact(message_hash: String, signature: &Signature, commitment: &str) -> bool {
+    let key_size = signature.security_level().key_size();
+    let mut message = [0u8; MAX_KEY_SIZE / 8];
+    let message = &mut message[..key_size / 8];
+    let unrevealed = match &signature.unrevealed {
+        Some(unrevealed) if unrevealed.len() >= key_size => unrevealed,
+        _ => return false,
+    };
+    if hex::decode_to_slice(&message_hash, message).is_err()
+        || signature.signatures.len() < key_size
+    {
+        return false;
+    }
+    let mut hasher = Sha256::new();
+    let mut revealed = [0u8; 2 * KEY_ELEMENT_SIZE];
+    for index in 0..key_size {
+        let bit = (message[index / 8] >> (7 - index % 8)) & 1;
+        let private_key_hash = Sha256::digest(signature.signatures[index].as_bytes());
+        if hex::encode_to_slice(private_key_hash, &mut revealed).is_err()
+            || unrevealed[index].len() != revealed.len()
+        {
+            return false;
+        }
+        if bit == 0 {
+            hasher.update(revealed);
+            hasher.update(&unrevealed[index]);
+        } else {
+            hasher.update(&unrevealed[index]);
+            hasher.update(revealed);
+        }
+    }
+    is_hex_of(&hasher.finalize(), commitment)
+}
+
+/// Whether `hex` is `bytes` encoded the way `hash` encodes digests, in lowercase,
+/// without encoding them into a new string.
+fn is_hex_of(bytes: &[u8], hex: &str) -> bool {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let hex = hex.as_bytes();
+    hex.len() == 2 * bytes.len()
+        && bytes.iter().zip(hex.chunks_exact(2)).all(|(byte, pair)| {
+            pair[0] == DIGITS[(byte >> 4) as usize] && pair[1] == DIGITS[(byte & 0x0f) as usize]
+        })
+}
 #[cfg(test)]
 mod tests {
-    use crate::_create_new_agreement;
+    use crate::user::{CreateAgreement, User};
 
     use super::*;
 
+    /// A regression vector from `vectors.json`, recorded from this
+    /// implementation rather than taken from a published standard. Keys and
+    /// signatures are summarized by the hex SHA-256 of all their hex elements
+    /// in order.
+    #[derive(Deserialize)]
+    struct KnownAnswer {
+        name: String,
+        security_level: SecurityLevel,
+        principal: String,
+        /// Set for keys derived with `context_private_key`
+        context: Option<String>,
+        /// Set for keys derived with `random_private_key`
+        agreement: Option<KnownAgreement>,
+        message: String,
+        private_key_sha256: String,
+        first_private_pair: (String, String),
+        last_private_pair: (String, String),
+        first_public_pair: (String, String),
+        last_public_pair: (String, String),
+        key_commitment: String,
+        message_digest: String,
+        signature_sha256: String,
+        first_signature_part: String,
+        last_signature_part: String,
+    }
+
+    #[derive(Deserialize)]
+    struct KnownAgreement {
+        by_user: String,
+        with_user: String,
+        date: String,
+        terms: Vec<String>,
+    }
+
+    fn summary<'a>(elements: impl Iterator<Item = &'a String>) -> String {
+        let mut hasher = Sha256::new();
+        for element in elements {
+            hasher.update(element);
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    fn pairs(key_pairs: &[(String, String)]) -> impl Iterator<Item = &String> {
+        key_pairs.iter().flat_map(|(first, second)| [first, second])
+    }
+
+    /// Flips one of the low seven bits of a character, so the text stays ASCII.
+    fn flip_bit(text: &str, bit: usize) -> String {
+        let mut bytes = text.as_bytes().to_vec();
+        bytes[bit / 7] ^= 1 << (bit % 7);
+        String::from_utf8(bytes).unwrap()
+    }
+
+    fn flip_digest_bit(message_hash: &str, bit: usize) -> String {
+        let mut bytes = hex::decode(message_hash).unwrap();
+        bytes[bit / 8] ^= 0x80 >> (bit % 8);
+        hex::encode(bytes)
+    }
+
+    #[test]
+    fn known_answer_vectors() {
+        let vectors: Vec<KnownAnswer> = serde_json::from_str(include_str!("vectors.json")).unwrap();
+        assert!(!vectors.is_empty());
+        for vector in vectors.iter() {
+            let private_key = match (&vector.context, &vector.agreement) {
+                (Some(context), _) => context_private_key(vector.principal.clone(), context),
+                (None, Some(known)) => {
+                    let by_user = User {
+                        identity: known.by_user.clone(),
+                    };
+                    let with_user = User {
+                        identity: known.with_user.clone(),
+                    };
+                    let agreement = by_user.clone().new_agreement(
+                        known.terms.clone(),
+                        known.date.clone(),
+                        with_user,
+                        by_user,
+                        0,
+                    );
+                    assert_eq!(agreement.message(), vector.message, "{}", vector.name);
+                    let agreement = Agreement {
+                        security_level: Some(vector.security_level.clone()),
+                        ..agreement
+                    };
+                    random_private_key(vector.principal.clone(), agreement)
+                }
+                (None, None) => panic!("{} names no key derivation", vector.name),
+            };
+            assert_eq!(private_key.security_level, vector.security_level);
+            let key_pairs = &private_key.key_pairs;
+            assert_eq!(key_pairs.len(), vector.security_level.key_size());
+            assert_eq!(key_pairs[0], vector.first_private_pair, "{}", vector.name);
+            assert_eq!(
+                key_pairs[key_pairs.len() - 1],
+                vector.last_private_pair,
+                "{}",
+                vector.name
+            );
+            assert_eq!(summary(pairs(key_pairs)), vector.private_key_sha256);
+
+            let public_key = create_public_key(&private_key);
+            assert_eq!(public_key.key_pairs[0], vector.first_public_pair);
+            assert_eq!(
+                public_key.key_pairs[key_pairs.len() - 1],
+                vector.last_public_pair
+            );
+            assert_eq!(key_commitment(&public_key), vector.key_commitment);
+
+            let message_hash = vector.security_level.digest(&vector.message);
+            assert_eq!(message_hash, vector.message_digest, "{}", vector.name);
+            let signature = sign(message_hash.clone(), &private_key);
+            let parts = signature.parts();
+            assert_eq!(parts[0], vector.first_signature_part, "{}", vector.name);
+            assert_eq!(parts[parts.len() - 1], vector.last_signature_part);
+            assert_eq!(summary(parts.iter()), vector.signature_sha256);
+
+            assert!(verify(message_hash.clone(), &signature, &public_key));
+            let compact = sign_compact(message_hash.clone(), &private_key, &public_key);
+            assert!(verify_compact(
+                message_hash,
+                &compact,
+                &vector.key_commitment
+            ));
+        }
+    }
+
+    #[test]
+    fn any_flipped_message_bit_fails_verification() {
+        let private_key = context_private_key(String::from("alice"), "properties");
+        let public_key = create_public_key(&private_key);
+        for text in ["", "abc", "Alice sells Bob 10 chairs", "\u{1F91D}"] {
+            let message_hash = hash(text);
+            let signature = sign(message_hash.clone(), &private_key);
+            assert!(verify(message_hash.clone(), &signature, &public_key));
+            for bit in 0..KEY_SIZE {
+                let flipped = flip_digest_bit(&message_hash, bit);
+                assert!(!verify(flipped, &signature, &public_key), "bit {}", bit);
+            }
+        }
+    }
+
+    #[test]
+    fn any_flipped_signature_bit_fails_verification() {
+        let private_key = context_private_key(String::from("alice"), "properties");
+        let public_key = create_public_key(&private_key);
+        let message_hash = hash("Alice sells Bob 10 chairs");
+        let signature = sign(message_hash.clone(), &private_key);
+        // Every part, each at a different bit, and every bit of the first part
+        let flips = (0..KEY_SIZE)
+            .map(|index| (index, (index * 11) % (7 * 32)))
+            .chain((0..7 * 32).map(|bit| (0, bit)));
+        for (index, bit) in flips {
+            let mut tampered = signature.clone();
+            tampered.signatures[index] = flip_bit(&tampered.signatures[index], bit);
+            assert!(
+                !verify(message_hash.clone(), &tampered, &public_key),
+                "part {}, bit {}",
+                index,
+                bit
+            );
+        }
+    }
+
+    #[test]
+    fn any_flipped_public_key_bit_fails_verification() {
+        let private_key = context_private_key(String::from("alice"), "properties");
+        let public_key = create_public_key(&private_key);
+        let commitment = key_commitment(&public_key);
+        let message_hash = hash("Alice sells Bob 10 chairs");
+        let message_binary_array = hash_to_binary_array(message_hash.clone());
+        let signature = sign(message_hash.clone(), &private_key);
+        for index in 0..KEY_SIZE {
+            for second in [false, true] {
+                let mut tampered = public_key.clone();
+                let (first_half, second_half) = &mut tampered.key_pairs[index];
+                let half = if second { second_half } else { first_half };
+                *half = flip_bit(half, index % (7 * 64));
+                // A signature only reveals the halves its message selects, but
+                // a commitment covers the whole key
+                let selected = (message_binary_array[index] == 1) == second;
+                assert_eq!(
+                    verify(message_hash.clone(), &signature, &tampered),
+                    !selected,
+                    "pair {}",
+                    index
+                );
+                assert_ne!(key_commitment(&tampered), commitment);
+            }
+        }
+    }
+
     #[test]
     fn test_sign_and_verify() {
         let terms: Vec<String> = vec![
@@ -206,7 +613,15 @@ mod tests {
         "Thou shalt not covet thy neighbour's house".to_string(),
         "Thou shalt not covet thy neighbour's wife, nor his manservant, nor his maidservant, nor his ox, nor his ass, nor any thing that is thy neighbour's".to_string(),
     ];
-        let agreement = _create_new_agreement(terms, String::from("God"), 1, String::from("wHAT!"));
+        let god = User {
+            identity: String::from("God"),
+        };
+        let man = User {
+            identity: String::from("wHAT!"),
+        };
+        let agreement = man
+            .clone()
+            .new_agreement(terms, String::from("0"), god, man, 1);
 
         let private_key = random_private_key(String::from("amschel"), agreement);
 
@@ -230,6 +645,211 @@ mod tests {
         );
     }
 
+    /// The string-based check `verify` replaced.
+    fn verify_as_before(
+        message_hash: String,
+        signature: &Signature,
+        public_key: &PublicKey,
+    ) -> bool {
+        let message_binary_array = hash_to_binary_array(message_hash);
+        message_binary_array
+            .iter()
+            .enumerate()
+            .all(|(index, item)| {
+                let (first_pub_key_hash, second_pub_key_hash) = public_key.get_key(index).unwrap();
+                let private_key_hash = hash(&signature.get_key(index).unwrap());
+                if *item == 0 {
+                    private_key_hash == first_pub_key_hash
+                } else {
+                    private_key_hash == second_pub_key_hash
+                }
+            })
+    }
+
+    #[test]
+    fn verify_accepts_exactly_what_it_accepted_before() {
+        let private_key = context_private_key(String::from("alice"), "benchmarks");
+        let public_key = create_public_key(&private_key);
+        for text in ["", "Hello, world!", "Alice sells Bob 10 chairs"] {
+            let message_hash = hash(text);
+            let signature = sign(message_hash.clone(), &private_key);
+            let mut tampered = signature.clone();
+            tampered.signatures[255].push('0');
+            let mut uppercase = public_key.clone();
+            uppercase.key_pairs[0].0.make_ascii_uppercase();
+            uppercase.key_pairs[0].1.make_ascii_uppercase();
+
+            let cases = [
+                (hash(text), &signature, &public_key, true),
+                (hash("something else"), &signature, &public_key, false),
+                (hash(text), &tampered, &public_key, false),
+                (hash(text), &signature, &uppercase, false),
+            ];
+            for (message_hash, signature, public_key, expected) in cases {
+                assert_eq!(
+                    verify(message_hash.clone(), signature, public_key),
+                    expected
+                );
+                assert_eq!(
+                    verify_as_before(message_hash, signature, public_key),
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn verify_rejects_malformed_input() {
+        let private_key = context_private_key(String::from("alice"), "benchmarks");
+        let public_key = create_public_key(&private_key);
+        let message_hash = hash("Hello, world!");
+        let signature = sign(message_hash.clone(), &private_key);
+        assert!(!verify(String::from("not hex"), &signature, &public_key));
+        assert!(!verify(
+            message_hash[..32].to_string(),
+            &signature,
+            &public_key
+        ));
+        assert!(!verify(
+            format!("{}0", message_hash),
+            &signature,
+            &public_key
+        ));
+        assert!(!verify(
+            message_hash.replace('a', "g"),
+            &signature,
+            &public_key
+        ));
+        let short = Signature {
+            signatures: signature.parts()[..KEY_SIZE - 1].to_vec(),
+            unrevealed: None,
+            security_level: None,
+        };
+        assert!(!verify(message_hash.clone(), &short, &public_key));
+        let empty = Signature {
+            signatures: vec![],
+            unrevealed: Some(vec![]),
+            security_level: None,
+        };
+        assert!(!verify(message_hash.clone(), &empty, &public_key));
+        assert!(!verify_compact(
+            message_hash.clone(),
+            &empty,
+            &key_commitment(&public_key)
+        ));
+        let short_key = PublicKey {
+            key_pairs: public_key.key_pairs[..KEY_SIZE - 1].to_vec(),
+            security_level: None,
+        };
+        assert!(!verify(message_hash, &signature, &short_key));
+
+        assert_eq!(short.get_key(KEY_SIZE - 1), None);
+        assert_eq!(short_key.get_key(KEY_SIZE), None);
+        assert_eq!(private_key.get_key(KEY_SIZE), None);
+        assert!(signature.get_key(KEY_SIZE - 1).is_some());
+    }
+
+    #[test]
+    fn compact_signatures_verify_against_the_key_commitment() {
+        let private_key = context_private_key(String::from("alice"), "compact");
+        let public_key = create_public_key(&private_key);
+        let commitment = key_commitment(&public_key);
+        let message_hash = hash("Alice sells Bob 10 chairs");
+        let signature = sign_compact(message_hash.clone(), &private_key, &public_key);
+        assert!(verify_compact(
+            message_hash.clone(),
+            &signature,
+            &commitment
+        ));
+        assert!(verify(message_hash.clone(), &signature, &public_key));
+        assert!(!verify_compact(
+            hash("Alice sells Bob 8 chairs"),
+            &signature,
+            &commitment
+        ));
+
+        // Every revealed and unrevealed half is bound by the commitment
+        let mut tampered = signature.clone();
+        tampered.signatures[3].push('0');
+        assert!(!verify_compact(
+            message_hash.clone(),
+            &tampered,
+            &commitment
+        ));
+        let mut tampered = signature.clone();
+        tampered.unrevealed.as_mut().unwrap()[3] = hash("forged");
+        assert!(!verify_compact(
+            message_hash.clone(),
+            &tampered,
+            &commitment
+        ));
+
+        let other = create_public_key(&context_private_key(String::from("bob"), "compact"));
+        assert!(!verify_compact(
+            message_hash.clone(),
+            &signature,
+            &key_commitment(&other)
+        ));
+        let full = sign(message_hash.clone(), &private_key);
+        assert!(!verify_compact(message_hash, &full, &commitment));
+    }
+
+    #[test]
+    fn levels_must_match_between_key_signature_and_digest() {
+        let alice = User {
+            identity: String::from("alice"),
+        };
+        let draft = alice.clone().new_agreement(
+            vec!["Alice sells Bob 10 chairs".to_string()],
+            String::from("0"),
+            User {
+                identity: String::from("bob"),
+            },
+            alice,
+            1,
+        );
+        let short_key = random_private_key(String::from("alice"), draft.clone());
+        assert_eq!(short_key.key_pairs.len(), 256);
+        assert_eq!(short_key.key_pairs[0].0.len(), 32);
+
+        let agreement = Agreement {
+            security_level: Some(SecurityLevel::Bits256),
+            ..draft
+        };
+        let private_key = random_private_key(String::from("alice"), agreement);
+        assert_eq!(private_key.key_pairs.len(), 512);
+        assert_eq!(private_key.key_pairs[0].0.len(), 64);
+        let public_key = create_public_key(&private_key);
+        let message_hash = SecurityLevel::Bits256.digest("Hello, world!");
+        let signature = sign(message_hash.clone(), &private_key);
+        assert!(verify(message_hash.clone(), &signature, &public_key));
+        assert!(!verify(hash("Hello, world!"), &signature, &public_key));
+
+        // The level recorded on the signature must be the level of the key
+        let relabeled = Signature {
+            security_level: Some(SecurityLevel::Bits128),
+            ..signature.clone()
+        };
+        assert!(!verify(message_hash.clone(), &relabeled, &public_key));
+        let short_public_key = create_public_key(&short_key);
+        let short_signature = sign(hash("Hello, world!"), &short_key);
+        assert!(!verify(
+            hash("Hello, world!"),
+            &short_signature,
+            &public_key
+        ));
+        assert!(!verify(message_hash.clone(), &signature, &short_public_key));
+
+        let compact = sign_compact(message_hash.clone(), &private_key, &public_key);
+        let commitment = key_commitment(&public_key);
+        assert!(verify_compact(message_hash.clone(), &compact, &commitment));
+        let relabeled = Signature {
+            security_level: Some(SecurityLevel::Bits128),
+            ..compact
+        };
+        assert!(!verify_compact(message_hash, &relabeled, &commitment));
+    }
+
     #[test]
 
     fn test_hash_to_binary_array() {
@@ -1,24 +1,90 @@
 #[macro_use]
 extern crate serde;
+use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::ops::Bound;
 
-use agreement::Agreement;
-use candid::Principal;
+use agreement::{Agreement, ExpiryKey, InitiateOptions};
+use attachment::{Attachment, AttachmentRef, AttachmentUse, Chunk, ChunkKey};
+use backup::{
+    Backup, BackupManifest, BackupProgress, Counters, Entry, Restore, RestoreProgress, Table,
+    BACKUP_BATCH_SIZE,
+};
+use candid::{Decode, Nat, Principal};
 use chrono::prelude::*;
+use commitment::{Commitment, Reveal};
+use config::{AdminAction, AdminActionKind, Config, InitArgs};
+use consent::{ConsentError, ConsentInfo, ConsentMessageRequest};
+use delegation::{DelegatedSignature, Delegation, DelegationScope};
+use dispute::Statement;
+use escrow::{
+    Icrc1TransferArg, Icrc1TransferError, Payment, PaymentStatus, Settlement, TransferFromArgs,
+    TransferFromError,
+};
+use execution::{ExecutionHook, ExecutionOutcome};
 use helpers::ToUser;
+use history::{AgreementEvent, EventKind};
+use http::{HttpRequest, HttpResponse, Route};
+use ic_cdk::api::management_canister::main::{canister_info, CanisterInfoRequest};
 use ic_cdk::api::time;
+use ic_certified_map::{Hash, RbTree};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-    BTreeMap, Cell, DefaultMemoryImpl, Vec as VecStructure,
+    BTreeMap, BoundedStorable, Cell, DefaultMemoryImpl, Storable, Vec as VecStructure,
 };
-use lamport::{hash, verify};
+use icrc::{Account, SupportedStandard, Value};
+use inbox::{InboxCategory, InboxItem, InboxKey};
+use lamport::SecurityLevel;
+use limits::{Limits, RateKey, RateWindow};
+use nft::{CollectionConfig, OwnerKey, Token, TransferArg, TransferError};
+use obligation::{DeadlineKey, OverdueObligation};
+use signature::SignerMetadata;
+use std::time::Duration;
+use template::{Placeholder, Template, TemplateKey, TemplateRef};
+use timestamp::{TimeFilter, Timestamp};
 use user::{Agree, CreateAgreement, User};
+use verification::{cached_or_verify, verify_agreement, CachedVerification, VerificationReport};
+use visibility::Visibility;
 
 mod agreement;
+mod attachment;
+mod backup;
+#[cfg(feature = "canbench-rs")]
+mod benches;
+mod commitment;
+mod config;
+mod consent;
+mod delegation;
+mod dispute;
+mod escrow;
+mod execution;
 mod helpers;
+mod history;
+mod http;
+mod icrc;
+mod inbox;
 mod lamport;
+mod limits;
+mod nft;
+mod obligation;
 mod signature;
+mod template;
+mod timestamp;
 mod user;
+mod verification;
+mod visibility;
+mod witness;
+
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const DEADLINE_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const SETTLEMENT_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// How long a call or transfer due while the canister is paused waits before
+/// checking again.
+const PAUSED_RECHECK_DELAY: Duration = Duration::from_secs(60);
+/// How many agreements are certified again per message after an upgrade. Each
+/// may need its signatures verified.
+const REINDEX_BATCH_SIZE: usize = 20;
 
 //Memory implementations
 type Memory = VirtualMemory<DefaultMemoryImpl>;
@@ -52,6 +118,133 @@ thread_local! {
             .expect("Cannot create an Agreements  counter")
     );
 
+    static TEMPLATES: RefCell<BTreeMap<TemplateKey,Template,Memory>> = RefCell::new(
+        BTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+        )
+    );
+    static TEMPLATE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))), 0)
+            .expect("Cannot create a Templates counter")
+    );
+
+    static ATTACHMENTS: RefCell<BTreeMap<u64,Attachment,Memory>> = RefCell::new(
+        BTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+        )
+    );
+    static ATTACHMENT_CHUNKS: RefCell<BTreeMap<ChunkKey,Chunk,Memory>> = RefCell::new(
+        BTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+        )
+    );
+    static ATTACHMENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))), 0)
+            .expect("Cannot create an Attachments counter")
+    );
+
+    static DELEGATIONS: RefCell<BTreeMap<u64,Delegation,Memory>> = RefCell::new(
+        BTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        )
+    );
+    static DELEGATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))), 0)
+            .expect("Cannot create a Delegations counter")
+    );
+
+    static INBOX: RefCell<BTreeMap<InboxKey,InboxItem,Memory>> = RefCell::new(
+        BTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+    static EXPIRATIONS: RefCell<BTreeMap<ExpiryKey,u64,Memory>> = RefCell::new(
+        BTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+
+    static NFT_CONFIG: RefCell<Cell<CollectionConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+            CollectionConfig::default(),
+        )
+        .expect("Cannot create the collection config")
+    );
+    static TOKENS: RefCell<BTreeMap<u64,Token,Memory>> = RefCell::new(
+        BTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        )
+    );
+    static TOKEN_OWNERS: RefCell<BTreeMap<OwnerKey,u64,Memory>> = RefCell::new(
+        BTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+        )
+    );
+    static TOKEN_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))), 0)
+            .expect("Cannot create a Tokens counter")
+    );
+    static TRANSACTION_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))), 0)
+            .expect("Cannot create a Transactions counter")
+    );
+
+    static ATTACHMENT_USES: RefCell<BTreeMap<AttachmentUse,u64,Memory>> = RefCell::new(
+        BTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+    static RATE_LIMITS: RefCell<BTreeMap<RateKey,RateWindow,Memory>> = RefCell::new(
+        BTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+        )
+    );
+
+    static CONFIG: RefCell<Cell<Config, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+            Config::default(),
+        )
+        .expect("Cannot create the canister config")
+    );
+    static ADMIN_LOG: RefCell<BTreeMap<u64,AdminAction,Memory>> = RefCell::new(
+        BTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+        )
+    );
+    static ADMIN_LOG_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))), 0)
+            .expect("Cannot create an Admin log counter")
+    );
+
+    static DEADLINES: RefCell<BTreeMap<DeadlineKey,u64,Memory>> = RefCell::new(
+        BTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+        )
+    );
+
+    // The last snapshot taken, or the one being uploaded for a restore
+    static BACKUP_MEMORY: RefCell<Memory> = RefCell::new(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    );
+
+    // Hashes of every HTTP response, rebuilt from AGREEMENTS after an upgrade
+    static HTTP_TREE: RefCell<RbTree<String, Hash>> = RefCell::new(RbTree::new());
+
+    // Agreements whose execution hook is being called right now
+    static EXECUTING: RefCell<BTreeSet<u64>> = RefCell::new(BTreeSet::new());
+    // Agreements waiting on the ledger for a deposit, a release or a refund
+    static DEPOSITING: RefCell<BTreeSet<u64>> = RefCell::new(BTreeSet::new());
+    static SETTLING: RefCell<BTreeSet<u64>> = RefCell::new(BTreeSet::new());
+
+    // The snapshot being written to BACKUP_MEMORY, or the last one taken
+    static BACKUP: RefCell<Option<Backup>> = const { RefCell::new(None) };
+    // A snapshot being uploaded into a fresh canister, then restored
+    static RESTORE: RefCell<Option<Restore>> = const { RefCell::new(None) };
+
+    // The hash of the running module, looked up after every install and upgrade
+    static MODULE_HASH: RefCell<Option<String>> = RefCell::new(None);
 
 }
 
@@ -61,235 +254,2880 @@ impl ToUser for Principal {
     }
 }
 
+fn _draft_agreement(terms: Vec<String>, with_user: String, id: u64, by_user: String) -> Agreement {
+    let creator = Principal::principal_to_user(String::from("aMSCHEL"));
+    let now = time();
+
+    Agreement {
+        created_at: Some(Timestamp::new(now)),
+        ..creator.new_agreement(
+            terms,
+            now.to_string(),
+            Principal::principal_to_user(with_user),
+            Principal::principal_to_user(by_user),
+            id,
+        )
+    }
+}
+fn _sign_as_initiator(agreement: Agreement) -> Agreement {
+    let creator = Principal::principal_to_user(String::from("aMSCHEL"));
+    creator.automatic_agreement(agreement)
+}
 fn _create_new_agreement(
     terms: Vec<String>,
     with_user: String,
     id: u64,
     by_user: String,
 ) -> Agreement {
-    let creator = Principal::principal_to_user(String::from("aMSCHEL"));
-
-    let agreement = creator.clone().new_agreement(
-        terms,
-        time().to_string(),
-        Principal::principal_to_user(with_user),
-        Principal::principal_to_user(by_user),
-        id,
-    );
-    creator.automatic_agreement(agreement)
+    _sign_as_initiator(_draft_agreement(terms, with_user, id, by_user))
 }
-fn _agree_to_agreement(user: String, agreement: Agreement) -> Agreement {
-    let agreeing_party = Principal::principal_to_user(user);
-    agreeing_party.agree(agreement)
+fn _next_agreement_id() -> u64 {
+    AGREEMENT_ID_COUNTER.with(|counter| {
+        let counter_value = *counter.borrow().get();
+        let _ = counter.borrow_mut().set(counter_value + 1);
+        counter_value
+    })
 }
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn agreement_btwn_god_and_man() {
-        let terms: Vec<String> = vec![
-        "I am the Lord thy God".to_string(),
-        "Thou shalt have no other gods before me".to_string(),
-        "Thou shalt not make unto thee any graven image".to_string(),
-        "Thou shalt not take the name of the Lord thy God in vain".to_string(),
-        "Remember the sabbath day, to keep it holy".to_string(),
-        "Honour thy father and thy mother".to_string(),
-        "Thou shalt not kill".to_string(),
-        "Thou shalt not commit adultery".to_string(),
-        "Thou shalt not steal".to_string(),
-        "Thou shalt not bear false witness against thy neighbour".to_string(),
-        "Thou shalt not covet thy neighbour's house".to_string(),
-        "Thou shalt not covet thy neighbour's wife, nor his manservant, nor his maidservant, nor his ox, nor his ass, nor any thing that is thy neighbour's".to_string(),
-    ];
-        let agreement =
-            _create_new_agreement(terms, String::from("God"), 1, String::from("the heck"));
-        let amschel_agrees = _agree_to_agreement(String::from("God"), agreement);
-        dbg!(amschel_agrees.proof_of_agreement.unwrap().0.unwrap().value);
+fn _resolve_attachments(
+    attachment_ids: &[u64],
+    by_user: &str,
+) -> Result<Option<Vec<AttachmentRef>>, Error> {
+    if attachment_ids.is_empty() {
+        return Ok(None);
     }
-    #[test]
-    fn _agree_to_agreement_works() {}
+    let mut attachments: Vec<AttachmentRef> = vec![];
+    for attachment_id in attachment_ids.iter() {
+        let attachment = ATTACHMENTS.with(|storage| storage.borrow().get(attachment_id));
+        match attachment {
+            Some(attachment) if attachment.uploaded_by.identity == by_user => {
+                match attachment.reference() {
+                    Some(reference) => attachments.push(reference),
+                    None => {
+                        return Err(Error::InvalidInput {
+                            msg: format!("Attachment {} has not been finalized", attachment_id),
+                        })
+                    }
+                }
+            }
+            _ => {
+                return Err(Error::NotFound {
+                    msg: format!("Attachment with ID {} wasn't found.", attachment_id),
+                })
+            }
+        }
+    }
+    Ok(Some(attachments))
 }
+/// Validates the options and builds the unsigned agreement that `_initiate`
+/// stores, so that a consent message shows the digest the parties will sign.
+fn _build_agreement(
+    terms: Vec<String>,
+    with_user: String,
+    by_user: String,
+    template: Option<TemplateRef>,
+    options: InitiateOptions,
+) -> Result<Agreement, Error> {
+    limits::validate_terms(&terms, &_config().limits)?;
+    let attachments = _resolve_attachments(&options.attachments.unwrap_or_default(), &by_user)?;
 
-// Internet computer functions here
+    let tags = agreement::normalize_tags(options.tags.unwrap_or_default())?;
+    if options
+        .expires_at
+        .map_or(false, |expires_at| expires_at <= time())
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("An agreement must expire in the future"),
+        });
+    }
 
-#[ic_cdk::query]
-fn check_status() -> String {
-    String::from("We are live")
-}
+    let commitment = match options.commitment {
+        Some(_) if !terms.is_empty() => {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "A committed agreement keeps its terms off-chain, so no terms may be sent"
+                ),
+            })
+        }
+        Some(value) => Some(Commitment::new(value)?),
+        None => None,
+    };
 
-#[ic_cdk::update]
+    // 256-bit keys are only stored as commitments, so they default to compact
+    let security_level = options.security_level.clone().unwrap_or_default();
+    let compact_keys = options
+        .compact_keys
+        .unwrap_or(security_level == SecurityLevel::Bits256);
+    limits::ensure_keys_fit(&security_level, compact_keys)?;
 
-fn initiate_agreement(terms: Vec<String>, with_user: String) -> Result<Agreement, Error> {
-    let id = AGREEMENT_ID_COUNTER.with(|counter| {
-        let counter_value = *counter.borrow().get();
-        let _ = counter.borrow_mut().set(counter_value + 1);
-        counter_value
+    let draft = _draft_agreement(terms, with_user, 0, by_user);
+    let witnesses = witness::from_requests(options.witnesses.unwrap_or_default(), &draft)?;
+    let viewers = options.viewers.unwrap_or_default();
+    if !viewers.is_empty() && options.visibility != Some(Visibility::PartiesAndViewers) {
+        return Err(Error::InvalidInput {
+            msg: format!("Viewers can only be listed on agreements visible to viewers"),
+        });
+    }
+    let viewers = visibility::normalize_viewers(viewers, [&draft.by_user, &draft.with_user])?;
+    let execution = match options.execution {
+        Some(request) => Some(ExecutionHook::from_request(request, ic_cdk::id())?),
+        None => None,
+    };
+    let payment = match options.payment {
+        Some(request) => Some(Payment::from_request(request, &draft)?),
+        None => None,
+    };
+    if let Some(hook) = &execution {
+        hook.ensure_safe_target(ic_cdk::id(), payment.as_ref().map(|payment| payment.ledger))?;
+    }
+    let agreement = Agreement {
+        template,
+        attachments,
+        witnesses,
+        tags,
+        expires_at: options.expires_at,
+        execution,
+        payment,
+        commitment,
+        visibility: options.visibility,
+        viewers,
+        key_commitments: compact_keys.then(|| (None, None)),
+        security_level: options.security_level,
+        ..draft
+    };
+    let agreement = Agreement {
+        obligations: obligation::from_requests(
+            options.obligations.unwrap_or_default(),
+            &agreement,
+            time(),
+        )?,
+        ..agreement
+    };
+    let agreement = match options.arbitrator {
+        Some(identity) => Agreement {
+            arbitrator: Some(dispute::arbitrator_from_request(identity, &agreement)?),
+            ..agreement
+        },
+        None => agreement,
+    };
+    limits::ensure_fits(&agreement)?;
+    Ok(agreement)
+}
+async fn _initiate(
+    terms: Vec<String>,
+    with_user: String,
+    template: Option<TemplateRef>,
+    options: InitiateOptions,
+) -> Result<Agreement, Error> {
+    let by_user = ic_cdk::caller().to_string();
+    let agreement = _build_agreement(terms, with_user, by_user, template, options)?;
+    _record_offer(&agreement.by_user.identity)?;
+    let agreement = Agreement {
+        id: _next_agreement_id(),
+        ..agreement
+    };
+    // An initiator who pays only signs once the deposit is in escrow
+    let deposit_first = agreement.payment.as_ref().map_or(false, |payment| {
+        payment.needs_deposit_from(&agreement.by_user.identity)
     });
+    let agreement = if deposit_first {
+        agreement
+    } else {
+        let mut agreement = _sign_as_initiator(agreement);
+        let initiator = agreement.by_user.identity.clone();
+        _stamp_signature(&mut agreement, &initiator, &initiator);
+        agreement
+    };
 
-    let agreement = _create_new_agreement(terms, with_user, id, ic_cdk::caller().to_string());
+    if let Some(expires_at) = agreement.expires_at {
+        let key = ExpiryKey {
+            expires_at,
+            agreement_id: agreement.id,
+        };
+        EXPIRATIONS.with(|index| index.borrow_mut().insert(key, agreement.id));
+    }
+    _index_attachment_uses(&agreement);
+    _save_agreement(&agreement);
+    if !deposit_first {
+        return Ok(agreement);
+    }
 
-    match AGREEMENTS.with(|db| db.borrow_mut().insert(id, agreement.clone())) {
-        Some(_) => {
-            let new_agreement = AGREEMENTS
-                .with(|storage| storage.borrow_mut().get(&agreement.clone().id))
-                .unwrap();
-            Ok(new_agreement)
-        }
+    if let Err(err) = _deposit(agreement.id).await {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Agreement {} was created but is unsigned until the deposit succeeds: {}",
+                agreement.id,
+                err.msg()
+            ),
+        });
+    }
+    let agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&agreement.id)) {
+        Some(agreement) => agreement,
         None => {
-            let new_agreement = AGREEMENTS
-                .with(|storage| storage.borrow_mut().get(&agreement.clone().id))
-                .unwrap();
-            Ok(new_agreement)
+            return Err(Error::NotFound {
+                msg: format!("That agreement was not found"),
+            })
         }
-    }
+    };
+    _ensure_open(&agreement)?;
+    let initiator = agreement.by_user.identity.clone();
+    let mut agreement = _agree_to_agreement(initiator.clone(), agreement);
+    _stamp_signature(&mut agreement, &initiator, &initiator);
+    _save_agreement(&agreement);
+    Ok(agreement)
 }
-
-#[ic_cdk::update]
-
-fn signup_user() -> String {
-    let id = USER_ID_COUNTER.with(|counter| {
-        let counter_value = *counter.borrow().get();
-        let _ = counter.borrow_mut().set(counter_value + 1);
-        counter_value
+/// Stores an agreement and brings every participant's inbox up to date with it.
+fn _save_agreement(agreement: &Agreement) {
+    _store_agreement(agreement);
+    _set_certified_data();
+}
+/// Everything `_save_agreement` does short of publishing the certified data,
+/// which only update calls can set.
+fn _store_agreement(agreement: &Agreement) {
+    AGREEMENTS.with(|storage| storage.borrow_mut().insert(agreement.id, agreement.clone()));
+    _update_inbox(agreement, time());
+    _index_deadlines(agreement);
+    _certify_agreement(agreement);
+}
+/// Watches the deadlines of a signed agreement's obligations until they are
+/// fulfilled or flagged as missed.
+fn _index_deadlines(agreement: &Agreement) {
+    if !agreement.is_fully_signed() || agreement.is_terminated() {
+        return;
+    }
+    DEADLINES.with(|index| {
+        let mut index = index.borrow_mut();
+        for obligation in agreement.obligations.iter().flatten() {
+            if obligation.is_watched() {
+                let key = DeadlineKey {
+                    due_at: obligation.due_at,
+                    agreement_id: agreement.id,
+                    obligation_id: obligation.id,
+                };
+                index.insert(key, agreement.id);
+            }
+        }
     });
-    let user = User {
-        identity: ic_cdk::caller().to_string(),
+}
+/// Counts an agreement or counter-offer against the sender's rate limit.
+fn _record_offer(identity: &str) -> Result<(), Error> {
+    let key = RateKey {
+        identity: identity.to_string(),
     };
-
-    match USERS.with(|db| db.borrow_mut().insert(id, user.clone())) {
-        Some(_) => format!("User {} created", user.identity),
-        None => format!("User {} created", user.identity),
-    }
+    RATE_LIMITS.with(|limits| {
+        let mut limits = limits.borrow_mut();
+        let window = RateWindow::record(limits.get(&key), time(), &_config().limits)?;
+        limits.insert(key, window);
+        Ok(())
+    })
 }
-
-#[ic_cdk::update]
-
-fn agree_to(agreement_id: u64) -> Result<Agreement, Error> {
-    //We are supposed to sign and store the update in stable storage
-
-    let initial_agreement = AGREEMENTS.with(|storage| storage.borrow_mut().get(&agreement_id));
-    match initial_agreement {
-        //say that the agreement was not found
-        Some(agreement) => {
-            let signed_agreement =
-                _agree_to_agreement(ic_cdk::caller().to_string(), agreement.clone());
-
-            match AGREEMENTS.with(|storage| {
-                storage
-                    .borrow_mut()
-                    .insert(agreement.clone().id, signed_agreement)
-            }) {
-                Some(agreement) => {
-                    let new_agreement = AGREEMENTS
-                        .with(|storage| storage.borrow_mut().get(&agreement.clone().id))
-                        .unwrap();
-                    Ok(new_agreement)
-                }
-                None => {
-                    let new_agreement = AGREEMENTS
-                        .with(|storage| storage.borrow_mut().get(&agreement.clone().id))
-                        .unwrap();
-                    Ok(new_agreement)
-                }
-            }
+fn _index_attachment_uses(agreement: &Agreement) {
+    ATTACHMENT_USES.with(|index| {
+        let mut index = index.borrow_mut();
+        let evidence = agreement.dispute.iter().flat_map(|dispute| {
+            [Some(&dispute.claim), dispute.response.as_ref()]
+                .into_iter()
+                .flatten()
+                .flat_map(|statement| statement.evidence.iter())
+        });
+        for attachment in agreement.attachments.iter().flatten().chain(evidence) {
+            let key = AttachmentUse {
+                attachment_id: attachment.id,
+                agreement_id: agreement.id,
+            };
+            index.insert(key, agreement.id);
         }
-        None => Err(Error::NotFound {
+    });
+}
+/// Reads an agreement on behalf of `identity`. Agreements they may not read
+/// are reported as missing so that their existence is not revealed either.
+fn _readable_agreement(agreement_id: u64, identity: &str) -> Result<Agreement, Error> {
+    match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+        Some(agreement) if agreement.can_read(identity) => Ok(agreement),
+        _ => Err(Error::NotFound {
             msg: format!("That agreement was not found"),
         }),
     }
 }
-
-#[ic_cdk::update]
-
-fn verify_signatures(agreement_id: u64) -> Result<bool, Error> {
-    let agreement = AGREEMENTS.with(|storage| storage.borrow_mut().get(&agreement_id));
-    match agreement {
-        Some(agreement) => {
-            //Reconstruct the message
-            let mut message: String = String::new();
-            for term in agreement.clone().terms.iter() {
-                message.push_str(term);
+/// The uploader can always read an attachment; anyone else needs to be able
+/// to read an agreement that embeds it.
+fn _can_read_attachment(attachment: &Attachment, identity: &str) -> bool {
+    if attachment.uploaded_by.identity == identity {
+        return true;
+    }
+    let agreement_ids: Vec<u64> = ATTACHMENT_USES.with(|index| {
+        index
+            .borrow()
+            .range(AttachmentUse::range_of(attachment.id))
+            .map(|(_, agreement_id)| agreement_id)
+            .collect()
+    });
+    agreement_ids.into_iter().any(|agreement_id| {
+        AGREEMENTS
+            .with(|storage| storage.borrow().get(&agreement_id))
+            .map_or(false, |agreement| agreement.can_read(identity))
+    })
+}
+fn _verification_for_http(agreement: &Agreement) -> Result<VerificationReport, String> {
+    cached_or_verify(agreement).map_err(|err| err.msg().to_string())
+}
+/// Stores the verification of a fully signed agreement with it. Called after
+/// every signature, attestation, reveal and ruling, which are all that change the report.
+fn _refresh_verification(agreement: &mut Agreement) {
+    agreement.verification = verify_agreement(agreement)
+        .ok()
+        .map(|report| CachedVerification::new(agreement, report, time()));
+}
+/// Certifies the hash of every page served for the agreement. Only public
+/// agreements are served, so the pages of any other agreement are removed.
+fn _certify_agreement(agreement: &Agreement) {
+    if agreement.visibility() != Visibility::Public {
+        HTTP_TREE.with(|tree| {
+            let mut tree = tree.borrow_mut();
+            for route in Route::all(agreement.id) {
+                tree.delete(route.path().as_bytes());
             }
-
-            if agreement.clone().proof_of_agreement.unwrap().0.is_none()
-                || agreement.clone().proof_of_agreement.unwrap().1.is_none()
-            {
-                return   Err(Error::NotFound {
-                    msg: format!("The agreement has only one signature hence it cannot be verified since the other person has not signed"),
-                });
+        });
+        return;
+    }
+    let verification = _verification_for_http(agreement);
+    HTTP_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for route in Route::all(agreement.id) {
+            let body = route.render(agreement, &verification);
+            tree.insert(route.path(), http::body_hash(&body));
+        }
+    });
+}
+fn _set_certified_data() {
+    let root = HTTP_TREE.with(|tree| http::certified_data(&tree.borrow()));
+    ic_cdk::api::set_certified_data(&root);
+}
+fn _update_inbox(agreement: &Agreement, now: u64) {
+    INBOX.with(|inbox| {
+        let mut inbox = inbox.borrow_mut();
+        for (identity, category) in inbox::categorize(agreement, now) {
+            let key = InboxKey {
+                identity,
+                agreement_id: agreement.id,
+            };
+            let unchanged = inbox
+                .get(&key)
+                .map_or(false, |item| item.category == category);
+            if !unchanged {
+                let item = InboxItem {
+                    agreement_id: agreement.id,
+                    category,
+                    read: false,
+                    updated_at: now,
+                };
+                inbox.insert(key, item);
             }
-
-            let signature1 = agreement
-                .clone()
-                .proof_of_agreement
-                .unwrap()
-                .0
-                .unwrap()
-                .value;
-            let signature2 = agreement
-                .clone()
-                .proof_of_agreement
-                .unwrap()
-                .1
-                .unwrap()
-                .value;
-            let key1 = agreement.clone().public_keys.unwrap().0.unwrap();
-            let key2 = agreement.clone().public_keys.unwrap().1.unwrap();
-            let signature_one_is_valid = verify(hash(&message.as_str()), &signature1, &key1);
-            let signature_two_is_valid = verify(hash(&message.as_str()), &signature2, &key2);
-            Ok(signature_one_is_valid && signature_two_is_valid)
-
-            //do something
         }
-        None => Err(Error::NotFound {
-            msg: format!("That agreement was not found"),
-        }),
+    });
+}
+fn _expire_agreements() {
+    // Expired agreements stay indexed, so the first sweep after `resume` catches up
+    if _config().paused {
+        return;
+    }
+    let now = time();
+    let due: Vec<ExpiryKey> = EXPIRATIONS.with(|index| {
+        index
+            .borrow()
+            .range(
+                ..=ExpiryKey {
+                    expires_at: now,
+                    agreement_id: u64::MAX,
+                },
+            )
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for key in due.into_iter() {
+        EXPIRATIONS.with(|index| index.borrow_mut().remove(&key));
+        let agreement = AGREEMENTS.with(|storage| storage.borrow().get(&key.agreement_id));
+        if let Some(agreement) = agreement.filter(|agreement| agreement.is_expired(now)) {
+            let canister = Principal::principal_to_user(ic_cdk::id().to_string());
+            // Agreements stored before events had room reserved may be full,
+            // and failing here would stop every later sweep. They expire by
+            // their deadline all the same, so the escrow is still refunded
+            if let Some(expired) = _record_expiry(agreement.clone(), canister, now) {
+                _save_agreement(&expired);
+            }
+            _maybe_settle(&agreement);
+        }
     }
 }
-
-#[ic_cdk::query]
-fn get_my_agreements(user_id: u64) -> Result<Vec<Agreement>, Error> {
-    let mut my_agreements: Vec<Agreement> = vec![];
-    // Borrow the USERS storage and get the user by ID
-    match USERS.with(|storage| storage.borrow().get(&user_id)) {
-        Some(user) => {
-            // Borrow the AGREEMENTS storage and collect all agreements
-            let all_agreements: Vec<(u64, Agreement)> =
-                AGREEMENTS.with(|storage| storage.borrow().iter().collect());
-
-            // Iterate through each agreement
-            for (id, agreement) in all_agreements.clone() {
-                // Debugging: print the identities being compared
-
-                if agreement.with_user.identity.trim() == user.identity.trim() {
-                    my_agreements.push(agreement.clone())
-                }
-                if agreement.by_user.identity.trim() == user.identity.trim() {
-                    my_agreements.push(agreement)
-                }
+/// Records that the agreement expired, unless the event would no longer fit
+/// in stable memory.
+fn _record_expiry(mut agreement: Agreement, canister: User, now: u64) -> Option<Agreement> {
+    agreement.record(AgreementEvent::new(now, canister, EventKind::Expired));
+    (agreement.to_bytes().len() <= Agreement::MAX_SIZE as usize).then_some(agreement)
+}
+fn _flag_missed_obligations() {
+    if _config().paused {
+        return;
+    }
+    let now = time();
+    let due: Vec<DeadlineKey> = DEADLINES.with(|index| {
+        index
+            .borrow()
+            .range(
+                ..=DeadlineKey {
+                    due_at: now,
+                    agreement_id: u64::MAX,
+                    obligation_id: u32::MAX,
+                },
+            )
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for key in due.into_iter() {
+        DEADLINES.with(|index| index.borrow_mut().remove(&key));
+        let agreement = AGREEMENTS.with(|storage| storage.borrow().get(&key.agreement_id));
+        if let Some(mut agreement) = agreement.filter(|agreement| !agreement.is_terminated()) {
+            let canister = Principal::principal_to_user(ic_cdk::id().to_string());
+            // Agreements stored before claims were capped may have no room
+            // left for the event, and failing here would stop every later sweep
+            if obligation::flag_missed(&mut agreement, key.obligation_id, canister, now)
+                && agreement.to_bytes().len() <= Agreement::MAX_SIZE as usize
+            {
+                _save_agreement(&agreement);
             }
-
-            Ok(my_agreements)
         }
-        None => Err(Error::NotFound {
-            msg: format!("User with ID {} wasn't found.", user_id),
-        }),
     }
 }
-
-#[ic_cdk::query]
-fn get_single_agreement(agreement_id: u64) -> Result<Agreement, Error> {
-    let agreement = AGREEMENTS.with(|storage| storage.borrow_mut().get(&agreement_id));
-    match agreement {
-        Some(agreement) => Ok(agreement),
-        None => Err(Error::NotFound {
-            msg: format!("That agreement  wasn't found sorry "),
-        }),
+fn _start_timers() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_SWEEP_INTERVAL, _expire_agreements);
+    ic_cdk_timers::set_timer_interval(DEADLINE_SWEEP_INTERVAL, _flag_missed_obligations);
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(_load_module_hash()));
+}
+/// The optional work `_reindex_agreements` does besides certifying each
+/// agreement and rescheduling its retries and settlement.
+#[derive(Clone, Copy)]
+struct Reindex {
+    inbox: bool,
+    attachment_uses: bool,
+    /// Verifies every agreement again instead of trusting the stored reports,
+    /// which a restored snapshot carries from elsewhere.
+    verification: bool,
+}
+/// The certification tree and the timers live on the heap, so after an upgrade
+/// or a restore every agreement is certified and its retries and settlement
+/// scheduled again. The pages of an agreement are not certified until its
+/// batch has run.
+fn _start_reindex(reindex: Reindex) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || _reindex_agreements(0, reindex));
+}
+/// Handles the agreements from ID `from` on, a batch per message so that no
+/// single message verifies every stored signature.
+fn _reindex_agreements(from: u64, reindex: Reindex) {
+    let now = time();
+    let batch: Vec<Agreement> = AGREEMENTS.with(|storage| {
+        storage
+            .borrow()
+            .range(from..)
+            .take(REINDEX_BATCH_SIZE)
+            .map(|(_, agreement)| agreement)
+            .collect()
+    });
+    let next = batch.last().map(|agreement| agreement.id + 1);
+    let full = batch.len() == REINDEX_BATCH_SIZE;
+    for mut agreement in batch.into_iter() {
+        // Reports cached before they recorded their digest are made again
+        let stale = agreement
+            .verification
+            .as_ref()
+            .map_or(false, |cached| cached.digest.is_none());
+        if reindex.verification || stale {
+            _refresh_verification(&mut agreement);
+            AGREEMENTS.with(|storage| storage.borrow_mut().insert(agreement.id, agreement.clone()));
+        }
+        if reindex.inbox {
+            _update_inbox(&agreement, now);
+        }
+        if reindex.attachment_uses {
+            _index_attachment_uses(&agreement);
+        }
+        _certify_agreement(&agreement);
+        _rearm_execution(&agreement, now);
+        _maybe_settle(&agreement);
+    }
+    _set_certified_data();
+    if full {
+        if let Some(next) = next {
+            ic_cdk_timers::set_timer(Duration::ZERO, move || _reindex_agreements(next, reindex));
+        }
     }
 }
-#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
-enum Error {
-    NotFound { msg: String },
+/// Looks up the hash of the running module, which signatures record. A
+/// canister cannot read its own module, so it asks the management canister.
+/// If the lookup fails, signatures leave the hash out.
+async fn _load_module_hash() {
+    let request = CanisterInfoRequest {
+        canister_id: ic_cdk::id(),
+        num_requested_changes: None,
+    };
+    if let Ok((info,)) = canister_info(request).await {
+        let module_hash = info.module_hash.map(hex::encode);
+        MODULE_HASH.with(|hash| *hash.borrow_mut() = module_hash);
+    }
+}
+/// Schedules the execution hook once every party signature and witness
+/// attestation is present and verifies.
+fn _maybe_execute(agreement: &Agreement) {
+    let pending = agreement.execution.as_ref().map_or(false, |hook| {
+        hook.attempts.is_empty() && hook.next_attempt_at.is_none()
+    });
+    if pending && agreement.is_complete() && _is_verified(agreement) {
+        _schedule_execution(agreement.id, Duration::ZERO);
+    }
+    _maybe_settle(agreement);
+}
+fn _is_verified(agreement: &Agreement) -> bool {
+    match cached_or_verify(agreement) {
+        Ok(report) => report.parties_valid && report.witnesses.iter().all(|witness| witness.valid),
+        Err(_) => false,
+    }
+}
+/// Schedules a pending retry again, since timers do not survive an upgrade.
+fn _rearm_execution(agreement: &Agreement, now: u64) {
+    if let Some(delay) = agreement
+        .execution
+        .as_ref()
+        .and_then(|hook| hook.retry_delay(now))
+    {
+        _schedule_execution(agreement.id, delay);
+    }
+}
+fn _schedule_execution(agreement_id: u64, delay: Duration) {
+    ic_cdk_timers::set_timer(delay, move || ic_cdk::spawn(_execute(agreement_id)));
+}
+async fn _execute(agreement_id: u64) {
+    let mut agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+        Some(agreement) => agreement,
+        None => return,
+    };
+    let hook = match agreement
+        .execution
+        .clone()
+        .filter(|hook| hook.can_attempt())
+    {
+        Some(hook) => hook,
+        None => return,
+    };
+    if _config().paused {
+        _schedule_execution(agreement_id, PAUSED_RECHECK_DELAY);
+        return;
+    }
+    // Hooks stored before their targets were checked are checked here
+    let escrow_ledger = agreement.payment.as_ref().map(|payment| payment.ledger);
+    if let Err(err) = hook.ensure_safe_target(ic_cdk::id(), escrow_ledger) {
+        if let Some(hook) = agreement.execution.as_mut() {
+            hook.refuse(time(), err.msg().to_string());
+        }
+        _save_agreement(&agreement);
+        return;
+    }
+    if !EXECUTING.with(|executing| executing.borrow_mut().insert(agreement_id)) {
+        return;
+    }
+
+    let result =
+        ic_cdk::api::call::call_raw(hook.canister_id, &hook.method, hook.arg.clone(), 0).await;
+    EXECUTING.with(|executing| executing.borrow_mut().remove(&agreement_id));
+
+    let outcome = match result {
+        Ok(reply) => ExecutionOutcome::Succeeded { reply },
+        Err((code, msg)) => ExecutionOutcome::Failed {
+            reason: format!("{:?}: {}", code, msg),
+        },
+    };
+    let mut agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+        Some(agreement) => agreement,
+        None => return,
+    };
+    let retry = match agreement.execution.as_mut() {
+        Some(hook) => hook.record(time(), outcome),
+        None => return,
+    };
+    _save_agreement(&agreement);
+    if let Some(delay) = retry {
+        _schedule_execution(agreement_id, delay);
+    }
+}
+/// Mints one token to each party once the agreement is fully signed, if the
+/// collection is enabled. Parties whose identity is not a principal get no token.
+fn _mint_tokens(agreement: &mut Agreement) {
+    let enabled = NFT_CONFIG.with(|config| config.borrow().get().enabled);
+    if !enabled || agreement.tokens.is_some() || !agreement.is_fully_signed() {
+        return;
+    }
+    let digest = agreement.security_level().digest(&agreement.message());
+    let now = time();
+    let mut minted: Vec<u64> = vec![];
+    for party in [&agreement.by_user, &agreement.with_user] {
+        let owner = match Principal::from_text(party.identity.trim()) {
+            Ok(owner) if owner != Principal::anonymous() => owner,
+            _ => continue,
+        };
+        let token = Token {
+            id: _next_id(&TOKEN_ID_COUNTER),
+            owner: Account {
+                owner,
+                subaccount: None,
+            },
+            agreement_id: agreement.id,
+            digest: digest.clone(),
+            minted_at: now,
+        };
+        _store_token(&token);
+        _next_id(&TRANSACTION_COUNTER);
+        minted.push(token.id);
+    }
+    agreement.tokens = Some(minted);
+}
+fn _store_token(token: &Token) {
+    TOKENS.with(|storage| storage.borrow_mut().insert(token.id, token.clone()));
+    TOKEN_OWNERS.with(|index| {
+        index
+            .borrow_mut()
+            .insert(OwnerKey::new(&token.owner, token.id), token.id)
+    });
+}
+fn _next_id(counter: &'static std::thread::LocalKey<RefCell<IdCell>>) -> u64 {
+    counter.with(|counter| {
+        let counter_value = *counter.borrow().get();
+        let _ = counter.borrow_mut().set(counter_value + 1);
+        counter_value
+    })
+}
+fn _transfer_token(arg: &TransferArg, caller: Principal) -> Result<Nat, TransferError> {
+    let token = nft::token_id(&arg.token_id)
+        .and_then(|token_id| TOKENS.with(|storage| storage.borrow().get(&token_id)));
+    let previous_owner = token.as_ref().map(|token| token.owner.clone());
+    let token = nft::check_transfer(arg, caller, token)?;
+    if let Some(previous_owner) = previous_owner {
+        TOKEN_OWNERS.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&OwnerKey::new(&previous_owner, token.id))
+        });
+    }
+    _store_token(&token);
+    Ok(Nat::from(_next_id(&TRANSACTION_COUNTER)))
+}
+fn _collection_config() -> CollectionConfig {
+    NFT_CONFIG.with(|config| config.borrow().get().clone())
+}
+fn _config() -> Config {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+fn _set_config(config: Config) {
+    CONFIG
+        .with(|cell| cell.borrow_mut().set(config))
+        .expect("Cannot store the canister config");
+}
+fn _ensure_admin() -> Result<Principal, Error> {
+    let caller = ic_cdk::caller();
+    if !_config().is_admin(&caller, ic_cdk::api::is_controller(&caller)) {
+        return Err(Error::Unauthorized {
+            msg: format!("Only an admin can do that"),
+        });
+    }
+    Ok(caller)
+}
+fn _log_admin_action(by: Principal, kind: AdminActionKind) {
+    let action = AdminAction {
+        id: _next_id(&ADMIN_LOG_ID_COUNTER),
+        at: time(),
+        by,
+        kind,
+    };
+    ADMIN_LOG.with(|log| log.borrow_mut().insert(action.id, action));
+}
+/// Writes the entries of `map` that follow the key `after` until the snapshot
+/// has grown to `until` bytes. Returns the key of the last entry written if the
+/// table was not finished.
+fn _backup_entries<K, V>(
+    map: &'static std::thread::LocalKey<RefCell<BTreeMap<K, V, Memory>>>,
+    after: Option<Vec<u8>>,
+    until: u64,
+    backup: &mut Backup,
+    entry: impl Fn(K, V) -> Entry,
+) -> Option<Vec<u8>>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    let start = match after {
+        Some(bytes) => Bound::Excluded(K::from_bytes(Cow::Owned(bytes))),
+        None => Bound::Unbounded,
+    };
+    map.with(|map| {
+        BACKUP_MEMORY.with(|memory| {
+            let mut memory = memory.borrow_mut();
+            for (key, value) in map.borrow().range((start, Bound::Unbounded)) {
+                let last = key.to_bytes().into_owned();
+                backup.append(&mut *memory, &entry(key, value));
+                if backup.size() >= until {
+                    return Some(last);
+                }
+            }
+            None
+        })
+    })
+}
+fn _insert<K, V>(
+    map: &'static std::thread::LocalKey<RefCell<BTreeMap<K, V, Memory>>>,
+    key: K,
+    value: V,
+) where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    map.with(|map| map.borrow_mut().insert(key, value));
+}
+fn _counter(counter: &'static std::thread::LocalKey<RefCell<IdCell>>) -> u64 {
+    counter.with(|counter| *counter.borrow().get())
+}
+fn _set_counter(counter: &'static std::thread::LocalKey<RefCell<IdCell>>, value: u64) {
+    counter.with(|counter| {
+        let _ = counter.borrow_mut().set(value);
+    });
+}
+/// Writes about `budget` bytes of entries to the backup memory, going on from
+/// where the last batch stopped, and seals the snapshot after the last table.
+/// Heap state such as the certified HTTP tree is derived from the stable
+/// structures and rebuilt after a restore.
+fn _write_backup_batch(backup: &mut Backup, budget: u64) {
+    let until = backup.size().saturating_add(budget);
+    while let Some((table, after)) = backup.cursor.take() {
+        let stopped = match table {
+            Table::Users => _backup_entries(&USERS, after, until, backup, Entry::User),
+            Table::Agreements => {
+                _backup_entries(&AGREEMENTS, after, until, backup, |id, agreement| {
+                    Entry::Agreement(id, Box::new(agreement))
+                })
+            }
+            Table::Templates => _backup_entries(&TEMPLATES, after, until, backup, |_, template| {
+                Entry::Template(template)
+            }),
+            Table::Attachments => {
+                _backup_entries(&ATTACHMENTS, after, until, backup, Entry::Attachment)
+            }
+            Table::AttachmentChunks => {
+                _backup_entries(&ATTACHMENT_CHUNKS, after, until, backup, |key, chunk| {
+                    Entry::AttachmentChunk(key, chunk.0)
+                })
+            }
+            Table::AttachmentUses => {
+                _backup_entries(&ATTACHMENT_USES, after, until, backup, Entry::AttachmentUse)
+            }
+            Table::Delegations => {
+                _backup_entries(&DELEGATIONS, after, until, backup, Entry::Delegation)
+            }
+            Table::Inbox => _backup_entries(&INBOX, after, until, backup, Entry::InboxItem),
+            Table::Expirations => {
+                _backup_entries(&EXPIRATIONS, after, until, backup, Entry::Expiration)
+            }
+            Table::Deadlines => _backup_entries(&DEADLINES, after, until, backup, Entry::Deadline),
+            Table::Tokens => _backup_entries(&TOKENS, after, until, backup, Entry::Token),
+            Table::TokenOwners => {
+                _backup_entries(&TOKEN_OWNERS, after, until, backup, Entry::TokenOwner)
+            }
+            Table::RateLimits => {
+                _backup_entries(&RATE_LIMITS, after, until, backup, Entry::RateLimit)
+            }
+            Table::AdminLog => {
+                _backup_entries(&ADMIN_LOG, after, until, backup, Entry::AdminAction)
+            }
+            Table::Settings => {
+                let settings = Entry::Settings {
+                    collection: _collection_config(),
+                    config: _config(),
+                    counters: Counters {
+                        users: _counter(&USER_ID_COUNTER),
+                        agreements: _counter(&AGREEMENT_ID_COUNTER),
+                        templates: _counter(&TEMPLATE_ID_COUNTER),
+                        attachments: _counter(&ATTACHMENT_ID_COUNTER),
+                        delegations: _counter(&DELEGATION_ID_COUNTER),
+                        tokens: _counter(&TOKEN_ID_COUNTER),
+                        transactions: _counter(&TRANSACTION_COUNTER),
+                        admin_log: _counter(&ADMIN_LOG_ID_COUNTER),
+                    },
+                };
+                BACKUP_MEMORY.with(|memory| backup.append(&mut *memory.borrow_mut(), &settings));
+                None
+            }
+        };
+        backup.cursor = match stopped {
+            Some(last) => Some((table, Some(last))),
+            None => table.next().map(|table| (table, None)),
+        };
+        if backup.size() >= until {
+            break;
+        }
+    }
+    backup.finish();
+}
+/// Whether nothing has been created yet, so that a snapshot can be restored.
+fn _is_fresh() -> bool {
+    USERS.with(|storage| storage.borrow().is_empty())
+        && AGREEMENTS.with(|storage| storage.borrow().is_empty())
+        && TEMPLATES.with(|storage| storage.borrow().is_empty())
+        && ATTACHMENTS.with(|storage| storage.borrow().is_empty())
+        && DELEGATIONS.with(|storage| storage.borrow().is_empty())
+        && TOKENS.with(|storage| storage.borrow().is_empty())
+}
+fn _ensure_fresh() -> Result<(), Error> {
+    if !_is_fresh() {
+        return Err(Error::InvalidInput {
+            msg: "A snapshot can only be restored into a fresh canister".to_string(),
+        });
+    }
+    Ok(())
+}
+/// Snapshots are taken and restored across several calls, so nothing may be
+/// written in between.
+fn _ensure_paused() -> Result<(), Error> {
+    if !_config().paused {
+        return Err(Error::InvalidInput {
+            msg: "Pause the canister before taking or restoring a snapshot".to_string(),
+        });
+    }
+    Ok(())
+}
+fn _restore_entry(entry: Entry) {
+    match entry {
+        Entry::User(id, user) => _insert(&USERS, id, user),
+        Entry::Agreement(id, agreement) => _insert(&AGREEMENTS, id, *agreement),
+        Entry::Template(template) => _insert(&TEMPLATES, template.key(), template),
+        Entry::Attachment(id, attachment) => _insert(&ATTACHMENTS, id, attachment),
+        Entry::AttachmentChunk(key, bytes) => _insert(&ATTACHMENT_CHUNKS, key, Chunk(bytes)),
+        Entry::AttachmentUse(key, value) => _insert(&ATTACHMENT_USES, key, value),
+        Entry::Delegation(id, delegation) => _insert(&DELEGATIONS, id, delegation),
+        Entry::InboxItem(key, item) => _insert(&INBOX, key, item),
+        Entry::Expiration(key, id) => _insert(&EXPIRATIONS, key, id),
+        Entry::Deadline(key, id) => _insert(&DEADLINES, key, id),
+        Entry::Token(id, token) => _insert(&TOKENS, id, token),
+        Entry::TokenOwner(key, id) => _insert(&TOKEN_OWNERS, key, id),
+        Entry::RateLimit(key, window) => _insert(&RATE_LIMITS, key, window),
+        Entry::AdminAction(id, action) => _insert(&ADMIN_LOG, id, action),
+        Entry::Settings {
+            collection,
+            config,
+            counters,
+        } => {
+            NFT_CONFIG
+                .with(|cell| cell.borrow_mut().set(collection))
+                .expect("Cannot store the collection config");
+            _set_config(config);
+            _set_counter(&USER_ID_COUNTER, counters.users);
+            _set_counter(&AGREEMENT_ID_COUNTER, counters.agreements);
+            _set_counter(&TEMPLATE_ID_COUNTER, counters.templates);
+            _set_counter(&ATTACHMENT_ID_COUNTER, counters.attachments);
+            _set_counter(&DELEGATION_ID_COUNTER, counters.delegations);
+            _set_counter(&TOKEN_ID_COUNTER, counters.tokens);
+            _set_counter(&TRANSACTION_COUNTER, counters.transactions);
+            _set_counter(&ADMIN_LOG_ID_COUNTER, counters.admin_log);
+        }
+    }
+}
+/// Restores about `budget` bytes of entries from the uploaded snapshot. The
+/// first batch checks the snapshot and that the canister is still fresh.
+fn _apply_restore_batch(restore: &mut Restore, budget: u64) -> Result<(), Error> {
+    let first = !restore.is_applying();
+    if first {
+        _ensure_fresh()?;
+    }
+    let entries = BACKUP_MEMORY.with(|memory| restore.next_entries(&*memory.borrow(), budget))?;
+    if first {
+        // The fresh canister's own log gives way to the restored one
+        let installed: Vec<u64> =
+            ADMIN_LOG.with(|log| log.borrow().iter().map(|(id, _)| id).collect());
+        ADMIN_LOG.with(|log| {
+            let mut log = log.borrow_mut();
+            for id in installed.iter() {
+                log.remove(id);
+            }
+        });
+    }
+    for entry in entries.into_iter() {
+        _restore_entry(entry);
+    }
+    Ok(())
+}
+/// Guards every update call that writes, so that admins can stop writes during an incident.
+fn _not_paused() -> Result<(), String> {
+    if _config().paused {
+        return Err(String::from(
+            "The canister is paused for maintenance. Please try again later",
+        ));
+    }
+    Ok(())
+}
+/// Pulls the payer's deposit into the agreement's escrow subaccount. The payer
+/// must have approved this canister for the amount plus the ledger fee.
+async fn _deposit(agreement_id: u64) -> Result<(), Error> {
+    let payment = match AGREEMENTS
+        .with(|storage| storage.borrow().get(&agreement_id))
+        .and_then(|agreement| agreement.payment)
+    {
+        Some(payment) => payment,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("That agreement has no payment"),
+            })
+        }
+    };
+    if !DEPOSITING.with(|depositing| depositing.borrow_mut().insert(agreement_id)) {
+        return Err(Error::InvalidInput {
+            msg: format!("A deposit for that agreement is already in progress"),
+        });
+    }
+    let result = _transfer_into_escrow(agreement_id, &payment).await;
+    DEPOSITING.with(|depositing| depositing.borrow_mut().remove(&agreement_id));
+    let (block, fee) = result?;
+
+    let mut agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+        Some(agreement) => agreement,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("That agreement was not found"),
+            })
+        }
+    };
+    if let Some(payment) = agreement.payment.as_mut() {
+        payment.fee = Some(fee);
+        payment.status = PaymentStatus::Deposited { block, at: time() };
+    }
+    _save_agreement(&agreement);
+    // The agreement may have expired or been terminated while the ledger was busy
+    _maybe_settle(&agreement);
+    Ok(())
+}
+async fn _transfer_into_escrow(agreement_id: u64, payment: &Payment) -> Result<(Nat, Nat), Error> {
+    let unreachable =
+        |(code, msg): (ic_cdk::api::call::RejectionCode, String)| Error::InvalidInput {
+            msg: format!("The ledger could not be reached: {:?}: {}", code, msg),
+        };
+    let (fee,): (Nat,) = ic_cdk::call(payment.ledger, "icrc1_fee", ())
+        .await
+        .map_err(unreachable)?;
+    let payer = match Principal::from_text(payment.payer.identity.trim()) {
+        Ok(payer) => payer,
+        Err(_) => {
+            return Err(Error::InvalidInput {
+                msg: format!("{} cannot hold tokens on a ledger", payment.payer.identity),
+            })
+        }
+    };
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: payer,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(escrow::escrow_subaccount(agreement_id)),
+        },
+        amount: payment.amount.clone() + fee.clone(),
+        fee: Some(fee.clone()),
+        memo: Some(agreement_id.to_be_bytes().to_vec()),
+        created_at_time: None,
+    };
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(payment.ledger, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(unreachable)?;
+    match result {
+        Ok(block) => Ok((block, fee)),
+        Err(err) => Err(Error::InvalidInput {
+            msg: format!("The ledger rejected the deposit: {:?}", err),
+        }),
+    }
+}
+fn _maybe_settle(agreement: &Agreement) {
+    let settlement = agreement
+        .payment
+        .as_ref()
+        .and_then(|payment| payment.settlement(agreement, time()));
+    match settlement {
+        Some(Settlement::Release) if !_is_verified(agreement) => {}
+        Some(_) => _schedule_settlement(agreement.id, Duration::ZERO),
+        None => {}
+    }
+}
+fn _schedule_settlement(agreement_id: u64, delay: Duration) {
+    ic_cdk_timers::set_timer(delay, move || ic_cdk::spawn(_settle(agreement_id)));
+}
+/// Releases the escrowed funds to the payee, or refunds them to the payer.
+async fn _settle(agreement_id: u64) {
+    let agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+        Some(agreement) => agreement,
+        None => return,
+    };
+    let (payment, settlement) = match agreement.payment.clone().and_then(|payment| {
+        payment
+            .settlement(&agreement, time())
+            .map(|settlement| (payment, settlement))
+    }) {
+        Some(pending) => pending,
+        None => return,
+    };
+    if settlement == Settlement::Release && !_is_verified(&agreement) {
+        return;
+    }
+    if _config().paused {
+        _schedule_settlement(agreement_id, PAUSED_RECHECK_DELAY);
+        return;
+    }
+    if !SETTLING.with(|settling| settling.borrow_mut().insert(agreement_id)) {
+        return;
+    }
+
+    let args = Icrc1TransferArg {
+        from_subaccount: Some(escrow::escrow_subaccount(agreement_id)),
+        to: payment.recipient(&settlement),
+        amount: payment.amount.clone(),
+        fee: payment.fee.clone(),
+        memo: Some(agreement_id.to_be_bytes().to_vec()),
+        created_at_time: None,
+    };
+    let result: Result<(Result<Nat, Icrc1TransferError>,), _> =
+        ic_cdk::call(payment.ledger, "icrc1_transfer", (args,)).await;
+    SETTLING.with(|settling| settling.borrow_mut().remove(&agreement_id));
+
+    let mut agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+        Some(agreement) => agreement,
+        None => return,
+    };
+    let payment = match agreement.payment.as_mut() {
+        Some(payment) => payment,
+        None => return,
+    };
+    let now = time();
+    let retry = match result {
+        Ok((Ok(block),)) => {
+            payment.status = match settlement {
+                Settlement::Release => PaymentStatus::Released { block, at: now },
+                Settlement::Refund => PaymentStatus::Refunded { block, at: now },
+            };
+            payment.last_error = None;
+            false
+        }
+        Ok((Err(err),)) => {
+            payment.attempts += 1;
+            payment.last_error = Some(format!("{:?}", err));
+            payment.attempts < escrow::MAX_SETTLEMENT_ATTEMPTS
+        }
+        Err((code, msg)) => {
+            payment.attempts += 1;
+            payment.last_error = Some(format!("{:?}: {}", code, msg));
+            payment.attempts < escrow::MAX_SETTLEMENT_ATTEMPTS
+        }
+    };
+    _save_agreement(&agreement);
+    if retry {
+        _schedule_settlement(agreement_id, SETTLEMENT_RETRY_DELAY);
+    }
+}
+fn _ensure_no_deposit_in_progress(agreement_id: u64) -> Result<(), Error> {
+    if DEPOSITING.with(|depositing| depositing.borrow().contains(&agreement_id)) {
+        return Err(Error::InvalidInput {
+            msg: format!("A deposit for that agreement is in progress"),
+        });
+    }
+    Ok(())
+}
+/// Fails unless the agreement can still be signed or changed.
+fn _ensure_open(agreement: &Agreement) -> Result<(), Error> {
+    if agreement.is_terminated() {
+        return Err(Error::InvalidInput {
+            msg: format!("That agreement has been terminated"),
+        });
+    }
+    if agreement.is_expired(time()) {
+        return Err(Error::InvalidInput {
+            msg: format!("That agreement has expired"),
+        });
+    }
+    Ok(())
+}
+fn _latest_template(template_id: u64) -> Option<Template> {
+    TEMPLATES.with(|storage| {
+        storage
+            .borrow()
+            .range(
+                TemplateKey {
+                    id: template_id,
+                    version: 0,
+                }..=TemplateKey {
+                    id: template_id,
+                    version: u32::MAX,
+                },
+            )
+            .last()
+            .map(|(_, template)| template)
+    })
+}
+/// Records when `party` signed, who made the call and with which module.
+fn _stamp_signature(agreement: &mut Agreement, party: &str, signer: &str) {
+    let module_hash = MODULE_HASH.with(|hash| hash.borrow().clone());
+    let signer = Principal::principal_to_user(signer.to_string());
+    let metadata = SignerMetadata::new(signer, agreement, module_hash);
+    agreement.stamp_signature(party, Timestamp::new(time()), metadata);
+}
+fn _agree_to_agreement(user: String, agreement: Agreement) -> Agreement {
+    let agreeing_party = Principal::principal_to_user(user);
+    agreeing_party.agree(agreement)
+}
+fn _agree_on_behalf(delegation: &Delegation, agreement: Agreement) -> Agreement {
+    let principal_signs_first = agreement.is_initiator(&delegation.grantor.identity);
+    let mut signed_agreement = delegation
+        .delegate
+        .clone()
+        .agree_for(&delegation.grantor, agreement);
+    let signature = match signed_agreement.proof_of_agreement.as_mut() {
+        Some((signature, _)) if principal_signs_first => signature.as_mut(),
+        Some((_, signature)) => signature.as_mut(),
+        None => None,
+    };
+    if let Some(signature) = signature {
+        signature.delegation = Some(DelegatedSignature {
+            principal: delegation.grantor.clone(),
+            delegate: delegation.delegate.clone(),
+            delegation_id: delegation.id,
+        });
+    }
+    signed_agreement
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn agreement_btwn_god_and_man() {
+        let terms: Vec<String> = vec![
+        "I am the Lord thy God".to_string(),
+        "Thou shalt have no other gods before me".to_string(),
+        "Thou shalt not make unto thee any graven image".to_string(),
+        "Thou shalt not take the name of the Lord thy God in vain".to_string(),
+        "Remember the sabbath day, to keep it holy".to_string(),
+        "Honour thy father and thy mother".to_string(),
+        "Thou shalt not kill".to_string(),
+        "Thou shalt not commit adultery".to_string(),
+        "Thou shalt not steal".to_string(),
+        "Thou shalt not bear false witness against thy neighbour".to_string(),
+        "Thou shalt not covet thy neighbour's house".to_string(),
+        "Thou shalt not covet thy neighbour's wife, nor his manservant, nor his maidservant, nor his ox, nor his ass, nor any thing that is thy neighbour's".to_string(),
+    ];
+        let man = Principal::principal_to_user(String::from("the heck"));
+        let agreement = man.clone().new_agreement(
+            terms,
+            String::from("0"),
+            Principal::principal_to_user(String::from("God")),
+            man,
+            1,
+        );
+        let amschel_agrees = _agree_to_agreement(String::from("God"), agreement);
+        dbg!(amschel_agrees.proof_of_agreement.unwrap().1.unwrap().value);
+    }
+    #[test]
+    fn _agree_to_agreement_works() {}
+
+    #[test]
+    fn counter_offer_is_signed_in_the_proposers_place() {
+        let alice = Principal::principal_to_user(String::from("alice"));
+        let bob = Principal::principal_to_user(String::from("bob"));
+        let offer = alice.clone().new_agreement(
+            vec!["Bob delivers 10 chairs".to_string()],
+            String::from("0"),
+            bob.clone(),
+            alice.clone(),
+            1,
+        );
+        let offer = _sign_as_initiator(offer);
+
+        // Bob counters: only his signature is on the new terms
+        let counter_offer = _agree_to_agreement(
+            bob.identity.clone(),
+            Agreement {
+                terms: vec!["Bob delivers 8 chairs".to_string()],
+                proof_of_agreement: None,
+                public_keys: None,
+                ..offer
+            },
+        );
+        assert!(matches!(
+            counter_offer.proof_of_agreement,
+            Some((None, Some(_)))
+        ));
+        assert!(verify_agreement(&counter_offer).is_err());
+
+        // Alice accepts the counter-offer
+        let accepted = _agree_to_agreement(alice.identity, counter_offer);
+        assert!(accepted.is_fully_signed());
+        assert!(verify_agreement(&accepted).unwrap().parties_valid);
+    }
+
+    #[test]
+    fn restored_state_is_identical_to_the_backup() {
+        let alice = Principal::principal_to_user(String::from("2vxsx-fae"));
+        let bob = Principal::principal_to_user(String::from("aaaaa-aa"));
+        let agreement = alice.clone().new_agreement(
+            vec!["Bob delivers 10 chairs".to_string()],
+            String::from("0"),
+            bob.clone(),
+            alice.clone(),
+            _next_agreement_id(),
+        );
+        let agreement = _agree_to_agreement(bob.identity.clone(), _sign_as_initiator(agreement));
+        USERS.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert(_next_id(&USER_ID_COUNTER), alice)
+        });
+        AGREEMENTS.with(|storage| storage.borrow_mut().insert(agreement.id, agreement.clone()));
+        _update_inbox(&agreement, 0);
+        let attachment_id = _next_id(&ATTACHMENT_ID_COUNTER);
+        let attachment = Attachment::new(
+            attachment_id,
+            String::from("chairs.txt"),
+            String::from("text/plain"),
+            3,
+            bob,
+        )
+        .unwrap();
+        ATTACHMENTS.with(|storage| storage.borrow_mut().insert(attachment_id, attachment));
+        ATTACHMENT_CHUNKS.with(|storage| {
+            storage.borrow_mut().insert(
+                ChunkKey {
+                    attachment_id,
+                    index: 0,
+                },
+                Chunk(vec![1, 2, 3]),
+            )
+        });
+        _store_token(&Token {
+            id: _next_id(&TOKEN_ID_COUNTER),
+            owner: Account {
+                owner: Principal::anonymous(),
+                subaccount: None,
+            },
+            agreement_id: agreement.id,
+            digest: lamport::hash(&agreement.message()),
+            minted_at: 0,
+        });
+        _set_config(Config {
+            paused: true,
+            .._config()
+        });
+
+        // A budget of one byte writes a single entry per batch
+        let mut backup = Backup::new(0);
+        let mut batches = 0;
+        while backup.manifest().is_none() {
+            _write_backup_batch(&mut backup, 1);
+            batches += 1;
+        }
+        assert!(batches > 5);
+        let manifest = backup.manifest().unwrap().clone();
+        let chunks: Vec<Vec<u8>> = BACKUP_MEMORY.with(|memory| {
+            (0..manifest.chunk_count)
+                .map(|index| backup.chunk(&*memory.borrow(), index).unwrap())
+                .collect()
+        });
+        let uploaded = chunks.clone();
+        // Every thread has its own stable memory, so this one is a fresh canister
+        let restored = std::thread::spawn(move || {
+            assert!(_is_fresh());
+            let mut restore = Restore::new(manifest).unwrap();
+            BACKUP_MEMORY.with(|memory| {
+                for (index, chunk) in uploaded.iter().enumerate() {
+                    restore
+                        .add_chunk(&mut *memory.borrow_mut(), index as u32, chunk)
+                        .unwrap();
+                }
+            });
+            while !restore.is_done() {
+                _apply_restore_batch(&mut restore, 1).unwrap();
+            }
+            assert!(!_is_fresh());
+            assert!(_config().paused);
+            let mut backup = Backup::new(0);
+            _write_backup_batch(&mut backup, u64::MAX);
+            let manifest = backup.manifest().unwrap().clone();
+            let chunks: Vec<Vec<u8>> = BACKUP_MEMORY.with(|memory| {
+                (0..manifest.chunk_count)
+                    .map(|index| backup.chunk(&*memory.borrow(), index).unwrap())
+                    .collect()
+            });
+            (manifest, chunks)
+        })
+        .join()
+        .unwrap();
+        assert_eq!(&restored.0, backup.manifest().unwrap());
+        assert_eq!(restored.1, chunks);
+    }
+
+    #[test]
+    fn full_agreements_expire_without_the_event() {
+        let alice = Principal::principal_to_user(String::from("alice"));
+        let bob = Principal::principal_to_user(String::from("bob"));
+        let canister = Principal::principal_to_user(String::from("rrkah-fqaaa-aaaaa-aaaaq-cai"));
+        let mut agreement =
+            alice
+                .clone()
+                .new_agreement(vec![String::new()], String::from("0"), bob, alice, 1);
+        let expired = _record_expiry(agreement.clone(), canister.clone(), 1).unwrap();
+        assert!(matches!(
+            expired.history.unwrap().last().unwrap().kind,
+            EventKind::Expired
+        ));
+
+        // Fill the agreement to the byte, as one stored before events had room reserved
+        let room = Agreement::MAX_SIZE as usize - agreement.to_bytes().len();
+        agreement.terms = vec!["x".repeat(room - 2)];
+        assert_eq!(agreement.to_bytes().len(), Agreement::MAX_SIZE as usize);
+        assert!(_record_expiry(agreement, canister, 1).is_none());
+    }
+}
+
+// Internet computer functions here
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    let config = match _config().apply(args.unwrap_or_default()) {
+        Ok(config) => config,
+        Err(err) => ic_cdk::trap(err.msg()),
+    };
+    _log_admin_action(
+        ic_cdk::caller(),
+        AdminActionKind::Installed {
+            admins: config.admins.clone(),
+            limits: config.limits.clone(),
+        },
+    );
+    _set_config(config);
+    _start_timers();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    if let Some(args) = args {
+        let config = match _config().apply(args) {
+            Ok(config) => config,
+            Err(err) => ic_cdk::trap(err.msg()),
+        };
+        _log_admin_action(
+            ic_cdk::caller(),
+            AdminActionKind::Upgraded {
+                admins: config.admins.clone(),
+                limits: config.limits.clone(),
+            },
+        );
+        _set_config(config);
+    }
+    let reindex = Reindex {
+        // Agreements stored before the inbox existed are indexed once
+        inbox: INBOX.with(|inbox| inbox.borrow().is_empty()),
+        // Agreements stored before attachment reads were restricted are indexed once
+        attachment_uses: ATTACHMENT_USES.with(|index| index.borrow().is_empty()),
+        verification: false,
+    };
+    _set_certified_data();
+    _start_timers();
+    _start_reindex(reindex);
+}
+
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    if _config().paused && !config::is_admin_method(&method) {
+        return;
+    }
+    let anonymous = ic_cdk::caller() == Principal::anonymous();
+    if limits::accepts_ingress(&method, anonymous, ic_cdk::api::call::arg_data_raw_size()) {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+#[ic_cdk::query]
+fn check_status() -> String {
+    String::from("We are live")
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+
+async fn initiate_agreement(
+    terms: Vec<String>,
+    with_user: String,
+    options: Option<InitiateOptions>,
+) -> Result<Agreement, Error> {
+    _initiate(terms, with_user, None, options.unwrap_or_default()).await
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+
+fn signup_user() -> String {
+    let id = USER_ID_COUNTER.with(|counter| {
+        let counter_value = *counter.borrow().get();
+        let _ = counter.borrow_mut().set(counter_value + 1);
+        counter_value
+    });
+    let user = User {
+        identity: ic_cdk::caller().to_string(),
+    };
+
+    match USERS.with(|db| db.borrow_mut().insert(id, user.clone())) {
+        Some(_) => format!("User {} created", user.identity),
+        None => format!("User {} created", user.identity),
+    }
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+
+async fn agree_to(agreement_id: u64, delegation_id: Option<u64>) -> Result<Agreement, Error> {
+    //We are supposed to sign and store the update in stable storage
+
+    let agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+        Some(agreement) => agreement,
+        //say that the agreement was not found
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("That agreement was not found"),
+            })
+        }
+    };
+    _ensure_open(&agreement)?;
+    let caller = ic_cdk::caller().to_string();
+    let delegation = match delegation_id {
+        Some(delegation_id) => {
+            let delegation = match DELEGATIONS.with(|storage| storage.borrow().get(&delegation_id))
+            {
+                Some(delegation) => delegation,
+                None => {
+                    return Err(Error::NotFound {
+                        msg: format!("Delegation with ID {} wasn't found.", delegation_id),
+                    })
+                }
+            };
+            delegation.authorize(&caller, &agreement, time())?;
+            Some(delegation)
+        }
+        None => None,
+    };
+
+    let signer = delegation.as_ref().map_or(caller.clone(), |delegation| {
+        delegation.grantor.identity.clone()
+    });
+    agreement.ensure_can_sign(&signer)?;
+
+    // A payer's signature only counts once their deposit is in escrow. The
+    // agreement is read again afterwards since other calls ran in the meantime.
+    let agreement = if agreement
+        .payment
+        .as_ref()
+        .map_or(false, |payment| payment.needs_deposit_from(&signer))
+    {
+        _deposit(agreement_id).await?;
+        match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+            Some(agreement) => {
+                _ensure_open(&agreement)?;
+                agreement.ensure_can_sign(&signer)?;
+                agreement
+            }
+            None => {
+                return Err(Error::NotFound {
+                    msg: format!("That agreement was not found"),
+                })
+            }
+        }
+    } else {
+        agreement
+    };
+
+    let signed_agreement = _sign_and_store(caller, delegation.as_ref(), agreement);
+    _set_certified_data();
+    _maybe_execute(&signed_agreement);
+    Ok(signed_agreement)
+}
+/// The synchronous part of `agree_to`: signs, mints, verifies and stores.
+fn _sign_and_store(
+    caller: String,
+    delegation: Option<&Delegation>,
+    agreement: Agreement,
+) -> Agreement {
+    let party = delegation.map_or(caller.clone(), |delegation| {
+        delegation.grantor.identity.clone()
+    });
+    let mut signed_agreement = match delegation {
+        Some(delegation) => _agree_on_behalf(delegation, agreement),
+        None => _agree_to_agreement(caller.clone(), agreement),
+    };
+    _stamp_signature(&mut signed_agreement, &party, &caller);
+    _mint_tokens(&mut signed_agreement);
+    _refresh_verification(&mut signed_agreement);
+    _store_agreement(&signed_agreement);
+    signed_agreement
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn propose_changes(
+    agreement_id: u64,
+    new_terms: Vec<String>,
+    note: String,
+) -> Result<Agreement, Error> {
+    let agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+        Some(agreement) => agreement,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("That agreement was not found"),
+            })
+        }
+    };
+    let caller = ic_cdk::caller().to_string();
+    if !agreement.is_party(&caller) {
+        return Err(Error::Unauthorized {
+            msg: format!("Only the parties to an agreement can propose changes"),
+        });
+    }
+    if agreement.is_fully_signed() {
+        return Err(Error::InvalidInput {
+            msg: format!("The agreement has been signed by both parties and can no longer change"),
+        });
+    }
+    _ensure_open(&agreement)?;
+    _ensure_no_deposit_in_progress(agreement_id)?;
+    if agreement.commitment.is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "The terms of a committed agreement are off-chain and cannot be countered here"
+            ),
+        });
+    }
+    if new_terms.is_empty() || new_terms == agreement.terms {
+        return Err(Error::InvalidInput {
+            msg: format!("A counter-offer must change the terms"),
+        });
+    }
+    limits::validate_terms(&new_terms, &_config().limits)?;
+    limits::validate_note(&note)?;
+
+    // Signatures only ever cover the version that is finally accepted, so the
+    // counter-offer starts over with just the proposer's signature
+    let mut counter_offer = Agreement {
+        terms: new_terms.clone(),
+        ..agreement.unsigned()
+    };
+    counter_offer.record(AgreementEvent::new(
+        time(),
+        Principal::principal_to_user(caller.clone()),
+        EventKind::CounterOffer {
+            previous_terms: agreement.terms,
+            terms: new_terms,
+            note,
+        },
+    ));
+    obligation::ensure_terms_exist(&counter_offer)?;
+    limits::ensure_fits(&counter_offer)?;
+    _record_offer(&caller)?;
+    let mut counter_offer = _agree_to_agreement(caller.clone(), counter_offer);
+    _stamp_signature(&mut counter_offer, &caller, &caller);
+
+    _save_agreement(&counter_offer);
+    Ok(counter_offer)
+}
+
+#[ic_cdk::query]
+fn verify_signatures(agreement_id: u64) -> Result<VerificationReport, Error> {
+    let agreement = _readable_agreement(agreement_id, &ic_cdk::caller().to_string())?;
+    cached_or_verify(&agreement)
+}
+
+#[ic_cdk::query]
+fn verify_batch(agreement_ids: Vec<u64>) -> Result<Vec<Result<VerificationReport, Error>>, Error> {
+    if agreement_ids.len() > verification::MAX_VERIFY_BATCH {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "At most {} agreements can be verified in one batch",
+                verification::MAX_VERIFY_BATCH
+            ),
+        });
+    }
+    let caller = ic_cdk::caller().to_string();
+    Ok(agreement_ids
+        .into_iter()
+        .map(|agreement_id| {
+            _readable_agreement(agreement_id, &caller)
+                .and_then(|agreement| cached_or_verify(&agreement))
+        })
+        .collect())
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn attest(agreement_id: u64) -> Result<Agreement, Error> {
+    let mut agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+        Some(agreement) => agreement,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("That agreement was not found"),
+            })
+        }
+    };
+    let caller = ic_cdk::caller().to_string();
+    let attestation = {
+        let witness = agreement
+            .witnesses
+            .iter()
+            .flatten()
+            .find(|witness| witness.user.identity == caller);
+        match witness {
+            Some(witness) if witness.attestation.is_some() => {
+                return Err(Error::InvalidInput {
+                    msg: format!("You have already attested this agreement"),
+                })
+            }
+            Some(witness) => witness::attest(&witness.user, &agreement),
+            None => {
+                return Err(Error::Unauthorized {
+                    msg: format!("You are not a witness to this agreement"),
+                })
+            }
+        }
+    };
+    if attestation.is_none() {
+        return Err(Error::InvalidInput {
+            msg: format!("Both parties must sign before the agreement can be attested"),
+        });
+    }
+    let attestation = attestation.map(|mut attestation| {
+        attestation.attested_at = Some(Timestamp::new(time()));
+        attestation
+    });
+    for witness in agreement.witnesses.iter_mut().flatten() {
+        if witness.user.identity == caller {
+            witness.attestation = attestation.clone();
+        }
+    }
+    _refresh_verification(&mut agreement);
+
+    _save_agreement(&agreement);
+    _maybe_execute(&agreement);
+    Ok(agreement)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn retry_execution(agreement_id: u64) -> Result<Agreement, Error> {
+    let agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+        Some(agreement) => agreement,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("That agreement was not found"),
+            })
+        }
+    };
+    if !agreement.is_party(&ic_cdk::caller().to_string()) {
+        return Err(Error::Unauthorized {
+            msg: format!("Only the parties to an agreement can retry its execution"),
+        });
+    }
+    let can_attempt = agreement.execution.as_ref().map_or(false, |hook| {
+        hook.can_attempt() && !hook.attempts.is_empty()
+    });
+    if !can_attempt {
+        return Err(Error::InvalidInput {
+            msg: format!("That agreement has no failed execution left to retry"),
+        });
+    }
+    let pending = agreement
+        .execution
+        .as_ref()
+        .map_or(false, |hook| hook.is_retry_pending(time()));
+    if pending || EXECUTING.with(|executing| executing.borrow().contains(&agreement_id)) {
+        return Err(Error::InvalidInput {
+            msg: format!("A retry of that execution is already scheduled"),
+        });
+    }
+    _schedule_execution(agreement_id, Duration::ZERO);
+    Ok(agreement)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn reveal(agreement_id: u64, terms: Vec<String>, salt: String) -> Result<Agreement, Error> {
+    let mut agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+        Some(agreement) => agreement,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("That agreement was not found"),
+            })
+        }
+    };
+    let caller = ic_cdk::caller().to_string();
+    if !agreement.is_party(&caller) {
+        return Err(Error::Unauthorized {
+            msg: format!("Only the parties to an agreement can reveal its terms"),
+        });
+    }
+    let commitment = match agreement.commitment.as_mut() {
+        Some(commitment) => commitment,
+        None => {
+            return Err(Error::InvalidInput {
+                msg: format!("That agreement has no committed terms to reveal"),
+            })
+        }
+    };
+    if commitment.reveal.is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("The terms of that agreement have already been revealed"),
+        });
+    }
+    limits::validate_terms(&terms, &_config().limits)?;
+    limits::validate_salt(&salt)?;
+    if !commitment.matches(&terms, &salt) {
+        return Err(Error::InvalidInput {
+            msg: format!("The terms and salt do not match the commitment"),
+        });
+    }
+    commitment.reveal = Some(Reveal {
+        terms,
+        salt,
+        by: Principal::principal_to_user(caller),
+        at: time(),
+    });
+    limits::ensure_fits(&agreement)?;
+    _refresh_verification(&mut agreement);
+
+    _save_agreement(&agreement);
+    Ok(agreement)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn terminate_agreement(agreement_id: u64) -> Result<Agreement, Error> {
+    let mut agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)) {
+        Some(agreement) => agreement,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("That agreement was not found"),
+            })
+        }
+    };
+    let caller = ic_cdk::caller().to_string();
+    if !agreement.is_party(&caller) {
+        return Err(Error::Unauthorized {
+            msg: format!("Only the parties to an agreement can terminate it"),
+        });
+    }
+    if agreement.is_complete() {
+        return Err(Error::InvalidInput {
+            msg: format!("A complete agreement can no longer be terminated"),
+        });
+    }
+    _ensure_open(&agreement)?;
+    _ensure_no_deposit_in_progress(agreement_id)?;
+
+    agreement.record(AgreementEvent::new(
+        time(),
+        Principal::principal_to_user(caller),
+        EventKind::Terminated,
+    ));
+    _save_agreement(&agreement);
+    _maybe_settle(&agreement);
+    Ok(agreement)
+}
+
+/// A dispute statement by the caller, with the attachments they uploaded as evidence.
+fn _statement(caller: &str, text: String, evidence: Vec<u64>) -> Result<Statement, Error> {
+    limits::validate_statement(&text, &evidence)?;
+    Ok(Statement {
+        by: Principal::principal_to_user(caller.to_string()),
+        at: Timestamp::new(time()),
+        text,
+        evidence: _resolve_attachments(&evidence, caller)?.unwrap_or_default(),
+    })
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn open_dispute(
+    agreement_id: u64,
+    statement: String,
+    evidence: Vec<u64>,
+) -> Result<Agreement, Error> {
+    let caller = ic_cdk::caller().to_string();
+    let mut agreement = _readable_agreement(agreement_id, &caller)?;
+    _ensure_open(&agreement)?;
+    dispute::open(&mut agreement, _statement(&caller, statement, evidence)?)?;
+    _index_attachment_uses(&agreement);
+    _save_agreement(&agreement);
+    Ok(agreement)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn respond_to_dispute(
+    agreement_id: u64,
+    statement: String,
+    evidence: Vec<u64>,
+) -> Result<Agreement, Error> {
+    let caller = ic_cdk::caller().to_string();
+    let mut agreement = _readable_agreement(agreement_id, &caller)?;
+    dispute::respond(&mut agreement, _statement(&caller, statement, evidence)?)?;
+    _index_attachment_uses(&agreement);
+    _save_agreement(&agreement);
+    Ok(agreement)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn issue_ruling(agreement_id: u64, decision: String) -> Result<Agreement, Error> {
+    let caller = ic_cdk::caller().to_string();
+    let mut agreement = _readable_agreement(agreement_id, &caller)?;
+    limits::validate_statement(&decision, &[])?;
+    dispute::rule(&mut agreement, &caller, decision, Timestamp::new(time()))?;
+    _refresh_verification(&mut agreement);
+    _save_agreement(&agreement);
+    Ok(agreement)
+}
+
+/// An agreement in force that the caller is a party to.
+fn _obligations_of(agreement_id: u64, caller: &str) -> Result<Agreement, Error> {
+    let agreement = _readable_agreement(agreement_id, caller)?;
+    if !agreement.is_party(caller) {
+        return Err(Error::Unauthorized {
+            msg: format!("Only the parties can update obligations"),
+        });
+    }
+    _ensure_open(&agreement)?;
+    Ok(agreement)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn claim_obligation(
+    agreement_id: u64,
+    obligation_id: u32,
+    note: String,
+) -> Result<Agreement, Error> {
+    let caller = ic_cdk::caller().to_string();
+    limits::validate_note(&note)?;
+    let mut agreement = _obligations_of(agreement_id, &caller)?;
+    obligation::claim(&mut agreement, obligation_id, &caller, note, time())?;
+    _save_agreement(&agreement);
+    Ok(agreement)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn confirm_obligation(agreement_id: u64, obligation_id: u32) -> Result<Agreement, Error> {
+    let caller = ic_cdk::caller().to_string();
+    let mut agreement = _obligations_of(agreement_id, &caller)?;
+    obligation::confirm(&mut agreement, obligation_id, &caller, time())?;
+    _save_agreement(&agreement);
+    Ok(agreement)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn contest_obligation(
+    agreement_id: u64,
+    obligation_id: u32,
+    reason: String,
+) -> Result<Agreement, Error> {
+    let caller = ic_cdk::caller().to_string();
+    limits::validate_note(&reason)?;
+    let mut agreement = _obligations_of(agreement_id, &caller)?;
+    obligation::contest(&mut agreement, obligation_id, &caller, reason, time())?;
+    _save_agreement(&agreement);
+    Ok(agreement)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn grant_viewer(agreement_id: u64, viewer: String) -> Result<Agreement, Error> {
+    let mut agreement = _viewers_editable_by_caller(agreement_id)?;
+    let mut viewers: Vec<String> = agreement
+        .viewers
+        .iter()
+        .flatten()
+        .map(|viewer| viewer.identity.clone())
+        .collect();
+    viewers.push(viewer);
+    agreement.viewers =
+        visibility::normalize_viewers(viewers, [&agreement.by_user, &agreement.with_user])?;
+    limits::ensure_fits(&agreement)?;
+
+    _save_agreement(&agreement);
+    Ok(agreement)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn revoke_viewer(agreement_id: u64, viewer: String) -> Result<Agreement, Error> {
+    let mut agreement = _viewers_editable_by_caller(agreement_id)?;
+    let viewers: Vec<User> = agreement
+        .viewers
+        .take()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|user| user.identity.trim() != viewer.trim())
+        .collect();
+    agreement.viewers = if viewers.is_empty() {
+        None
+    } else {
+        Some(viewers)
+    };
+
+    _save_agreement(&agreement);
+    Ok(agreement)
+}
+
+fn _viewers_editable_by_caller(agreement_id: u64) -> Result<Agreement, Error> {
+    let caller = ic_cdk::caller().to_string();
+    let agreement = _readable_agreement(agreement_id, &caller)?;
+    if !agreement.is_party(&caller) {
+        return Err(Error::Unauthorized {
+            msg: format!("Only the parties to an agreement can manage its viewers"),
+        });
+    }
+    if agreement.visibility() != Visibility::PartiesAndViewers {
+        return Err(Error::InvalidInput {
+            msg: format!("That agreement is not visible to viewers"),
+        });
+    }
+    Ok(agreement)
+}
+
+#[ic_cdk::query]
+fn get_my_agreements(user_id: u64, filter: Option<TimeFilter>) -> Result<Vec<Agreement>, Error> {
+    let caller = ic_cdk::caller().to_string();
+    let filter = filter.unwrap_or_default();
+    filter.validate()?;
+    let mut my_agreements: Vec<Agreement> = vec![];
+    // Borrow the USERS storage and get the user by ID
+    match USERS.with(|storage| storage.borrow().get(&user_id)) {
+        Some(user) => {
+            // Borrow the AGREEMENTS storage and collect all agreements
+            let all_agreements: Vec<(u64, Agreement)> =
+                AGREEMENTS.with(|storage| storage.borrow().iter().collect());
+
+            // Iterate through each agreement
+            for (id, agreement) in all_agreements.clone() {
+                // Other users' agreements are only listed if the caller may read them
+                if !agreement.can_read(&caller) || !filter.matches(&agreement) {
+                    continue;
+                }
+
+                if agreement.with_user.identity.trim() == user.identity.trim() {
+                    my_agreements.push(agreement.clone())
+                }
+                if agreement.by_user.identity.trim() == user.identity.trim() {
+                    my_agreements.push(agreement)
+                }
+            }
+
+            Ok(my_agreements)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("User with ID {} wasn't found.", user_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_single_agreement(agreement_id: u64) -> Result<Agreement, Error> {
+    _readable_agreement(agreement_id, &ic_cdk::caller().to_string())
+}
+#[ic_cdk::update(guard = "_not_paused")]
+fn create_template(
+    name: String,
+    terms: Vec<String>,
+    placeholders: Vec<Placeholder>,
+) -> Result<Template, Error> {
+    let id = TEMPLATE_ID_COUNTER.with(|counter| {
+        let counter_value = *counter.borrow().get();
+        let _ = counter.borrow_mut().set(counter_value + 1);
+        counter_value
+    });
+    let template = Template {
+        id,
+        version: 1,
+        name,
+        terms,
+        placeholders,
+        created_by: Principal::principal_to_user(ic_cdk::caller().to_string()),
+    };
+    template.validate(&_config().limits)?;
+
+    TEMPLATES.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(template.key(), template.clone())
+    });
+    Ok(template)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn update_template(
+    template_id: u64,
+    terms: Vec<String>,
+    placeholders: Vec<Placeholder>,
+) -> Result<Template, Error> {
+    match _latest_template(template_id) {
+        Some(latest) => {
+            if latest.created_by.identity != ic_cdk::caller().to_string() {
+                return Err(Error::Unauthorized {
+                    msg: format!("Only the creator of a template can publish a new version"),
+                });
+            }
+            let template = Template {
+                version: latest.version + 1,
+                terms,
+                placeholders,
+                ..latest
+            };
+            template.validate(&_config().limits)?;
+
+            TEMPLATES.with(|storage| {
+                storage
+                    .borrow_mut()
+                    .insert(template.key(), template.clone())
+            });
+            Ok(template)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Template with ID {} wasn't found.", template_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_template(template_id: u64, version: Option<u32>) -> Result<Template, Error> {
+    let template = match version {
+        Some(version) => TEMPLATES.with(|storage| {
+            storage.borrow().get(&TemplateKey {
+                id: template_id,
+                version,
+            })
+        }),
+        None => _latest_template(template_id),
+    };
+    match template {
+        Some(template) => Ok(template),
+        None => Err(Error::NotFound {
+            msg: format!("Template with ID {} wasn't found.", template_id),
+        }),
+    }
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+async fn initiate_from_template(
+    template_id: u64,
+    values: Vec<(String, String)>,
+    with_user: String,
+    options: Option<InitiateOptions>,
+) -> Result<Agreement, Error> {
+    let template = match _latest_template(template_id) {
+        Some(template) => template,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("Template with ID {} wasn't found.", template_id),
+            })
+        }
+    };
+    let terms = template.render(&values)?;
+
+    _initiate(
+        terms,
+        with_user,
+        Some(template.reference()),
+        options.unwrap_or_default(),
+    )
+    .await
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn create_attachment(name: String, mime_type: String, size: u64) -> Result<Attachment, Error> {
+    let id = ATTACHMENT_ID_COUNTER.with(|counter| {
+        let counter_value = *counter.borrow().get();
+        let _ = counter.borrow_mut().set(counter_value + 1);
+        counter_value
+    });
+    let uploaded_by = Principal::principal_to_user(ic_cdk::caller().to_string());
+    let attachment = Attachment::new(id, name, mime_type, size, uploaded_by)?;
+
+    ATTACHMENTS.with(|storage| storage.borrow_mut().insert(id, attachment.clone()));
+    Ok(attachment)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn upload_attachment_chunk(attachment_id: u64, index: u32, bytes: Vec<u8>) -> Result<(), Error> {
+    let attachment = match ATTACHMENTS.with(|storage| storage.borrow().get(&attachment_id)) {
+        Some(attachment) if attachment.uploaded_by.identity == ic_cdk::caller().to_string() => {
+            attachment
+        }
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!("Attachment with ID {} wasn't found.", attachment_id),
+            })
+        }
+    };
+    if attachment.is_finalized() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Attachment {} is finalized and can no longer change",
+                attachment_id
+            ),
+        });
+    }
+    match attachment.expected_chunk_size(index) {
+        Some(size) if size == bytes.len() => {
+            ATTACHMENT_CHUNKS.with(|storage| {
+                storage.borrow_mut().insert(
+                    ChunkKey {
+                        attachment_id,
+                        index,
+                    },
+                    Chunk(bytes),
+                )
+            });
+            Ok(())
+        }
+        Some(size) => Err(Error::InvalidInput {
+            msg: format!("Chunk {} must be exactly {} bytes", index, size),
+        }),
+        None => Err(Error::InvalidInput {
+            msg: format!(
+                "Attachment {} only has {} chunks",
+                attachment_id,
+                attachment.chunk_count()
+            ),
+        }),
+    }
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn finalize_attachment(attachment_id: u64) -> Result<Attachment, Error> {
+    let attachment = match ATTACHMENTS.with(|storage| storage.borrow().get(&attachment_id)) {
+        Some(attachment) if attachment.uploaded_by.identity == ic_cdk::caller().to_string() => {
+            attachment
+        }
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!("Attachment with ID {} wasn't found.", attachment_id),
+            })
+        }
+    };
+    if attachment.is_finalized() {
+        return Ok(attachment);
+    }
+    let sha256 = ATTACHMENT_CHUNKS.with(|storage| {
+        let storage = storage.borrow();
+        attachment::hash_chunks(&attachment, |index| {
+            storage.get(&ChunkKey {
+                attachment_id,
+                index,
+            })
+        })
+    });
+    match sha256 {
+        Some(sha256) => {
+            let attachment = Attachment {
+                sha256: Some(sha256),
+                ..attachment
+            };
+            ATTACHMENTS.with(|storage| {
+                storage
+                    .borrow_mut()
+                    .insert(attachment_id, attachment.clone())
+            });
+            Ok(attachment)
+        }
+        None => Err(Error::InvalidInput {
+            msg: format!("Attachment {} is missing chunks", attachment_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_attachment(attachment_id: u64) -> Result<Attachment, Error> {
+    let caller = ic_cdk::caller().to_string();
+    match ATTACHMENTS.with(|storage| storage.borrow().get(&attachment_id)) {
+        Some(attachment) if _can_read_attachment(&attachment, &caller) => Ok(attachment),
+        _ => Err(Error::NotFound {
+            msg: format!("Attachment with ID {} wasn't found.", attachment_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_attachment_chunk(attachment_id: u64, index: u32) -> Result<Vec<u8>, Error> {
+    let caller = ic_cdk::caller().to_string();
+    match ATTACHMENTS.with(|storage| storage.borrow().get(&attachment_id)) {
+        Some(attachment)
+            if attachment.is_finalized() && _can_read_attachment(&attachment, &caller) =>
+        {
+            let chunk = ATTACHMENT_CHUNKS.with(|storage| {
+                storage.borrow().get(&ChunkKey {
+                    attachment_id,
+                    index,
+                })
+            });
+            match chunk {
+                Some(chunk) => Ok(chunk.0),
+                None => Err(Error::NotFound {
+                    msg: format!("Attachment {} has no chunk {}", attachment_id, index),
+                }),
+            }
+        }
+        _ => Err(Error::NotFound {
+            msg: format!("Attachment with ID {} wasn't found.", attachment_id),
+        }),
+    }
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn grant_delegation(
+    delegate: String,
+    scope: DelegationScope,
+    expires_at: u64,
+) -> Result<Delegation, Error> {
+    let id = DELEGATION_ID_COUNTER.with(|counter| {
+        let counter_value = *counter.borrow().get();
+        let _ = counter.borrow_mut().set(counter_value + 1);
+        counter_value
+    });
+    let delegation = Delegation::grant(
+        id,
+        Principal::principal_to_user(ic_cdk::caller().to_string()),
+        Principal::principal_to_user(delegate.trim().to_string()),
+        scope,
+        time(),
+        expires_at,
+    )?;
+
+    DELEGATIONS.with(|storage| storage.borrow_mut().insert(id, delegation.clone()));
+    Ok(delegation)
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn revoke_delegation(delegation_id: u64) -> Result<Delegation, Error> {
+    match DELEGATIONS.with(|storage| storage.borrow().get(&delegation_id)) {
+        Some(delegation) if delegation.grantor.identity == ic_cdk::caller().to_string() => {
+            if delegation.revoked_at.is_some() {
+                return Ok(delegation);
+            }
+            let delegation = Delegation {
+                revoked_at: Some(time()),
+                ..delegation
+            };
+            DELEGATIONS.with(|storage| {
+                storage
+                    .borrow_mut()
+                    .insert(delegation_id, delegation.clone())
+            });
+            Ok(delegation)
+        }
+        _ => Err(Error::NotFound {
+            msg: format!("Delegation with ID {} wasn't found.", delegation_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_my_delegations() -> Vec<Delegation> {
+    let caller = ic_cdk::caller().to_string();
+    DELEGATIONS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, delegation)| delegation)
+            .filter(|delegation| {
+                delegation.grantor.identity == caller || delegation.delegate.identity == caller
+            })
+            .collect()
+    })
+}
+
+/// The caller's inbox items that `keep` accepts, whose agreement matches `filter`.
+fn _inbox_items(
+    caller: &str,
+    filter: &TimeFilter,
+    keep: impl Fn(&InboxItem) -> bool,
+) -> Vec<InboxItem> {
+    let items: Vec<InboxItem> = INBOX.with(|inbox| {
+        inbox
+            .borrow()
+            .range(InboxKey::range_of(caller))
+            .map(|(_, item)| item)
+            .filter(|item| keep(item))
+            .collect()
+    });
+    if filter.is_empty() {
+        return items;
+    }
+    items
+        .into_iter()
+        .filter(|item| {
+            AGREEMENTS
+                .with(|storage| storage.borrow().get(&item.agreement_id))
+                .is_some_and(|agreement| filter.matches(&agreement))
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn get_inbox(
+    category: Option<InboxCategory>,
+    filter: Option<TimeFilter>,
+) -> Result<Vec<InboxItem>, Error> {
+    let caller = ic_cdk::caller().to_string();
+    let filter = filter.unwrap_or_default();
+    filter.validate()?;
+    Ok(_inbox_items(&caller, &filter, |item| {
+        category.as_ref().is_none_or(|c| &item.category == c)
+    }))
+}
+
+#[ic_cdk::query]
+fn get_pending_items(filter: Option<TimeFilter>) -> Result<Vec<InboxItem>, Error> {
+    let caller = ic_cdk::caller().to_string();
+    let filter = filter.unwrap_or_default();
+    filter.validate()?;
+    Ok(_inbox_items(&caller, &filter, |item| {
+        item.category.needs_action()
+    }))
+}
+
+/// Obligations past their deadline on the agreements the caller is a party to.
+#[ic_cdk::query]
+fn get_overdue_obligations(filter: Option<TimeFilter>) -> Result<Vec<OverdueObligation>, Error> {
+    let caller = ic_cdk::caller().to_string();
+    let filter = filter.unwrap_or_default();
+    filter.validate()?;
+    let now = time();
+    let agreement_ids: Vec<u64> = INBOX.with(|inbox| {
+        inbox
+            .borrow()
+            .range(InboxKey::range_of(&caller))
+            .map(|(key, _)| key.agreement_id)
+            .collect()
+    });
+    Ok(agreement_ids
+        .into_iter()
+        .filter_map(|agreement_id| AGREEMENTS.with(|storage| storage.borrow().get(&agreement_id)))
+        .filter(|agreement| agreement.is_party(&caller) && filter.matches(agreement))
+        .flat_map(|agreement| obligation::overdue(&agreement, now))
+        .collect())
+}
+
+#[ic_cdk::query]
+fn get_unread_counts() -> Vec<(InboxCategory, u64)> {
+    let caller = ic_cdk::caller().to_string();
+    let mut counts: Vec<(InboxCategory, u64)> = InboxCategory::ALL
+        .iter()
+        .map(|category| (category.clone(), 0))
+        .collect();
+    INBOX.with(|inbox| {
+        for (_, item) in inbox.borrow().range(InboxKey::range_of(&caller)) {
+            if item.read {
+                continue;
+            }
+            if let Some((_, count)) = counts.iter_mut().find(|(c, _)| c == &item.category) {
+                *count += 1;
+            }
+        }
+    });
+    counts
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn mark_as_read(agreement_ids: Vec<u64>) -> u64 {
+    let identity = ic_cdk::caller().to_string();
+    let mut marked = 0;
+    INBOX.with(|inbox| {
+        let mut inbox = inbox.borrow_mut();
+        for agreement_id in agreement_ids.into_iter() {
+            let key = InboxKey {
+                identity: identity.clone(),
+                agreement_id,
+            };
+            if let Some(item) = inbox.get(&key).filter(|item| !item.read) {
+                inbox.insert(key, InboxItem { read: true, ..item });
+                marked += 1;
+            }
+        }
+    });
+    marked
+}
+
+#[ic_cdk::update]
+fn configure_collection(config: CollectionConfig) -> Result<CollectionConfig, Error> {
+    let admin = _ensure_admin()?;
+    NFT_CONFIG
+        .with(|cell| cell.borrow_mut().set(config.clone()))
+        .expect("Cannot store the collection config");
+    _log_admin_action(
+        admin,
+        AdminActionKind::CollectionConfigured {
+            enabled: config.enabled,
+        },
+    );
+    Ok(config)
+}
+
+#[ic_cdk::update]
+fn pause() -> Result<Config, Error> {
+    let admin = _ensure_admin()?;
+    let config = Config {
+        paused: true,
+        .._config()
+    };
+    _set_config(config.clone());
+    _log_admin_action(admin, AdminActionKind::Paused);
+    Ok(config)
+}
+
+#[ic_cdk::update]
+fn resume() -> Result<Config, Error> {
+    let admin = _ensure_admin()?;
+    if RESTORE.with(|pending| pending.borrow().as_ref().is_some_and(Restore::is_applying)) {
+        return Err(Error::InvalidInput {
+            msg: "Finish restoring the snapshot first".to_string(),
+        });
+    }
+    // A snapshot still being taken would no longer be consistent
+    BACKUP.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        if stored
+            .as_ref()
+            .is_some_and(|backup| backup.manifest().is_none())
+        {
+            *stored = None;
+        }
+    });
+    let config = Config {
+        paused: false,
+        .._config()
+    };
+    _set_config(config.clone());
+    _log_admin_action(admin, AdminActionKind::Resumed);
+    Ok(config)
+}
+
+#[ic_cdk::update]
+fn set_limits(limits: Limits) -> Result<Config, Error> {
+    let admin = _ensure_admin()?;
+    limits.validate()?;
+    let config = Config {
+        limits: limits.clone(),
+        .._config()
+    };
+    _set_config(config.clone());
+    _log_admin_action(admin, AdminActionKind::LimitsChanged { limits });
+    Ok(config)
+}
+
+#[ic_cdk::update]
+fn create_backup() -> Result<BackupProgress, Error> {
+    let admin = _ensure_admin()?;
+    _ensure_paused()?;
+    if RESTORE.with(|pending| pending.borrow().is_some()) {
+        return Err(Error::InvalidInput {
+            msg: "A snapshot is being restored".to_string(),
+        });
+    }
+    let mut backup = BACKUP
+        .with(|stored| stored.borrow_mut().take())
+        .filter(|backup| backup.manifest().is_none())
+        .unwrap_or_else(|| Backup::new(time()));
+    _write_backup_batch(&mut backup, BACKUP_BATCH_SIZE);
+    let progress = backup.progress();
+    BACKUP.with(|stored| *stored.borrow_mut() = Some(backup));
+    if let BackupProgress::Ready(manifest) = &progress {
+        _log_admin_action(
+            admin,
+            AdminActionKind::BackupCreated {
+                sha256: manifest.sha256.clone(),
+            },
+        );
+    }
+    Ok(progress)
+}
+
+#[ic_cdk::query]
+fn get_backup_chunk(index: u32) -> Result<Vec<u8>, Error> {
+    _ensure_admin()?;
+    BACKUP.with(|stored| match stored.borrow().as_ref() {
+        Some(backup) if backup.manifest().is_none() => Err(Error::InvalidInput {
+            msg: "The snapshot is still being taken".to_string(),
+        }),
+        Some(backup) => BACKUP_MEMORY
+            .with(|memory| backup.chunk(&*memory.borrow(), index))
+            .ok_or(Error::NotFound {
+                msg: format!("The snapshot has no chunk {}", index),
+            }),
+        None => Err(Error::NotFound {
+            msg: "No snapshot has been taken since the last upgrade".to_string(),
+        }),
+    })
+}
+
+#[ic_cdk::update]
+fn start_restore(manifest: BackupManifest) -> Result<(), Error> {
+    _ensure_admin()?;
+    _ensure_paused()?;
+    _ensure_fresh()?;
+    let restore = Restore::new(manifest)?;
+    // The upload takes the place of any snapshot taken here
+    BACKUP.with(|stored| *stored.borrow_mut() = None);
+    RESTORE.with(|pending| *pending.borrow_mut() = Some(restore));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn upload_restore_chunk(index: u32, bytes: Vec<u8>) -> Result<(), Error> {
+    _ensure_admin()?;
+    RESTORE.with(|pending| match pending.borrow_mut().as_mut() {
+        Some(restore) => {
+            BACKUP_MEMORY.with(|memory| restore.add_chunk(&mut *memory.borrow_mut(), index, &bytes))
+        }
+        None => Err(Error::NotFound {
+            msg: "No restore has been started".to_string(),
+        }),
+    })
+}
+
+#[ic_cdk::update]
+fn finish_restore() -> Result<RestoreProgress, Error> {
+    let admin = _ensure_admin()?;
+    _ensure_paused()?;
+    let progress = RESTORE.with(|pending| match pending.borrow_mut().as_mut() {
+        Some(restore) => {
+            _apply_restore_batch(restore, BACKUP_BATCH_SIZE)?;
+            Ok(restore.progress())
+        }
+        None => Err(Error::NotFound {
+            msg: "No restore has been started".to_string(),
+        }),
+    })?;
+    if let RestoreProgress::Done(manifest) = &progress {
+        RESTORE.with(|pending| *pending.borrow_mut() = None);
+        _start_reindex(Reindex {
+            inbox: false,
+            attachment_uses: false,
+            verification: true,
+        });
+        _log_admin_action(
+            admin,
+            AdminActionKind::Restored {
+                sha256: manifest.sha256.clone(),
+            },
+        );
+    }
+    Ok(progress)
+}
+
+#[ic_cdk::query]
+fn get_config() -> Config {
+    _config()
+}
+
+#[ic_cdk::query]
+fn get_admin_log(prev: Option<u64>, take: Option<u64>) -> Result<Vec<AdminAction>, Error> {
+    _ensure_admin()?;
+    let start = prev.map_or(0, |prev| prev.saturating_add(1));
+    Ok(ADMIN_LOG.with(|log| {
+        log.borrow()
+            .range(start..)
+            .take(take.unwrap_or(100).min(1000) as usize)
+            .map(|(_, action)| action)
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    let total_supply = TOKENS.with(|storage| storage.borrow().len());
+    nft::collection_metadata(&_collection_config(), total_supply)
+}
+
+#[ic_cdk::query]
+fn icrc7_symbol() -> String {
+    _collection_config().symbol
+}
+
+#[ic_cdk::query]
+fn icrc7_name() -> String {
+    _collection_config().name
+}
+
+#[ic_cdk::query]
+fn icrc7_description() -> Option<String> {
+    _collection_config().description
+}
+
+#[ic_cdk::query]
+fn icrc7_logo() -> Option<String> {
+    _collection_config().logo
+}
+
+#[ic_cdk::query]
+fn icrc7_total_supply() -> Nat {
+    Nat::from(TOKENS.with(|storage| storage.borrow().len()))
+}
+
+#[ic_cdk::query]
+fn icrc7_supply_cap() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_max_query_batch_size() -> Option<Nat> {
+    Some(Nat::from(nft::MAX_QUERY_BATCH_SIZE as u64))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_update_batch_size() -> Option<Nat> {
+    Some(Nat::from(nft::MAX_UPDATE_BATCH_SIZE as u64))
+}
+
+#[ic_cdk::query]
+fn icrc7_default_take_value() -> Option<Nat> {
+    Some(Nat::from(nft::DEFAULT_TAKE_VALUE as u64))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_take_value() -> Option<Nat> {
+    Some(Nat::from(nft::MAX_TAKE_VALUE as u64))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_memo_size() -> Option<Nat> {
+    Some(Nat::from(nft::MAX_MEMO_SIZE as u64))
+}
+
+#[ic_cdk::query]
+fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    Some(false)
+}
+
+#[ic_cdk::query]
+fn icrc7_tx_window() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_permitted_drift() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    if token_ids.len() > nft::MAX_QUERY_BATCH_SIZE {
+        ic_cdk::trap("Too many token ids in one query");
+    }
+    TOKENS.with(|storage| {
+        let storage = storage.borrow();
+        token_ids
+            .iter()
+            .map(|token_id| {
+                nft::token_id(token_id)
+                    .and_then(|token_id| storage.get(&token_id))
+                    .map(|token| token.metadata())
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    if token_ids.len() > nft::MAX_QUERY_BATCH_SIZE {
+        ic_cdk::trap("Too many token ids in one query");
+    }
+    TOKENS.with(|storage| {
+        let storage = storage.borrow();
+        token_ids
+            .iter()
+            .map(|token_id| {
+                nft::token_id(token_id)
+                    .and_then(|token_id| storage.get(&token_id))
+                    .map(|token| token.owner)
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    if accounts.len() > nft::MAX_QUERY_BATCH_SIZE {
+        ic_cdk::trap("Too many accounts in one query");
+    }
+    TOKEN_OWNERS.with(|index| {
+        let index = index.borrow();
+        accounts
+            .iter()
+            .map(|account| Nat::from(index.range(OwnerKey::range_of(account, None)).count() as u64))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let start = match prev {
+        Some(prev) => match nft::token_id(&prev) {
+            Some(prev) => prev.saturating_add(1),
+            None => return vec![],
+        },
+        None => 0,
+    };
+    TOKENS.with(|storage| {
+        storage
+            .borrow()
+            .range(start..)
+            .take(nft::take(take))
+            .map(|(token_id, _)| Nat::from(token_id))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let prev = match prev {
+        Some(prev) => match nft::token_id(&prev) {
+            Some(prev) => Some(prev),
+            None => return vec![],
+        },
+        None => None,
+    };
+    TOKEN_OWNERS.with(|index| {
+        index
+            .borrow()
+            .range(OwnerKey::range_of(&account, prev))
+            .take(nft::take(take))
+            .map(|(_, token_id)| Nat::from(token_id))
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "_not_paused")]
+fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<Result<Nat, TransferError>>> {
+    if args.len() > nft::MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: Nat::from(1u64),
+            message: format!(
+                "A batch can hold at most {} transfers",
+                nft::MAX_UPDATE_BATCH_SIZE
+            ),
+        }))];
+    }
+    let caller = ic_cdk::caller();
+    args.iter()
+        .map(|arg| Some(_transfer_token(arg, caller)))
+        .collect()
+}
+
+#[ic_cdk::update]
+fn icrc21_canister_call_consent_message(
+    request: ConsentMessageRequest,
+) -> Result<ConsentInfo, ConsentError> {
+    let (action, agreement) = match request.method.as_str() {
+        "agree_to" => {
+            let (agreement_id, delegation_id) =
+                Decode!(&request.arg, u64, Option<u64>).map_err(|_| {
+                    consent::unavailable("The arguments to agree_to could not be decoded")
+                })?;
+            let agreement = AGREEMENTS
+                .with(|storage| storage.borrow().get(&agreement_id))
+                .ok_or_else(|| consent::unavailable("That agreement was not found"))?;
+            if agreement.is_expired(time()) {
+                return Err(consent::unavailable("That agreement has expired"));
+            }
+            let caller = ic_cdk::caller().to_string();
+            let action = match delegation_id {
+                Some(delegation_id) => {
+                    let delegation = DELEGATIONS
+                        .with(|storage| storage.borrow().get(&delegation_id))
+                        .filter(|delegation| delegation.delegate.identity == caller)
+                        .ok_or_else(|| consent::unavailable("That delegation was not found"))?;
+                    if !agreement.can_read(&delegation.grantor.identity) {
+                        return Err(consent::unavailable("That agreement was not found"));
+                    }
+                    consent::Action::AgreeOnBehalf {
+                        principal: delegation.grantor.identity,
+                    }
+                }
+                None if agreement.can_read(&caller) => consent::Action::Agree,
+                None => return Err(consent::unavailable("That agreement was not found")),
+            };
+            let signer = match &action {
+                consent::Action::AgreeOnBehalf { principal } => principal.as_str(),
+                _ => caller.as_str(),
+            };
+            agreement
+                .ensure_can_sign(signer)
+                .map_err(|err| consent::unavailable(err.msg()))?;
+            (action, agreement)
+        }
+        "initiate_agreement" => {
+            let (terms, with_user, options) =
+                Decode!(&request.arg, Vec<String>, String, Option<InitiateOptions>).map_err(
+                    |_| {
+                        consent::unavailable(
+                            "The arguments to initiate_agreement could not be decoded",
+                        )
+                    },
+                )?;
+            let agreement = _build_agreement(
+                terms,
+                with_user,
+                ic_cdk::caller().to_string(),
+                None,
+                options.unwrap_or_default(),
+            )
+            .map_err(|err| consent::unavailable(err.msg()))?;
+            (consent::Action::Initiate, agreement)
+        }
+        method => return Err(consent::unsupported(method)),
+    };
+    consent::consent_message(&action, &agreement, &request.user_preferences)
+}
+
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let route = match Route::parse(&request.url) {
+        Some(route) if request.method.eq_ignore_ascii_case("GET") => route,
+        _ => return http::not_found(),
+    };
+    let agreement = match AGREEMENTS.with(|storage| storage.borrow().get(&route.agreement_id())) {
+        Some(agreement) if agreement.visibility() == Visibility::Public => agreement,
+        _ => return http::not_found(),
+    };
+    let body = route.render(&agreement, &_verification_for_http(&agreement));
+    HTTP_TREE.with(|tree| {
+        http::response(
+            &route,
+            body,
+            &tree.borrow(),
+            ic_cdk::api::data_certificate(),
+        )
+    })
+}
+
+#[ic_cdk::query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    icrc::supported_standards()
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
+enum Error {
+    NotFound { msg: String },
+    InvalidInput { msg: String },
+    Unauthorized { msg: String },
+}
+
+impl Error {
+    fn msg(&self) -> &str {
+        match self {
+            Error::NotFound { msg } | Error::InvalidInput { msg } | Error::Unauthorized { msg } => {
+                msg
+            }
+        }
+    }
 }
 
 ic_cdk::export_candid!();
@@ -0,0 +1,416 @@
+use std::borrow::Cow;
+
+use crate::agreement::Agreement;
+use crate::attachment::CHUNK_SIZE;
+use crate::backup::BACKUP_CHUNK_SIZE;
+use crate::lamport::SecurityLevel;
+use crate::obligation;
+use crate::Error;
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+
+pub const MAX_NOTE_SIZE: usize = 1024;
+const MAX_STATEMENT_SIZE: usize = 4 * 1024;
+const MAX_EVIDENCE: usize = 8;
+const MAX_SALT_SIZE: usize = 256;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+const MAX_INGRESS_ARG_SIZE: usize = 48 * 1024;
+/// Chunks are uploaded whole, with room for the other arguments.
+const MAX_CHUNK_ARG_SIZE: usize = CHUNK_SIZE as usize + 1024;
+const MAX_RESTORE_ARG_SIZE: usize = BACKUP_CHUNK_SIZE + 1024;
+/// Wallets may ask for a consent message before the user has signed in.
+const ANONYMOUS_METHODS: [&str; 1] = ["icrc21_canister_call_consent_message"];
+
+/// Bytes per hash in a key or signature: a hex string of at most 64
+/// characters behind a length byte.
+const HASH_SIZE: usize = 65;
+/// Room for a dispute besides the ruling's signature: the claim and the
+/// response with their evidence, and the decision.
+const DISPUTE_SIZE: usize = 3 * MAX_STATEMENT_SIZE + 2 * MAX_EVIDENCE * 512 + 1024;
+/// Room for one history event, which names who caused it.
+const EVENT_SIZE: usize = 192;
+
+/// Operating limits, set through the init and upgrade arguments and by admins.
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub struct Limits {
+    pub max_terms: u32,
+    pub max_term_size: u32,
+    pub max_terms_size: u32,
+    /// Agreements and counter-offers one caller can send per window.
+    pub max_offers_per_window: u32,
+    pub rate_window_seconds: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_terms: 64,
+            max_term_size: 4 * 1024,
+            max_terms_size: 8 * 1024,
+            max_offers_per_window: 20,
+            rate_window_seconds: 60 * 60,
+        }
+    }
+}
+
+impl Limits {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.max_terms == 0
+            || self.max_term_size == 0
+            || self.max_offers_per_window == 0
+            || self.rate_window_seconds == 0
+        {
+            return Err(Error::InvalidInput {
+                msg: format!("Every limit must be greater than zero"),
+            });
+        }
+        if self.max_terms_size < self.max_term_size {
+            return Err(Error::InvalidInput {
+                msg: format!("The total size of the terms cannot be below the size of one term"),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, candid::CandidType, Serialize, Deserialize,
+)]
+pub struct RateKey {
+    pub identity: String,
+}
+
+/// A fixed window counting what one caller sent since `started_at`.
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub struct RateWindow {
+    pub started_at: u64,
+    pub count: u32,
+}
+
+impl Storable for RateKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RateKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for RateWindow {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RateWindow {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl RateWindow {
+    /// Counts one more offer, starting a new window once the last one is over.
+    pub fn record(
+        window: Option<RateWindow>,
+        now: u64,
+        limits: &Limits,
+    ) -> Result<RateWindow, Error> {
+        let length = limits.rate_window_seconds.saturating_mul(NANOS_PER_SECOND);
+        let window = match window {
+            Some(window) if now.saturating_sub(window.started_at) < length => window,
+            _ => RateWindow {
+                started_at: now,
+                count: 0,
+            },
+        };
+        if window.count >= limits.max_offers_per_window {
+            let seconds = (window.started_at.saturating_add(length) - now) / NANOS_PER_SECOND + 1;
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "You can send at most {} offers every {} seconds. Try again in {} seconds",
+                    limits.max_offers_per_window, limits.rate_window_seconds, seconds
+                ),
+            });
+        }
+        Ok(RateWindow {
+            count: window.count + 1,
+            ..window
+        })
+    }
+}
+
+pub fn validate_terms(terms: &[String], limits: &Limits) -> Result<(), Error> {
+    if terms.len() > limits.max_terms as usize {
+        return Err(Error::InvalidInput {
+            msg: format!("An agreement can have at most {} terms", limits.max_terms),
+        });
+    }
+    if terms
+        .iter()
+        .any(|term| term.len() > limits.max_term_size as usize)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("A term can be at most {} bytes", limits.max_term_size),
+        });
+    }
+    if terms.iter().map(|term| term.len()).sum::<usize>() > limits.max_terms_size as usize {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "The terms can be at most {} bytes in total",
+                limits.max_terms_size
+            ),
+        });
+    }
+    Ok(())
+}
+
+pub fn validate_note(note: &str) -> Result<(), Error> {
+    if note.len() > MAX_NOTE_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("A note can be at most {} bytes", MAX_NOTE_SIZE),
+        });
+    }
+    Ok(())
+}
+
+pub fn validate_salt(salt: &str) -> Result<(), Error> {
+    if salt.len() > MAX_SALT_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("A salt can be at most {} bytes", MAX_SALT_SIZE),
+        });
+    }
+    Ok(())
+}
+
+/// Dispute statements and rulings share one limit on their text and evidence.
+pub fn validate_statement(text: &str, evidence: &[u64]) -> Result<(), Error> {
+    if text.trim().is_empty() || text.len() > MAX_STATEMENT_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A statement must be between 1 and {} bytes",
+                MAX_STATEMENT_SIZE
+            ),
+        });
+    }
+    if evidence.len() > MAX_EVIDENCE {
+        return Err(Error::InvalidInput {
+            msg: format!("A statement can have at most {} attachments", MAX_EVIDENCE),
+        });
+    }
+    Ok(())
+}
+
+/// What a signature adds to an agreement besides the copy of the unsigned
+/// agreement it embeds: a public key of two hashes per pair and one revealed
+/// half per pair. With compact keys the key is only committed to, and the
+/// signature carries the unrevealed halves instead.
+fn signature_overhead(security_level: &SecurityLevel, compact: bool) -> usize {
+    let hashes_per_pair = if compact { 2 } else { 3 };
+    hashes_per_pair * security_level.key_size() * HASH_SIZE + 1024
+}
+
+/// 256-bit public keys alone take more than half of an agreement's storage, so
+/// they are only stored as commitments.
+pub fn ensure_keys_fit(security_level: &SecurityLevel, compact: bool) -> Result<(), Error> {
+    if *security_level == SecurityLevel::Bits256 && !compact {
+        return Err(Error::InvalidInput {
+            msg: format!("256-bit keys are too large to store in full, so they need compact keys"),
+        });
+    }
+    Ok(())
+}
+
+/// Fails if the agreement would outgrow its storage once every missing
+/// signature and attestation is added, once a dispute is settled if it names
+/// an arbitrator, and once its obligations are claimed as often as they can be.
+/// Each party signature also embeds a copy of the agreement without its
+/// signatures.
+pub fn ensure_fits(agreement: &Agreement) -> Result<(), Error> {
+    ensure_keys_fit(&agreement.security_level(), agreement.has_compact_keys())?;
+    let mut size = agreement.to_bytes().len();
+    let unsigned_size = agreement.unsigned().to_bytes().len();
+    let security_level = agreement.security_level();
+    let (first, second) = agreement.proof_of_agreement.clone().unwrap_or((None, None));
+    let missing_signatures = [first.is_none(), second.is_none()]
+        .iter()
+        .filter(|missing| **missing)
+        .count();
+    size += missing_signatures
+        * (unsigned_size + signature_overhead(&security_level, agreement.has_compact_keys()));
+    let missing_attestations = agreement
+        .witnesses
+        .iter()
+        .flatten()
+        .filter(|witness| witness.attestation.is_none())
+        .count();
+    size += missing_attestations * signature_overhead(&security_level, false);
+    if agreement.arbitrator.is_some() && agreement.dispute.is_none() {
+        size += DISPUTE_SIZE + signature_overhead(&security_level, false);
+    }
+    // Room for the note of a claim, the reason it is contested and the events
+    // of every round left
+    for obligation in agreement.obligations.iter().flatten() {
+        size += 2 * MAX_NOTE_SIZE + obligation::events_left(agreement, obligation) * EVENT_SIZE;
+    }
+    if size > Agreement::MAX_SIZE as usize {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "The agreement would be too large to store once it is signed. Shorten the terms, or name fewer witnesses or obligations"
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Whether an ingress message is worth executing. Anonymous callers cannot
+/// sign or own anything, and arguments above the limits would be rejected anyway.
+pub fn accepts_ingress(method: &str, anonymous: bool, arg_size: usize) -> bool {
+    if anonymous && !ANONYMOUS_METHODS.contains(&method) {
+        return false;
+    }
+    let max_arg_size = match method {
+        "upload_attachment_chunk" => MAX_CHUNK_ARG_SIZE,
+        "upload_restore_chunk" => MAX_RESTORE_ARG_SIZE,
+        _ => MAX_INGRESS_ARG_SIZE,
+    };
+    arg_size <= max_arg_size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{AgreementEvent, EventKind};
+    use crate::user::{CreateAgreement, User};
+
+    #[test]
+    fn events_fit_the_room_left_for_them() {
+        let event = AgreementEvent::new(
+            u64::MAX,
+            User {
+                identity: String::from("x").repeat(63),
+            },
+            EventKind::ObligationContested {
+                obligation_id: u32::MAX,
+            },
+        );
+        // Measured inside a list, so that the type table is not counted
+        let one = Encode!(&vec![event.clone()]).unwrap().len();
+        let two = Encode!(&vec![event.clone(), event]).unwrap().len();
+        assert!(two - one <= EVENT_SIZE);
+    }
+
+    #[test]
+    fn rejects_too_many_or_too_long_terms() {
+        let limits = Limits::default();
+        let max_terms = limits.max_terms as usize;
+        let max_term_size = limits.max_term_size as usize;
+        assert!(validate_terms(&vec![String::from("term"); max_terms], &limits).is_ok());
+        assert!(validate_terms(&vec![String::from("term"); max_terms + 1], &limits).is_err());
+        assert!(validate_terms(&[String::from("x").repeat(max_term_size + 1)], &limits).is_err());
+        let long = String::from("x").repeat(max_term_size);
+        assert!(validate_terms(&[long.clone(), long.clone(), long], &limits).is_err());
+    }
+
+    #[test]
+    fn rate_window_allows_a_burst_and_then_resets() {
+        let limits = Limits::default();
+        let length = limits.rate_window_seconds * NANOS_PER_SECOND;
+        let mut window: Option<RateWindow> = None;
+        for _ in 0..limits.max_offers_per_window {
+            window = Some(RateWindow::record(window, 10, &limits).unwrap());
+        }
+        assert!(RateWindow::record(window.clone(), 10 + length - 1, &limits).is_err());
+        let reset = RateWindow::record(window, 10 + length, &limits).unwrap();
+        assert_eq!(reset.count, 1);
+    }
+
+    #[test]
+    fn unsigned_agreements_leave_room_for_signatures() {
+        let alice = User {
+            identity: String::from("alice"),
+        };
+        let agreement = alice.clone().new_agreement(
+            vec![String::from("x").repeat(Limits::default().max_term_size as usize); 2],
+            String::from("0"),
+            User {
+                identity: String::from("bob"),
+            },
+            alice,
+            1,
+        );
+        assert!(ensure_fits(&agreement).is_ok());
+    }
+
+    #[test]
+    fn keys_at_256_bits_only_fit_when_compact() {
+        let alice = User {
+            identity: String::from("alice"),
+        };
+        let agreement = alice.clone().new_agreement(
+            vec![String::from("Alice sells Bob 10 chairs")],
+            String::from("0"),
+            User {
+                identity: String::from("bob"),
+            },
+            alice,
+            1,
+        );
+        let agreement = Agreement {
+            security_level: Some(SecurityLevel::Bits256),
+            ..agreement
+        };
+        let err = ensure_fits(&agreement).unwrap_err();
+        assert!(err.msg().contains("compact keys"));
+        let compact = Agreement {
+            key_commitments: Some((None, None)),
+            ..agreement
+        };
+        assert!(ensure_fits(&compact).is_ok());
+    }
+
+    #[test]
+    fn limits_must_be_positive_and_consistent() {
+        assert!(Limits::default().validate().is_ok());
+        let no_offers = Limits {
+            max_offers_per_window: 0,
+            ..Limits::default()
+        };
+        assert!(no_offers.validate().is_err());
+        let inverted = Limits {
+            max_terms_size: 10,
+            ..Limits::default()
+        };
+        assert!(inverted.validate().is_err());
+    }
+
+    #[test]
+    fn ingress_filter_rejects_anonymous_and_oversized_calls() {
+        assert!(accepts_ingress("initiate_agreement", false, 1024));
+        assert!(!accepts_ingress("initiate_agreement", true, 1024));
+        assert!(!accepts_ingress(
+            "initiate_agreement",
+            false,
+            MAX_CHUNK_ARG_SIZE
+        ));
+        assert!(accepts_ingress(
+            "upload_attachment_chunk",
+            false,
+            MAX_CHUNK_ARG_SIZE
+        ));
+        assert!(accepts_ingress(
+            "icrc21_canister_call_consent_message",
+            true,
+            1024
+        ));
+    }
+}
@@ -0,0 +1,299 @@
+use std::borrow::Cow;
+
+use crate::icrc::{Account, Value};
+use candid::{Decode, Encode, Nat, Principal};
+use ic_stable_structures::{BoundedStorable, Storable};
+
+pub const MAX_QUERY_BATCH_SIZE: usize = 100;
+pub const MAX_UPDATE_BATCH_SIZE: usize = 20;
+pub const DEFAULT_TAKE_VALUE: usize = 100;
+pub const MAX_TAKE_VALUE: usize = 1000;
+pub const MAX_MEMO_SIZE: usize = 32;
+
+/// Settings for the optional ICRC-7 collection. While `enabled` is false no
+/// tokens are minted, but tokens minted earlier remain transferable.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct CollectionConfig {
+    pub enabled: bool,
+    pub name: String,
+    pub symbol: String,
+    pub description: Option<String>,
+    pub logo: Option<String>,
+}
+
+impl Default for CollectionConfig {
+    fn default() -> Self {
+        CollectionConfig {
+            enabled: false,
+            name: String::from("Proof of Agreement"),
+            symbol: String::from("POA"),
+            description: Some(String::from(
+                "One token for each party to a fully signed agreement",
+            )),
+            logo: None,
+        }
+    }
+}
+
+/// A token minted to one party of a fully signed agreement.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Token {
+    pub id: u64,
+    pub owner: Account,
+    pub agreement_id: u64,
+    pub digest: String,
+    pub minted_at: u64,
+}
+
+/// Indexes tokens by owner so balances and listings do not scan every token.
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, candid::CandidType, Serialize, Deserialize,
+)]
+pub struct OwnerKey {
+    pub owner: Principal,
+    pub subaccount: Vec<u8>,
+    pub token_id: u64,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub enum TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+impl Storable for CollectionConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Token {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Token {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for OwnerKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OwnerKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl OwnerKey {
+    pub fn new(account: &Account, token_id: u64) -> OwnerKey {
+        OwnerKey {
+            owner: account.owner,
+            subaccount: account.effective_subaccount().to_vec(),
+            token_id,
+        }
+    }
+
+    /// The keys of every token owned by `account` with an id after `prev`.
+    pub fn range_of(account: &Account, prev: Option<u64>) -> std::ops::RangeInclusive<OwnerKey> {
+        let start = prev.map_or(0, |prev| prev.saturating_add(1));
+        OwnerKey::new(account, start)..=OwnerKey::new(account, u64::MAX)
+    }
+}
+
+impl Token {
+    pub fn metadata(&self) -> Vec<(String, Value)> {
+        vec![
+            (
+                String::from("icrc7:name"),
+                Value::Text(format!("Agreement #{}", self.agreement_id)),
+            ),
+            (
+                String::from("agreement_id"),
+                Value::Nat(Nat::from(self.agreement_id)),
+            ),
+            (String::from("digest"), Value::Text(self.digest.clone())),
+            (
+                String::from("minted_at"),
+                Value::Nat(Nat::from(self.minted_at)),
+            ),
+        ]
+    }
+}
+
+pub fn collection_metadata(config: &CollectionConfig, total_supply: u64) -> Vec<(String, Value)> {
+    let mut metadata = vec![
+        (String::from("icrc7:name"), Value::Text(config.name.clone())),
+        (
+            String::from("icrc7:symbol"),
+            Value::Text(config.symbol.clone()),
+        ),
+        (
+            String::from("icrc7:total_supply"),
+            Value::Nat(Nat::from(total_supply)),
+        ),
+        (
+            String::from("icrc7:max_query_batch_size"),
+            Value::Nat(Nat::from(MAX_QUERY_BATCH_SIZE as u64)),
+        ),
+        (
+            String::from("icrc7:max_update_batch_size"),
+            Value::Nat(Nat::from(MAX_UPDATE_BATCH_SIZE as u64)),
+        ),
+        (
+            String::from("icrc7:default_take_value"),
+            Value::Nat(Nat::from(DEFAULT_TAKE_VALUE as u64)),
+        ),
+        (
+            String::from("icrc7:max_take_value"),
+            Value::Nat(Nat::from(MAX_TAKE_VALUE as u64)),
+        ),
+        (
+            String::from("icrc7:max_memo_size"),
+            Value::Nat(Nat::from(MAX_MEMO_SIZE as u64)),
+        ),
+    ];
+    if let Some(description) = &config.description {
+        metadata.push((
+            String::from("icrc7:description"),
+            Value::Text(description.clone()),
+        ));
+    }
+    if let Some(logo) = &config.logo {
+        metadata.push((String::from("icrc7:logo"), Value::Text(logo.clone())));
+    }
+    metadata
+}
+
+/// Converts a token id from the candid `nat` used by ICRC-7. Ids that do not
+/// fit in a `u64` cannot exist.
+pub fn token_id(id: &Nat) -> Option<u64> {
+    u64::try_from(&id.0).ok()
+}
+
+pub fn take(take: Option<Nat>) -> usize {
+    take.and_then(|take| u64::try_from(&take.0).ok())
+        .map_or(DEFAULT_TAKE_VALUE, |take| {
+            (take as usize).min(MAX_TAKE_VALUE)
+        })
+}
+
+/// Checks a single transfer made by `caller`, returning the token with its new owner.
+pub fn check_transfer(
+    arg: &TransferArg,
+    caller: Principal,
+    token: Option<Token>,
+) -> Result<Token, TransferError> {
+    if arg
+        .memo
+        .as_ref()
+        .map_or(false, |memo| memo.len() > MAX_MEMO_SIZE)
+    {
+        return Err(TransferError::GenericError {
+            error_code: Nat::from(1u64),
+            message: format!("A memo can be at most {} bytes", MAX_MEMO_SIZE),
+        });
+    }
+    let token = token.ok_or(TransferError::NonExistingTokenId)?;
+    let from = Account {
+        owner: caller,
+        subaccount: arg.from_subaccount.clone(),
+    };
+    if !from.has_valid_subaccount() || !token.owner.same_as(&from) {
+        return Err(TransferError::Unauthorized);
+    }
+    if !arg.to.has_valid_subaccount()
+        || arg.to.owner == Principal::anonymous()
+        || arg.to.same_as(&from)
+    {
+        return Err(TransferError::InvalidRecipient);
+    }
+    Ok(Token {
+        owner: arg.to.clone(),
+        ..token
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(owner: &str) -> Account {
+        Account {
+            owner: Principal::from_text(owner).unwrap(),
+            subaccount: None,
+        }
+    }
+
+    fn token(owner: &Account) -> Token {
+        Token {
+            id: 4,
+            owner: owner.clone(),
+            agreement_id: 2,
+            digest: String::from("00"),
+            minted_at: 0,
+        }
+    }
+
+    fn transfer(to: &Account) -> TransferArg {
+        TransferArg {
+            from_subaccount: Some(vec![0; 32]),
+            to: to.clone(),
+            token_id: Nat::from(4u64),
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    #[test]
+    fn only_the_owner_can_transfer() {
+        let alice = account("2vxsx-fae");
+        let bob = account("aaaaa-aa");
+        let moved = check_transfer(&transfer(&bob), alice.owner, Some(token(&alice))).unwrap();
+        assert!(moved.owner.same_as(&bob));
+
+        assert!(matches!(
+            check_transfer(&transfer(&alice), bob.owner, Some(token(&alice))),
+            Err(TransferError::Unauthorized)
+        ));
+        assert!(matches!(
+            check_transfer(&transfer(&alice), alice.owner, Some(token(&alice))),
+            Err(TransferError::InvalidRecipient)
+        ));
+        assert!(matches!(
+            check_transfer(&transfer(&bob), alice.owner, None),
+            Err(TransferError::NonExistingTokenId)
+        ));
+    }
+}
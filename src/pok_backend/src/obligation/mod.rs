@@ -0,0 +1,535 @@
+use std::borrow::Cow;
+
+use crate::agreement::Agreement;
+use crate::history::{AgreementEvent, EventKind};
+use crate::limits::MAX_NOTE_SIZE;
+use crate::user::User;
+use crate::Error;
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+
+pub const MAX_OBLIGATIONS: usize = 16;
+/// How many times an obligation can be claimed. Every claim and contest adds to
+/// the agreement's history, so the rounds are capped to keep it within its storage.
+pub const MAX_CLAIMS: usize = 3;
+
+/// An obligation declared in the terms: `responsible` has to do what the term
+/// at index `term` says by `due_at`.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct ObligationRequest {
+    pub term: u32,
+    pub responsible: String,
+    pub due_at: u64,
+    pub description: String,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Obligation {
+    pub id: u32,
+    pub term: u32,
+    pub responsible: User,
+    pub due_at: u64,
+    pub description: String,
+    pub status: ObligationStatus,
+    /// When a sweep found the deadline passed without a claim or a confirmation.
+    pub missed_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum ObligationStatus {
+    Pending,
+    /// The responsible party says the obligation is fulfilled.
+    Claimed {
+        at: u64,
+        note: String,
+    },
+    /// The counterparty confirmed the claim.
+    Fulfilled {
+        claimed_at: u64,
+        confirmed_at: u64,
+    },
+    /// The counterparty disputes the claim. The responsible party can claim again.
+    Contested {
+        claimed_at: u64,
+        contested_at: u64,
+        reason: String,
+    },
+}
+
+/// Orders the obligations of signed agreements by their deadline.
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, candid::CandidType, Serialize, Deserialize,
+)]
+pub struct DeadlineKey {
+    pub due_at: u64,
+    pub agreement_id: u64,
+    pub obligation_id: u32,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct OverdueObligation {
+    pub agreement_id: u64,
+    pub obligation: Obligation,
+}
+
+impl Storable for DeadlineKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DeadlineKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Obligation {
+    /// What the parties sign about the obligation: the term, who is
+    /// responsible, the deadline and the description.
+    pub fn message(&self) -> String {
+        format!(
+            "{}{}{}{}",
+            self.term,
+            self.responsible.identity.trim(),
+            self.due_at,
+            self.description
+        )
+    }
+
+    /// Past its deadline without a claim waiting for confirmation or a
+    /// confirmed fulfilment.
+    pub fn is_overdue(&self, now: u64) -> bool {
+        now >= self.due_at
+            && !matches!(
+                self.status,
+                ObligationStatus::Claimed { .. } | ObligationStatus::Fulfilled { .. }
+            )
+    }
+
+    /// Whether the deadline still has to be watched.
+    pub fn is_watched(&self) -> bool {
+        self.missed_at.is_none() && !matches!(self.status, ObligationStatus::Fulfilled { .. })
+    }
+}
+
+pub fn from_requests(
+    requests: Vec<ObligationRequest>,
+    agreement: &Agreement,
+    now: u64,
+) -> Result<Option<Vec<Obligation>>, Error> {
+    if requests.is_empty() {
+        return Ok(None);
+    }
+    if requests.len() > MAX_OBLIGATIONS {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "An agreement can have at most {} obligations",
+                MAX_OBLIGATIONS
+            ),
+        });
+    }
+    let mut obligations: Vec<Obligation> = Vec::with_capacity(requests.len());
+    for (id, request) in requests.into_iter().enumerate() {
+        let responsible = request.responsible.trim();
+        let responsible = if agreement.is_initiator(responsible) {
+            agreement.by_user.clone()
+        } else if agreement.is_party(responsible) {
+            agreement.with_user.clone()
+        } else {
+            return Err(Error::InvalidInput {
+                msg: format!("Only a party can be responsible for an obligation"),
+            });
+        };
+        if request.term as usize >= agreement.terms.len() {
+            return Err(Error::InvalidInput {
+                msg: format!("The agreement has no term {}", request.term),
+            });
+        }
+        if request.due_at <= now {
+            return Err(Error::InvalidInput {
+                msg: format!("An obligation must be due in the future"),
+            });
+        }
+        if request.description.len() > MAX_NOTE_SIZE {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "An obligation can be described in at most {} bytes",
+                    MAX_NOTE_SIZE
+                ),
+            });
+        }
+        obligations.push(Obligation {
+            id: id as u32,
+            term: request.term,
+            responsible,
+            due_at: request.due_at,
+            description: request.description,
+            status: ObligationStatus::Pending,
+            missed_at: None,
+        });
+    }
+    Ok(Some(obligations))
+}
+
+/// Fails if an obligation refers to a term the agreement does not have, as
+/// happens when a counter-offer drops terms.
+pub fn ensure_terms_exist(agreement: &Agreement) -> Result<(), Error> {
+    let terms = agreement.terms.len();
+    for obligation in agreement.obligations.iter().flatten() {
+        if obligation.term as usize >= terms {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Obligation {} refers to term {}, but the agreement has {} terms",
+                    obligation.id, obligation.term, terms
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The responsible party says the obligation is fulfilled.
+pub fn claim(
+    agreement: &mut Agreement,
+    obligation_id: u32,
+    caller: &str,
+    note: String,
+    now: u64,
+) -> Result<(), Error> {
+    let obligation = obligation_mut(agreement, obligation_id)?;
+    if obligation.responsible.identity.trim() != caller.trim() {
+        return Err(Error::Unauthorized {
+            msg: format!("Only the party responsible for the obligation can claim it"),
+        });
+    }
+    if !matches!(
+        obligation.status,
+        ObligationStatus::Pending | ObligationStatus::Contested { .. }
+    ) {
+        return Err(Error::InvalidInput {
+            msg: format!("The obligation has already been claimed"),
+        });
+    }
+    if claims(agreement, obligation_id) >= MAX_CLAIMS {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "An obligation can be claimed at most {} times. Open a dispute instead",
+                MAX_CLAIMS
+            ),
+        });
+    }
+    let obligation = obligation_mut(agreement, obligation_id)?;
+    obligation.status = ObligationStatus::Claimed { at: now, note };
+    record(
+        agreement,
+        now,
+        caller,
+        EventKind::ObligationClaimed { obligation_id },
+    );
+    Ok(())
+}
+
+/// The counterparty accepts the claim.
+pub fn confirm(
+    agreement: &mut Agreement,
+    obligation_id: u32,
+    caller: &str,
+    now: u64,
+) -> Result<(), Error> {
+    let claimed_at = claim_to_answer(agreement, obligation_id, caller)?;
+    obligation_mut(agreement, obligation_id)?.status = ObligationStatus::Fulfilled {
+        claimed_at,
+        confirmed_at: now,
+    };
+    record(
+        agreement,
+        now,
+        caller,
+        EventKind::ObligationConfirmed { obligation_id },
+    );
+    Ok(())
+}
+
+/// The counterparty rejects the claim.
+pub fn contest(
+    agreement: &mut Agreement,
+    obligation_id: u32,
+    caller: &str,
+    reason: String,
+    now: u64,
+) -> Result<(), Error> {
+    let claimed_at = claim_to_answer(agreement, obligation_id, caller)?;
+    obligation_mut(agreement, obligation_id)?.status = ObligationStatus::Contested {
+        claimed_at,
+        contested_at: now,
+        reason,
+    };
+    record(
+        agreement,
+        now,
+        caller,
+        EventKind::ObligationContested { obligation_id },
+    );
+    Ok(())
+}
+
+/// Flags the obligation once its deadline passed without a claim or a
+/// confirmation. Returns whether it was flagged.
+pub fn flag_missed(agreement: &mut Agreement, obligation_id: u32, by: User, now: u64) -> bool {
+    let obligation = match obligation_mut(agreement, obligation_id) {
+        Ok(obligation) if obligation.is_watched() && obligation.is_overdue(now) => obligation,
+        _ => return false,
+    };
+    obligation.missed_at = Some(now);
+    agreement.record(AgreementEvent::new(
+        now,
+        by,
+        EventKind::ObligationMissed { obligation_id },
+    ));
+    true
+}
+
+/// How many times the obligation has been claimed so far.
+fn claims(agreement: &Agreement, obligation_id: u32) -> usize {
+    agreement
+        .history
+        .iter()
+        .flatten()
+        .filter(|event| {
+            matches!(event.kind, EventKind::ObligationClaimed { obligation_id: id } if id == obligation_id)
+        })
+        .count()
+}
+
+/// The most events the obligation can still add to the history: a claim and a
+/// contest for every claim left, then a confirmation and a missed deadline.
+pub fn events_left(agreement: &Agreement, obligation: &Obligation) -> usize {
+    2 * MAX_CLAIMS.saturating_sub(claims(agreement, obligation.id)) + 2
+}
+
+pub fn overdue(agreement: &Agreement, now: u64) -> Vec<OverdueObligation> {
+    if !agreement.is_fully_signed() || agreement.is_terminated() {
+        return vec![];
+    }
+    agreement
+        .obligations
+        .iter()
+        .flatten()
+        .filter(|obligation| obligation.is_overdue(now))
+        .map(|obligation| OverdueObligation {
+            agreement_id: agreement.id,
+            obligation: obligation.clone(),
+        })
+        .collect()
+}
+
+/// When the claim the counterparty is answering was made.
+fn claim_to_answer(
+    agreement: &mut Agreement,
+    obligation_id: u32,
+    caller: &str,
+) -> Result<u64, Error> {
+    let is_party = agreement.is_party(caller);
+    let obligation = obligation_mut(agreement, obligation_id)?;
+    if !is_party || obligation.responsible.identity.trim() == caller.trim() {
+        return Err(Error::Unauthorized {
+            msg: format!("Only the counterparty can confirm or contest a claim"),
+        });
+    }
+    match obligation.status {
+        ObligationStatus::Claimed { at, .. } => Ok(at),
+        _ => Err(Error::InvalidInput {
+            msg: format!("The obligation has no claim to answer"),
+        }),
+    }
+}
+
+fn obligation_mut(agreement: &mut Agreement, obligation_id: u32) -> Result<&mut Obligation, Error> {
+    if !agreement.is_fully_signed() {
+        return Err(Error::InvalidInput {
+            msg: format!("Obligations only apply once both parties signed"),
+        });
+    }
+    agreement
+        .obligations
+        .iter_mut()
+        .flatten()
+        .find(|obligation| obligation.id == obligation_id)
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("The agreement has no obligation {}", obligation_id),
+        })
+}
+
+fn record(agreement: &mut Agreement, now: u64, caller: &str, kind: EventKind) {
+    let by = User {
+        identity: caller.to_string(),
+    };
+    agreement.record(AgreementEvent::new(now, by, kind));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::{Agree, CreateAgreement};
+
+    fn user(identity: &str) -> User {
+        User {
+            identity: identity.to_string(),
+        }
+    }
+
+    fn offer() -> Agreement {
+        user("alice").new_agreement(
+            vec![
+                "Alice delivers 10 chairs".to_string(),
+                "Bob pays on delivery".to_string(),
+            ],
+            String::from("0"),
+            user("bob"),
+            user("alice"),
+            1,
+        )
+    }
+
+    fn request(term: u32, responsible: &str, due_at: u64) -> ObligationRequest {
+        ObligationRequest {
+            term,
+            responsible: responsible.to_string(),
+            due_at,
+            description: String::from("Delivery"),
+        }
+    }
+
+    fn signed() -> Agreement {
+        let agreement = offer();
+        let obligations = from_requests(
+            vec![request(0, "alice", 100), request(1, " bob ", 200)],
+            &agreement,
+            10,
+        )
+        .unwrap();
+        let agreement = Agreement {
+            obligations,
+            ..agreement
+        };
+        user("bob").agree(user("alice").automatic_agreement(agreement))
+    }
+
+    #[test]
+    fn rejects_invalid_obligations() {
+        let agreement = offer();
+        assert!(from_requests(vec![request(2, "alice", 100)], &agreement, 10).is_err());
+        assert!(from_requests(vec![request(0, "carol", 100)], &agreement, 10).is_err());
+        assert!(from_requests(vec![request(0, "alice", 10)], &agreement, 10).is_err());
+        let obligations = from_requests(vec![request(1, "bob", 100)], &agreement, 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(obligations[0].responsible.identity, "bob");
+
+        let counter_offer = Agreement {
+            terms: vec!["Alice delivers 10 chairs".to_string()],
+            obligations: Some(obligations),
+            ..agreement
+        };
+        assert!(ensure_terms_exist(&counter_offer).is_err());
+    }
+
+    #[test]
+    fn claims_are_confirmed_or_contested_by_the_counterparty() {
+        let mut unsigned = offer();
+        unsigned.obligations = signed().obligations;
+        assert!(claim(&mut unsigned, 0, "alice", String::new(), 50).is_err());
+
+        let mut agreement = signed();
+        assert!(claim(&mut agreement, 0, "bob", String::new(), 50).is_err());
+        assert!(claim(&mut agreement, 5, "alice", String::new(), 50).is_err());
+        assert!(confirm(&mut agreement, 0, "bob", 50).is_err());
+
+        claim(&mut agreement, 0, "alice", String::from("Delivered"), 50).unwrap();
+        assert!(confirm(&mut agreement, 0, "alice", 60).is_err());
+        contest(&mut agreement, 0, "bob", String::from("Only 9 chairs"), 60).unwrap();
+        assert!(contest(&mut agreement, 0, "bob", String::new(), 70).is_err());
+
+        claim(
+            &mut agreement,
+            0,
+            "alice",
+            String::from("Sent the last one"),
+            70,
+        )
+        .unwrap();
+        confirm(&mut agreement, 0, "bob", 80).unwrap();
+        assert_eq!(
+            agreement.obligations.as_ref().unwrap()[0].status,
+            ObligationStatus::Fulfilled {
+                claimed_at: 70,
+                confirmed_at: 80
+            }
+        );
+        assert_eq!(agreement.history.as_ref().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn claims_are_capped() {
+        let mut agreement = signed();
+        assert_eq!(
+            events_left(&agreement, &agreement.obligations.clone().unwrap()[0]),
+            2 * MAX_CLAIMS + 2
+        );
+        for round in 0..MAX_CLAIMS as u64 {
+            claim(&mut agreement, 0, "alice", String::from("Done"), 50 + round).unwrap();
+            contest(
+                &mut agreement,
+                0,
+                "bob",
+                String::from("Not yet"),
+                50 + round,
+            )
+            .unwrap();
+        }
+        assert!(claim(&mut agreement, 0, "alice", String::from("Done"), 90).is_err());
+        assert_eq!(
+            events_left(&agreement, &agreement.obligations.clone().unwrap()[0]),
+            2
+        );
+        // The other obligation still has every claim left
+        claim(&mut agreement, 1, "bob", String::from("Paid"), 90).unwrap();
+    }
+
+    #[test]
+    fn missed_deadlines_are_flagged_once() {
+        let mut agreement = signed();
+        assert!(overdue(&agreement, 99).is_empty());
+        assert!(!flag_missed(&mut agreement, 0, user("canister"), 99));
+
+        let overdue_ids: Vec<u32> = overdue(&agreement, 200)
+            .iter()
+            .map(|overdue| overdue.obligation.id)
+            .collect();
+        assert_eq!(overdue_ids, vec![0, 1]);
+
+        assert!(flag_missed(&mut agreement, 0, user("canister"), 100));
+        assert!(!flag_missed(&mut agreement, 0, user("canister"), 101));
+        assert_eq!(
+            agreement.obligations.as_ref().unwrap()[0].missed_at,
+            Some(100)
+        );
+
+        // A pending claim is not overdue, a contested one is again
+        claim(&mut agreement, 1, "bob", String::from("Paid"), 150).unwrap();
+        assert!(!flag_missed(&mut agreement, 1, user("canister"), 200));
+        contest(
+            &mut agreement,
+            1,
+            "alice",
+            String::from("Not received"),
+            210,
+        )
+        .unwrap();
+        assert!(flag_missed(&mut agreement, 1, user("canister"), 210));
+    }
+}
@@ -1,9 +1,62 @@
 use crate::agreement::Agreement;
-use crate::lamport::Signature as Lsignature;
+use crate::delegation::DelegatedSignature;
+use crate::lamport::{SecurityLevel, Signature as Lsignature};
+use crate::timestamp::Timestamp;
+use crate::user::User;
 
 #[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
 pub struct Signature {
     pub agrees_to: Box<Agreement>,
 
     pub value: Lsignature,
+
+    pub delegation: Option<DelegatedSignature>,
+
+    pub signed_at: Option<Timestamp>,
+
+    pub metadata: Option<SignerMetadata>,
+}
+
+/// Who produced a signature and how, recorded when it is added.
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub struct SignerMetadata {
+    /// The principal whose call produced the signature: the party, or their delegate.
+    pub signer: User,
+    pub scheme: SignatureScheme,
+    /// The hex SHA-256 of the canister module that signed. Missing if the
+    /// canister had not looked it up yet.
+    pub module_hash: Option<String>,
+    pub key_origin: KeyOrigin,
+}
+
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum SignatureScheme {
+    Lamport {
+        security_level: SecurityLevel,
+        key_pairs: u32,
+        compact_keys: bool,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum KeyOrigin {
+    /// Derived by the canister from the signer and the agreement.
+    Server,
+}
+
+impl SignerMetadata {
+    /// Metadata for a signature the canister makes on `agreement` with a key it derived.
+    pub fn new(signer: User, agreement: &Agreement, module_hash: Option<String>) -> SignerMetadata {
+        let security_level = agreement.security_level();
+        SignerMetadata {
+            signer,
+            scheme: SignatureScheme::Lamport {
+                key_pairs: security_level.key_size() as u32,
+                security_level,
+                compact_keys: agreement.has_compact_keys(),
+            },
+            module_hash,
+            key_origin: KeyOrigin::Server,
+        }
+    }
 }
@@ -0,0 +1,390 @@
+use std::borrow::Cow;
+
+use crate::limits::{self, Limits};
+use crate::user::User;
+use crate::Error;
+use candid::{Decode, Encode};
+use chrono::NaiveDate;
+use ic_stable_structures::{BoundedStorable, Storable};
+
+const MAX_PLACEHOLDER_NAME: usize = 64;
+const MAX_VALUE_SIZE: usize = 256;
+
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum PlaceholderKind {
+    PartyName,
+    Amount,
+    Date,
+    Duration,
+    Text,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Placeholder {
+    pub name: String,
+    pub kind: PlaceholderKind,
+}
+
+/// A reusable set of terms. Placeholders are written into the terms as `{{name}}`
+/// and are filled in when an agreement is initiated from the template.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Template {
+    pub id: u64,
+    pub version: u32,
+    pub name: String,
+    pub terms: Vec<String>,
+    pub placeholders: Vec<Placeholder>,
+    pub created_by: User,
+}
+
+/// Points an agreement back at the exact template version it was rendered from.
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub struct TemplateRef {
+    pub id: u64,
+    pub version: u32,
+}
+
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, candid::CandidType, Serialize, Deserialize,
+)]
+pub struct TemplateKey {
+    pub id: u64,
+    pub version: u32,
+}
+
+impl Storable for Template {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Template {
+    const MAX_SIZE: u32 = 100000;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for TemplateKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TemplateKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Template {
+    pub fn key(&self) -> TemplateKey {
+        TemplateKey {
+            id: self.id,
+            version: self.version,
+        }
+    }
+
+    pub fn reference(&self) -> TemplateRef {
+        TemplateRef {
+            id: self.id,
+            version: self.version,
+        }
+    }
+
+    /// Checks that every placeholder is declared once with a usable name and that
+    /// the terms only reference declared placeholders.
+    pub fn validate(&self, limits: &Limits) -> Result<(), Error> {
+        if self.terms.is_empty() {
+            return Err(invalid("A template needs at least one term"));
+        }
+        limits::validate_terms(&self.terms, limits)?;
+        for (index, placeholder) in self.placeholders.iter().enumerate() {
+            if !is_valid_name(&placeholder.name) {
+                return Err(invalid(&format!(
+                    "Placeholder name '{}' may only contain letters, digits and underscores",
+                    placeholder.name
+                )));
+            }
+            if self.placeholders[..index]
+                .iter()
+                .any(|other| other.name == placeholder.name)
+            {
+                return Err(invalid(&format!(
+                    "Placeholder '{}' is declared more than once",
+                    placeholder.name
+                )));
+            }
+        }
+        for term in self.terms.iter() {
+            for name in placeholder_names(term)? {
+                if !self.placeholders.iter().any(|p| p.name == name) {
+                    return Err(invalid(&format!(
+                        "Placeholder '{}' is used but not declared",
+                        name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates the supplied values against the declared placeholders and renders
+    /// them into the terms.
+    pub fn render(&self, values: &[(String, String)]) -> Result<Vec<String>, Error> {
+        for (name, _) in values.iter() {
+            if !self.placeholders.iter().any(|p| &p.name == name) {
+                return Err(invalid(&format!(
+                    "Template has no placeholder called '{}'",
+                    name
+                )));
+            }
+        }
+        let mut resolved: Vec<(&str, String)> = Vec::with_capacity(self.placeholders.len());
+        for placeholder in self.placeholders.iter() {
+            let value = match values.iter().find(|(name, _)| name == &placeholder.name) {
+                Some((_, value)) => value.trim(),
+                None => {
+                    return Err(invalid(&format!(
+                        "No value was given for '{}'",
+                        placeholder.name
+                    )))
+                }
+            };
+            validate_value(&placeholder.kind, value).map_err(|msg| {
+                invalid(&format!(
+                    "Invalid value for '{}': {}",
+                    placeholder.name, msg
+                ))
+            })?;
+            resolved.push((placeholder.name.as_str(), value.to_string()));
+        }
+
+        let mut rendered: Vec<String> = Vec::with_capacity(self.terms.len());
+        for term in self.terms.iter() {
+            let mut term = term.clone();
+            for (name, value) in resolved.iter() {
+                term = term.replace(&format!("{{{{{}}}}}", name), value);
+            }
+            rendered.push(term);
+        }
+        Ok(rendered)
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::InvalidInput {
+        msg: msg.to_string(),
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_PLACEHOLDER_NAME
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn placeholder_names(term: &str) -> Result<Vec<String>, Error> {
+    let mut names = vec![];
+    let mut rest = term;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                // `render` only replaces the exact `{{name}}` form, so anything
+                // else inside a marker would end up verbatim in the signed terms
+                let name = &after[..end];
+                if !is_valid_name(name) {
+                    return Err(invalid(&format!(
+                        "Placeholder '{{{{{}}}}}' in '{}' must be a bare name such as '{{{{name}}}}'",
+                        name, term
+                    )));
+                }
+                names.push(name.to_string());
+                rest = &after[end + 2..];
+            }
+            None => return Err(invalid(&format!("Unclosed placeholder in '{}'", term))),
+        }
+    }
+    Ok(names)
+}
+
+fn validate_value(kind: &PlaceholderKind, value: &str) -> Result<(), String> {
+    if value.is_empty() {
+        return Err(String::from("value is empty"));
+    }
+    if value.len() > MAX_VALUE_SIZE {
+        return Err(format!("value is longer than {} bytes", MAX_VALUE_SIZE));
+    }
+    if value.contains("{{") || value.contains("}}") {
+        return Err(String::from("value may not contain placeholder markers"));
+    }
+    match kind {
+        PlaceholderKind::PartyName | PlaceholderKind::Text => Ok(()),
+        PlaceholderKind::Amount => {
+            // A decimal number, optionally followed by a currency or token symbol: "1500.50 ICP"
+            let mut parts = value.splitn(2, ' ');
+            let number = parts.next().unwrap_or_default();
+            let mut halves = number.splitn(2, '.');
+            let whole = halves.next().unwrap_or_default();
+            let fraction = halves.next();
+            let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+            if !digits(whole) || fraction.map_or(false, |f| !digits(f)) {
+                return Err(format!("'{}' is not a decimal amount", number));
+            }
+            match parts.next() {
+                Some(symbol) if !symbol.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                    Err(format!("'{}' is not a currency symbol", symbol))
+                }
+                _ => Ok(()),
+            }
+        }
+        PlaceholderKind::Date => NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .map(|_| ())
+            .map_err(|_| format!("'{}' is not a date in the form YYYY-MM-DD", value)),
+        PlaceholderKind::Duration => {
+            // A whole number followed by a unit: "30 days", "1 year"
+            let mut parts = value.split_whitespace();
+            let count = parts.next().and_then(|n| n.parse::<u32>().ok());
+            let unit = parts.next();
+            match (count, unit, parts.next()) {
+                (Some(count), Some(unit), None)
+                    if count > 0
+                        && matches!(
+                            unit.trim_end_matches('s'),
+                            "day" | "week" | "month" | "year"
+                        ) =>
+                {
+                    Ok(())
+                }
+                _ => Err(format!(
+                    "'{}' is not a duration such as '30 days' or '1 year'",
+                    value
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nda() -> Template {
+        Template {
+            id: 0,
+            version: 1,
+            name: String::from("Mutual NDA"),
+            terms: vec![
+                "{{discloser}} shares confidential information with {{recipient}}".to_string(),
+                "This agreement lasts {{term}} from {{start}}".to_string(),
+                "Breach is compensated with {{penalty}}".to_string(),
+            ],
+            placeholders: vec![
+                Placeholder {
+                    name: "discloser".to_string(),
+                    kind: PlaceholderKind::PartyName,
+                },
+                Placeholder {
+                    name: "recipient".to_string(),
+                    kind: PlaceholderKind::PartyName,
+                },
+                Placeholder {
+                    name: "term".to_string(),
+                    kind: PlaceholderKind::Duration,
+                },
+                Placeholder {
+                    name: "start".to_string(),
+                    kind: PlaceholderKind::Date,
+                },
+                Placeholder {
+                    name: "penalty".to_string(),
+                    kind: PlaceholderKind::Amount,
+                },
+            ],
+            created_by: User {
+                identity: String::from("amschel"),
+            },
+        }
+    }
+
+    fn values() -> Vec<(String, String)> {
+        vec![
+            ("discloser".to_string(), "Acme DAO".to_string()),
+            ("recipient".to_string(), "Bob".to_string()),
+            ("term".to_string(), "2 years".to_string()),
+            ("start".to_string(), "2024-07-01".to_string()),
+            ("penalty".to_string(), "1000.5 ICP".to_string()),
+        ]
+    }
+
+    #[test]
+    fn renders_values_into_terms() {
+        let template = nda();
+        assert!(template.validate(&Limits::default()).is_ok());
+        let terms = template.render(&values()).unwrap();
+        assert_eq!(
+            terms,
+            vec![
+                "Acme DAO shares confidential information with Bob".to_string(),
+                "This agreement lasts 2 years from 2024-07-01".to_string(),
+                "Breach is compensated with 1000.5 ICP".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_badly_typed_and_missing_values() {
+        let template = nda();
+        let mut bad_date = values();
+        bad_date[3].1 = "01/07/2024".to_string();
+        assert!(template.render(&bad_date).is_err());
+
+        let mut bad_amount = values();
+        bad_amount[4].1 = "a lot".to_string();
+        assert!(template.render(&bad_amount).is_err());
+
+        let mut bad_duration = values();
+        bad_duration[2].1 = "forever".to_string();
+        assert!(template.render(&bad_duration).is_err());
+
+        let mut missing = values();
+        missing.pop();
+        assert!(template.render(&missing).is_err());
+
+        let mut unknown = values();
+        unknown.push(("witness".to_string(), "Carol".to_string()));
+        assert!(template.render(&unknown).is_err());
+    }
+
+    #[test]
+    fn rejects_undeclared_placeholders() {
+        let mut template = nda();
+        template
+            .terms
+            .push("Governed by the laws of {{jurisdiction}}".to_string());
+        assert!(template.validate(&Limits::default()).is_err());
+
+        let mut template = nda();
+        template.terms.push("Unclosed {{marker".to_string());
+        assert!(template.validate(&Limits::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_whitespace_inside_markers() {
+        let mut template = nda();
+        template
+            .terms
+            .push("Signed on behalf of {{ discloser }}".to_string());
+        assert!(template.validate(&Limits::default()).is_err());
+
+        let mut template = nda();
+        template.terms.push("Penalty: {{penalty }}".to_string());
+        assert!(template.validate(&Limits::default()).is_err());
+    }
+}
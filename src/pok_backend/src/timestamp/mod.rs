@@ -0,0 +1,193 @@
+use crate::agreement::Agreement;
+use crate::Error;
+use chrono::{DateTime, SecondsFormat};
+
+/// A point in time in IC nanoseconds since the Unix epoch, along with the same
+/// time in RFC 3339 for readers of the Candid output.
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub nanos: u64,
+    pub rfc3339: String,
+}
+
+/// Nanoseconds from `from`, inclusive, to `to`, exclusive. Either end may be open.
+#[derive(Clone, Debug, Default, candid::CandidType, Serialize, Deserialize)]
+pub struct TimeRange {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+/// Narrows a list of agreements by when things happened to them. Every range
+/// that is set must match.
+#[derive(Clone, Debug, Default, candid::CandidType, Serialize, Deserialize)]
+pub struct TimeFilter {
+    pub created: Option<TimeRange>,
+    /// Matches agreements a party signed within the range.
+    pub signed: Option<TimeRange>,
+    /// Matches agreements with an event in their history within the range.
+    pub event: Option<TimeRange>,
+}
+
+impl Timestamp {
+    pub fn new(nanos: u64) -> Timestamp {
+        Timestamp {
+            nanos,
+            rfc3339: rfc3339(nanos),
+        }
+    }
+}
+
+impl TimeRange {
+    pub fn contains(&self, nanos: u64) -> bool {
+        self.from.is_none_or(|from| nanos >= from) && self.to.is_none_or(|to| nanos < to)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        match (self.from, self.to) {
+            (Some(from), Some(to)) if from >= to => Err(Error::InvalidInput {
+                msg: "A time range must end after it starts".to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TimeFilter {
+    pub fn validate(&self) -> Result<(), Error> {
+        for range in [&self.created, &self.signed, &self.event]
+            .into_iter()
+            .flatten()
+        {
+            range.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Whether no range is set, so that every agreement matches without being read.
+    pub fn is_empty(&self) -> bool {
+        self.created.is_none() && self.signed.is_none() && self.event.is_none()
+    }
+
+    pub fn matches(&self, agreement: &Agreement) -> bool {
+        let created = self.created.as_ref().is_none_or(|range| {
+            agreement
+                .created_at_nanos()
+                .is_some_and(|nanos| range.contains(nanos))
+        });
+        let signed = self.signed.as_ref().is_none_or(|range| {
+            let (first, second) = agreement.signed_at();
+            [first, second]
+                .iter()
+                .flatten()
+                .any(|signed_at| range.contains(signed_at.nanos))
+        });
+        let event = self.event.as_ref().is_none_or(|range| {
+            agreement
+                .history
+                .iter()
+                .flatten()
+                .any(|event| range.contains(event.at))
+        });
+        created && signed && event
+    }
+}
+
+/// Formats IC nanoseconds as RFC 3339 in UTC, with as many fractional digits as
+/// the time needs.
+pub fn rfc3339(nanos: u64) -> String {
+    DateTime::from_timestamp_nanos(nanos.min(i64::MAX as u64) as i64)
+        .to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::SignerMetadata;
+    use crate::user::{Agree, CreateAgreement, User};
+
+    #[test]
+    fn formats_nanoseconds_as_rfc3339() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            Timestamp::new(1_700_000_000_000_000_000).rfc3339,
+            "2023-11-14T22:13:20Z"
+        );
+        assert_eq!(
+            rfc3339(1_700_000_000_123_000_000),
+            "2023-11-14T22:13:20.123Z"
+        );
+        assert_eq!(
+            rfc3339(1_700_000_000_000_000_001),
+            "2023-11-14T22:13:20.000000001Z"
+        );
+    }
+
+    #[test]
+    fn filters_agreements_by_creation_time() {
+        let alice = User {
+            identity: String::from("alice"),
+        };
+        let agreement = alice.clone().new_agreement(
+            vec!["Alice sells Bob 10 chairs".to_string()],
+            String::from("100"),
+            User {
+                identity: String::from("bob"),
+            },
+            alice,
+            1,
+        );
+        let created = |from, to| TimeFilter {
+            created: Some(TimeRange { from, to }),
+            ..TimeFilter::default()
+        };
+        // Agreements without a creation timestamp fall back to their date
+        assert!(created(Some(100), Some(101)).matches(&agreement));
+        assert!(!created(None, Some(100)).matches(&agreement));
+        let agreement = Agreement {
+            created_at: Some(Timestamp::new(200)),
+            ..agreement
+        };
+        assert!(created(Some(200), None).matches(&agreement));
+        assert!(!created(Some(100), Some(101)).matches(&agreement));
+        assert!(TimeFilter::default().is_empty());
+        assert!(TimeFilter::default().matches(&agreement));
+
+        assert!(created(Some(5), Some(5)).validate().is_err());
+    }
+
+    #[test]
+    fn stamps_and_filters_by_signature_time() {
+        let alice = User {
+            identity: String::from("alice"),
+        };
+        let bob = User {
+            identity: String::from("bob"),
+        };
+        let agreement = alice.clone().new_agreement(
+            vec!["Alice sells Bob 10 chairs".to_string()],
+            String::from("0"),
+            bob.clone(),
+            alice.clone(),
+            1,
+        );
+        let signed = |from, to| TimeFilter {
+            signed: Some(TimeRange { from, to }),
+            ..TimeFilter::default()
+        };
+        assert!(!signed(None, None).matches(&agreement));
+
+        let mut agreement = bob.clone().agree(agreement);
+        let metadata = SignerMetadata::new(bob, &agreement, None);
+        agreement.stamp_signature("bob", Timestamp::new(300), metadata);
+        let mut agreement = alice.clone().agree(agreement);
+        let metadata = SignerMetadata::new(alice, &agreement, None);
+        agreement.stamp_signature("alice", Timestamp::new(100), metadata);
+        let (first, second) = agreement.signed_at();
+        assert_eq!(first.map(|at| at.nanos), Some(100));
+        assert_eq!(second.map(|at| at.nanos), Some(300));
+
+        assert!(signed(Some(100), Some(101)).matches(&agreement));
+        assert!(signed(Some(300), None).matches(&agreement));
+        assert!(!signed(Some(101), Some(300)).matches(&agreement));
+    }
+}
@@ -1,7 +1,10 @@
 use std::borrow::Cow;
 
-use crate::agreement::{self, Agreement, ProofOfAgreement, PublicKeys};
-use crate::lamport::{create_public_key, hash, random_private_key, sign};
+use crate::agreement::{self, Agreement, ProofOfAgreement};
+use crate::lamport::{
+    self, create_public_key, key_commitment, random_private_key, sign, sign_compact, PrivateKey,
+    PublicKey,
+};
 use crate::signature::{self, Signature};
 
 use candid::{Decode, Encode, Principal};
@@ -9,7 +12,7 @@ use chrono::{DateTime, Utc};
 use ic_stable_structures::{BoundedStorable, Storable};
 use sha2::{Digest, Sha256};
 // #[derive(Clone, Debug)]
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct User {
     pub identity: String,
 }
@@ -41,30 +44,25 @@ pub trait CreateAgreement {
 }
 pub trait Agree {
     fn agree(self, agreement: Agreement) -> Agreement;
+    fn agree_for(self, party: &User, agreement: Agreement) -> Agreement;
     fn automatic_agreement(&self, mut agreement: Agreement) -> Agreement {
         //private key should be created from the user identity and the agreement and a cobination of other factors and then we sign the contract to get a signature
         let privateKey = random_private_key(agreement.clone().by_user.identity, agreement.clone());
         let public_key = create_public_key(&privateKey);
-        let mut terms_string: String = String::new();
-        for term in agreement.clone().terms.iter() {
-            terms_string.push_str(term);
-        }
-        let generated_signature = sign(hash(&terms_string), &privateKey);
+        let generated_signature = sign_agreement(&agreement, &privateKey, &public_key);
         //private key should be created from the user identity and the agreement and a cobination of other factors and then we sign the contract to get a signature
         let signature = Signature {
-            agrees_to: Box::new(agreement.clone()),
+            agrees_to: Box::new(agreement.unsigned()),
             value: generated_signature,
+            delegation: None,
+            signed_at: None,
+            metadata: None,
         };
 
         let new_agreement = (Some(signature), None);
-        let new_public__key_field = (Some(public_key), None);
-        agreement.proof_of_agreement = Some(new_agreement.clone());
-        Agreement {
-            proof_of_agreement: Some(new_agreement),
-            public_keys: Some(new_public__key_field),
-
-            ..agreement.clone()
-        }
+        agreement.proof_of_agreement = Some(new_agreement);
+        store_key(&mut agreement, public_key, true);
+        agreement
     }
 }
 impl CreateAgreement for User {
@@ -84,48 +82,94 @@ impl CreateAgreement for User {
             id,
             proof_of_agreement: None,
             public_keys: None,
+            template: None,
+            attachments: None,
+            witnesses: None,
+            tags: None,
+            history: None,
+            expires_at: None,
+            execution: None,
+            tokens: None,
+            payment: None,
+            commitment: None,
+            visibility: None,
+            viewers: None,
+            verification: None,
+            key_commitments: None,
+            security_level: None,
+            created_at: None,
+            arbitrator: None,
+            dispute: None,
+            obligations: None,
         }
     }
 }
 
 impl Agree for User {
-    fn agree(self, mut agreement: Agreement) -> Agreement {
+    fn agree(self, agreement: Agreement) -> Agreement {
+        let party = self.clone();
+        self.agree_for(&party, agreement)
+    }
+
+    fn agree_for(self, party: &User, mut agreement: Agreement) -> Agreement {
         let privateKey = random_private_key(self.identity, agreement.clone());
         let public_key = create_public_key(&privateKey);
-        let mut terms_string: String = String::new();
-        for term in agreement.clone().terms.iter() {
-            terms_string.push_str(term);
-        }
-        let generated_signature = sign(hash(&terms_string), &privateKey);
+        let generated_signature = sign_agreement(&agreement, &privateKey, &public_key);
         //private key should be created from the user identity and the agreement and a cobination of other factors and then we sign the contract to get a signature
         let signature = Signature {
-            agrees_to: Box::new(agreement.clone()),
+            agrees_to: Box::new(agreement.unsigned()),
             value: generated_signature,
+            delegation: None,
+            signed_at: None,
+            metadata: None,
         };
 
-        if let Some((first_sig_opt, second_sig_opt)) = &mut agreement.clone().proof_of_agreement {
-            match first_sig_opt {
-                Some(_) => {
-                    let new_public__key_field: PublicKeys = (
-                        Some(agreement.public_keys.clone().unwrap().0.unwrap()),
-                        Some(public_key),
-                    );
-                    let new_agreement = (first_sig_opt.take(), Some(signature));
-                    agreement.proof_of_agreement = Some(new_agreement);
-                    agreement.public_keys = Some(new_public__key_field);
-                }
-                None => {
-                    let new_public__key_field: PublicKeys = (
-                        Some(agreement.public_keys.clone().unwrap().0.unwrap()),
-                        Some(public_key),
-                    );
-                    agreement.public_keys = Some(new_public__key_field);
-
-                    let new_agreement = (Some(signature), second_sig_opt.take());
-                    agreement.proof_of_agreement = Some(new_agreement);
-                }
-            }
+        let (first_sig_opt, second_sig_opt): ProofOfAgreement =
+            agreement.proof_of_agreement.clone().unwrap_or((None, None));
+        // The initiator always signs first, whoever else agrees signs in the counterparty's place
+        let initiator = agreement.is_initiator(&party.identity);
+        if initiator {
+            agreement.proof_of_agreement = Some((Some(signature), second_sig_opt));
+        } else {
+            agreement.proof_of_agreement = Some((first_sig_opt, Some(signature)));
         }
+        store_key(&mut agreement, public_key, initiator);
         agreement
     }
 }
+
+fn sign_agreement(
+    agreement: &Agreement,
+    private_key: &PrivateKey,
+    public_key: &PublicKey,
+) -> lamport::Signature {
+    let message_hash = agreement.security_level().digest(&agreement.message());
+    if agreement.has_compact_keys() {
+        sign_compact(message_hash, private_key, public_key)
+    } else {
+        sign(message_hash, private_key)
+    }
+}
+
+/// Stores the signer's public key in their slot, or only the commitment to it
+/// on agreements with compact keys.
+fn store_key(agreement: &mut Agreement, public_key: PublicKey, initiator: bool) {
+    match agreement.key_commitments.clone() {
+        Some((first, second)) => {
+            let commitment = Some(key_commitment(&public_key));
+            agreement.key_commitments = Some(if initiator {
+                (commitment, second)
+            } else {
+                (first, commitment)
+            });
+        }
+        None => {
+            let (first, second) = agreement.public_keys.clone().unwrap_or((None, None));
+            agreement.public_keys = Some(if initiator {
+                (Some(public_key), second)
+            } else {
+                (first, Some(public_key))
+            });
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use crate::agreement::Agreement;
+use crate::delegation::DelegatedSignature;
+use crate::dispute::verify_ruling;
+use crate::lamport::{verify, verify_compact};
+use crate::signature::SignerMetadata;
+use crate::timestamp::Timestamp;
+use crate::witness::{attestation_message, WitnessRole};
+use crate::Error;
+
+/// Agreements verified in one batch call.
+pub const MAX_VERIFY_BATCH: usize = 20;
+
+/// The outcome of checking an agreement. Party signatures and witness
+/// attestations are reported separately since witnesses are not parties.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct VerificationReport {
+    pub parties_valid: bool,
+    pub witnesses: Vec<WitnessVerification>,
+    /// Whether revealed terms match the signed commitment. Missing when the
+    /// agreement has no commitment or its terms have not been revealed.
+    pub terms_match_commitment: Option<bool>,
+    /// How each party's signature was made, the initiator first.
+    pub parties: Option<Vec<PartySignature>>,
+    /// Whether the arbitrator's ruling verifies. Missing until there is one.
+    pub ruling_valid: Option<bool>,
+}
+
+/// What is recorded about a party's signature. The times and metadata are
+/// missing on signatures made before they were recorded.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct PartySignature {
+    pub identity: String,
+    pub signed: bool,
+    pub signed_at: Option<Timestamp>,
+    pub delegation: Option<DelegatedSignature>,
+    pub metadata: Option<SignerMetadata>,
+}
+
+/// The report stored with an agreement once its last signature or attestation
+/// is added, so that later checks do not verify the signatures again. Every
+/// change to the signatures, attestations, revealed terms or ruling stores a
+/// new report, and a restore verifies every agreement again.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct CachedVerification {
+    pub report: VerificationReport,
+    pub verified_at: u64,
+    /// The digest the parties signed when the report was made. Reports cached
+    /// before it was recorded lack it and are verified again.
+    pub digest: Option<String>,
+}
+
+impl CachedVerification {
+    pub fn new(agreement: &Agreement, report: VerificationReport, verified_at: u64) -> Self {
+        CachedVerification {
+            report,
+            verified_at,
+            digest: Some(agreement.security_level().digest(&agreement.message())),
+        }
+    }
+
+    /// Whether the report was made for the agreement's current message and
+    /// signatures. Only the digest is hashed, so a cache hit costs a fraction
+    /// of verifying the signatures.
+    pub fn is_current(&self, agreement: &Agreement) -> bool {
+        agreement.is_fully_signed()
+            && self.digest.as_deref()
+                == Some(&agreement.security_level().digest(&agreement.message()))
+    }
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct WitnessVerification {
+    pub identity: String,
+    pub role: WitnessRole,
+    pub attested: bool,
+    pub valid: bool,
+}
+
+/// The cached report if it was made for the agreement as it is, otherwise a
+/// fresh verification.
+pub fn cached_or_verify(agreement: &Agreement) -> Result<VerificationReport, Error> {
+    match &agreement.verification {
+        // Reports cached before the signer metadata was reported lack it
+        Some(cached) if cached.is_current(agreement) => Ok(VerificationReport {
+            parties: Some(party_signatures(agreement)),
+            ..cached.report.clone()
+        }),
+        _ => verify_agreement(agreement),
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// How many times signatures were verified, so that tests can tell a cache hit.
+    static VERIFICATIONS: std::cell::Cell<u32> = std::cell::Cell::new(0);
+}
+
+fn party_signatures(agreement: &Agreement) -> Vec<PartySignature> {
+    let (first, second) = agreement.proof_of_agreement.clone().unwrap_or((None, None));
+    [(&agreement.by_user, first), (&agreement.with_user, second)]
+        .into_iter()
+        .map(|(party, signature)| PartySignature {
+            identity: party.identity.clone(),
+            signed: signature.is_some(),
+            signed_at: signature
+                .as_ref()
+                .and_then(|signature| signature.signed_at.clone()),
+            delegation: signature
+                .as_ref()
+                .and_then(|signature| signature.delegation.clone()),
+            metadata: signature.and_then(|signature| signature.metadata),
+        })
+        .collect()
+}
+
+pub fn verify_agreement(agreement: &Agreement) -> Result<VerificationReport, Error> {
+    #[cfg(test)]
+    VERIFICATIONS.with(|count| count.set(count.get() + 1));
+    let (signature1, signature2) = match agreement.proof_of_agreement.clone() {
+        Some((Some(signature1), Some(signature2))) => (signature1, signature2),
+        _ => return Err(Error::NotFound {
+            msg: format!("The agreement has only one signature hence it cannot be verified since the other person has not signed"),
+        }),
+    };
+    let message = agreement.security_level().digest(&agreement.message());
+    let (signature_one_is_valid, signature_two_is_valid) =
+        match (&agreement.key_commitments, &agreement.public_keys) {
+            (Some((Some(commitment1), Some(commitment2))), _) => (
+                verify_compact(message.clone(), &signature1.value, commitment1),
+                verify_compact(message.clone(), &signature2.value, commitment2),
+            ),
+            (None, Some((Some(key1), Some(key2)))) => (
+                verify(message.clone(), &signature1.value, key1),
+                verify(message.clone(), &signature2.value, key2),
+            ),
+            _ => {
+                return Err(Error::NotFound {
+                    msg: format!("The public keys of the agreement are missing"),
+                })
+            }
+        };
+
+    let mut witnesses = vec![];
+    let attested_message = attestation_message(agreement);
+    for witness in agreement.witnesses.iter().flatten() {
+        let valid = match (&witness.attestation, &attested_message) {
+            (Some(attestation), Some(message)) => {
+                let digest = agreement.security_level().digest(message);
+                verify(digest, &attestation.value, &attestation.public_key)
+            }
+            _ => false,
+        };
+        witnesses.push(WitnessVerification {
+            identity: witness.user.identity.clone(),
+            role: witness.role.clone(),
+            attested: witness.attestation.is_some(),
+            valid,
+        });
+    }
+
+    let terms_match_commitment = agreement.commitment.as_ref().and_then(|commitment| {
+        commitment
+            .reveal
+            .as_ref()
+            .map(|reveal| commitment.matches(&reveal.terms, &reveal.salt))
+    });
+
+    Ok(VerificationReport {
+        parties_valid: signature_one_is_valid && signature_two_is_valid,
+        witnesses,
+        terms_match_commitment,
+        parties: Some(party_signatures(agreement)),
+        ruling_valid: verify_ruling(agreement),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agreement::KeyCommitments;
+    use crate::lamport::SecurityLevel;
+    use crate::signature::{KeyOrigin, SignatureScheme};
+    use crate::user::{Agree, CreateAgreement, User};
+    use ic_stable_structures::Storable;
+
+    fn signed() -> Agreement {
+        signed_with(None)
+    }
+
+    fn signed_with(key_commitments: Option<KeyCommitments>) -> Agreement {
+        let alice = User {
+            identity: String::from("alice"),
+        };
+        let bob = User {
+            identity: String::from("bob"),
+        };
+        let offer = alice.clone().new_agreement(
+            vec!["Alice rents Bob her flat".to_string()],
+            String::from("0"),
+            bob.clone(),
+            alice.clone(),
+            5,
+        );
+        let offer = Agreement {
+            key_commitments,
+            ..offer
+        };
+        bob.agree(alice.automatic_agreement(offer))
+    }
+
+    #[test]
+    fn cached_reports_only_vouch_for_the_signatures_they_checked() {
+        let agreement = signed();
+        let report = verify_agreement(&agreement).unwrap();
+        assert!(report.parties_valid);
+        let cached = Agreement {
+            verification: Some(CachedVerification::new(&agreement, report.clone(), 1)),
+            ..agreement.clone()
+        };
+        let before = VERIFICATIONS.with(|count| count.get());
+        assert!(cached_or_verify(&cached).unwrap().parties_valid);
+        assert_eq!(VERIFICATIONS.with(|count| count.get()), before);
+
+        // A report with the signatures gone is verified again, and fails
+        let stripped = Agreement {
+            proof_of_agreement: None,
+            ..cached.clone()
+        };
+        assert!(cached_or_verify(&stripped).is_err());
+
+        // So is a report made for other terms
+        let mut changed = cached.clone();
+        changed.terms.push(String::from("Bob may sublet"));
+        assert!(!changed.verification.as_ref().unwrap().is_current(&changed));
+
+        // And one cached without a digest
+        let unbound = Agreement {
+            verification: Some(CachedVerification {
+                digest: None,
+                ..CachedVerification::new(&agreement, report, 1)
+            }),
+            ..agreement
+        };
+        let before = VERIFICATIONS.with(|count| count.get());
+        assert!(cached_or_verify(&unbound).unwrap().parties_valid);
+        assert_eq!(VERIFICATIONS.with(|count| count.get()), before + 1);
+    }
+
+    #[test]
+    fn reports_how_each_party_signed() {
+        let mut agreement = signed();
+        let metadata = SignerMetadata::new(
+            User {
+                identity: String::from("carol"),
+            },
+            &agreement,
+            Some(String::from("ab")),
+        );
+        agreement.stamp_signature("bob", Timestamp::new(7), metadata);
+        let parties = verify_agreement(&agreement).unwrap().parties.unwrap();
+        assert_eq!(parties.len(), 2);
+        assert_eq!(parties[0].identity, "alice");
+        assert!(parties[0].signed && parties[0].metadata.is_none());
+
+        let bob = &parties[1];
+        assert_eq!(bob.identity, "bob");
+        assert_eq!(bob.signed_at, Some(Timestamp::new(7)));
+        let metadata = bob.metadata.clone().unwrap();
+        assert_eq!(metadata.signer.identity, "carol");
+        assert_eq!(metadata.module_hash, Some(String::from("ab")));
+        assert_eq!(metadata.key_origin, KeyOrigin::Server);
+        assert_eq!(
+            metadata.scheme,
+            SignatureScheme::Lamport {
+                security_level: SecurityLevel::Bits128,
+                key_pairs: 256,
+                compact_keys: false,
+            }
+        );
+    }
+
+    #[test]
+    fn compact_keys_verify_without_storing_the_public_keys() {
+        let agreement = signed_with(Some((None, None)));
+        assert!(agreement.public_keys.is_none());
+        assert!(matches!(
+            agreement.key_commitments,
+            Some((Some(_), Some(_)))
+        ));
+        assert!(verify_agreement(&agreement).unwrap().parties_valid);
+        assert!(agreement.to_bytes().len() < signed().to_bytes().len());
+
+        let swapped = Agreement {
+            key_commitments: agreement
+                .key_commitments
+                .clone()
+                .map(|(first, second)| (second, first)),
+            ..agreement
+        };
+        assert!(!verify_agreement(&swapped).unwrap().parties_valid);
+    }
+}
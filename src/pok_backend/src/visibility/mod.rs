@@ -0,0 +1,109 @@
+use crate::user::User;
+use crate::Error;
+
+const MAX_VIEWERS: usize = 32;
+
+/// Who can read an agreement. Witnesses can always read the agreements they attest.
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum Visibility {
+    Parties,
+    PartiesAndViewers,
+    Public,
+}
+
+/// Trims and de-duplicates viewers, who must not already be parties.
+pub fn normalize_viewers(
+    viewers: Vec<String>,
+    parties: [&User; 2],
+) -> Result<Option<Vec<User>>, Error> {
+    let mut normalized: Vec<User> = vec![];
+    for viewer in viewers.into_iter() {
+        let identity = viewer.trim().to_string();
+        if identity.is_empty() {
+            return Err(Error::InvalidInput {
+                msg: format!("A viewer cannot be empty"),
+            });
+        }
+        if parties
+            .iter()
+            .any(|party| party.identity.trim() == identity)
+        {
+            return Err(Error::InvalidInput {
+                msg: format!("{} is a party and can already read the agreement", identity),
+            });
+        }
+        if !normalized.iter().any(|user| user.identity == identity) {
+            normalized.push(User { identity });
+        }
+    }
+    if normalized.len() > MAX_VIEWERS {
+        return Err(Error::InvalidInput {
+            msg: format!("An agreement can have at most {} viewers", MAX_VIEWERS),
+        });
+    }
+    Ok(if normalized.is_empty() {
+        None
+    } else {
+        Some(normalized)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agreement::Agreement;
+    use crate::user::CreateAgreement;
+
+    fn user(identity: &str) -> User {
+        User {
+            identity: identity.to_string(),
+        }
+    }
+
+    fn agreement(visibility: Option<Visibility>, viewers: Option<Vec<User>>) -> Agreement {
+        let agreement = user("alice").new_agreement(
+            vec!["Alice lends Bob her bicycle".to_string()],
+            String::from("0"),
+            user("bob"),
+            user("alice"),
+            3,
+        );
+        Agreement {
+            visibility,
+            viewers,
+            ..agreement
+        }
+    }
+
+    #[test]
+    fn agreements_are_private_to_their_parties_by_default() {
+        let agreement = agreement(None, Some(vec![user("carol")]));
+        assert!(agreement.can_read("alice"));
+        assert!(agreement.can_read("bob"));
+        assert!(!agreement.can_read("carol"));
+    }
+
+    #[test]
+    fn listed_viewers_can_read_and_public_agreements_are_open_to_all() {
+        let shared = agreement(
+            Some(Visibility::PartiesAndViewers),
+            Some(vec![user("carol")]),
+        );
+        assert!(shared.can_read("carol"));
+        assert!(!shared.can_read("dave"));
+        assert!(agreement(Some(Visibility::Public), None).can_read("dave"));
+    }
+
+    #[test]
+    fn viewers_are_deduplicated_and_cannot_be_parties() {
+        let (alice, bob) = (user("alice"), user("bob"));
+        let viewers = normalize_viewers(
+            vec![String::from(" carol"), String::from("carol")],
+            [&alice, &bob],
+        )
+        .unwrap();
+        assert_eq!(viewers.map(|viewers| viewers.len()), Some(1));
+        assert!(normalize_viewers(vec![String::from("bob")], [&alice, &bob]).is_err());
+        assert!(normalize_viewers(vec![String::from(" ")], [&alice, &bob]).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::agreement::Agreement;
+use crate::lamport::{create_public_key, hash, random_private_key, sign, PublicKey, Signature};
+use crate::timestamp::Timestamp;
+use crate::user::User;
+use crate::Error;
+
+/// Prefix mixed into a witness's key derivation so that an attestation key can
+/// never coincide with the key the same principal would sign with as a party.
+const WITNESS_KEY_DOMAIN: &str = "witness:";
+
+#[derive(Clone, Debug, PartialEq, candid::CandidType, Serialize, Deserialize)]
+pub enum WitnessRole {
+    Witness,
+    Notary,
+}
+
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct WitnessRequest {
+    pub identity: String,
+    pub role: WitnessRole,
+}
+
+/// A principal who co-signs an agreement without becoming a party to it.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Witness {
+    pub user: User,
+    pub role: WitnessRole,
+    pub attestation: Option<Attestation>,
+}
+
+/// A witness's signature over the agreement digest and both party signatures.
+#[derive(Clone, Debug, candid::CandidType, Serialize, Deserialize)]
+pub struct Attestation {
+    pub value: Signature,
+    pub public_key: PublicKey,
+    pub attested_at: Option<Timestamp>,
+}
+
+pub fn from_requests(
+    requests: Vec<WitnessRequest>,
+    agreement: &Agreement,
+) -> Result<Option<Vec<Witness>>, Error> {
+    if requests.is_empty() {
+        return Ok(None);
+    }
+    let mut witnesses: Vec<Witness> = Vec::with_capacity(requests.len());
+    for request in requests.into_iter() {
+        let identity = request.identity.trim().to_string();
+        if identity == agreement.by_user.identity.trim()
+            || identity == agreement.with_user.identity.trim()
+        {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "{} is a party and cannot also witness the agreement",
+                    identity
+                ),
+            });
+        }
+        if witnesses.iter().any(|w| w.user.identity == identity) {
+            return Err(Error::InvalidInput {
+                msg: format!("{} is named as a witness more than once", identity),
+            });
+        }
+        witnesses.push(Witness {
+            user: User { identity },
+            role: request.role,
+            attestation: None,
+        });
+    }
+    Ok(Some(witnesses))
+}
+
+/// The message a witness signs, or `None` while a party signature is missing.
+pub fn attestation_message(agreement: &Agreement) -> Option<String> {
+    let (first, second) = agreement.proof_of_agreement.clone()?;
+    let (first, second) = (first?, second?);
+    let mut message = hash(&agreement.message());
+    for signature in [first, second].iter() {
+        for part in signature.value.parts() {
+            message.push_str(part);
+        }
+    }
+    Some(message)
+}
+
+pub fn attest(witness: &User, agreement: &Agreement) -> Option<Attestation> {
+    let message = attestation_message(agreement)?;
+    let private_key = random_private_key(
+        format!("{}{}", WITNESS_KEY_DOMAIN, witness.identity),
+        agreement.clone(),
+    );
+    Some(Attestation {
+        value: sign(agreement.security_level().digest(&message), &private_key),
+        public_key: create_public_key(&private_key),
+        attested_at: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::{Agree, CreateAgreement};
+    use crate::verification::verify_agreement;
+
+    fn signed_agreement() -> Agreement {
+        let creator = User {
+            identity: String::from("amschel"),
+        };
+        let agreement = creator.clone().new_agreement(
+            vec!["Witnessed terms".to_string()],
+            String::from("0"),
+            User {
+                identity: String::from("bob"),
+            },
+            User {
+                identity: String::from("alice"),
+            },
+            1,
+        );
+        let agreement = Agreement {
+            witnesses: from_requests(
+                vec![WitnessRequest {
+                    identity: String::from("carol"),
+                    role: WitnessRole::Notary,
+                }],
+                &agreement,
+            )
+            .unwrap(),
+            ..agreement
+        };
+        let agreement = creator.automatic_agreement(agreement);
+        User {
+            identity: String::from("bob"),
+        }
+        .agree(agreement)
+    }
+
+    #[test]
+    fn attestation_is_reported_separately() {
+        let mut agreement = signed_agreement();
+        let report = verify_agreement(&agreement).unwrap();
+        assert!(report.parties_valid);
+        assert!(!report.witnesses[0].attested);
+        assert!(!report.witnesses[0].valid);
+
+        let carol = agreement.witnesses.clone().unwrap()[0].user.clone();
+        agreement.witnesses.as_mut().unwrap()[0].attestation = attest(&carol, &agreement);
+        let report = verify_agreement(&agreement).unwrap();
+        assert!(report.parties_valid);
+        assert!(report.witnesses[0].attested);
+        assert!(report.witnesses[0].valid);
+
+        agreement.terms.push("Sneaky extra term".to_string());
+        let report = verify_agreement(&agreement).unwrap();
+        assert!(!report.parties_valid);
+        assert!(!report.witnesses[0].valid);
+    }
+
+    #[test]
+    fn parties_cannot_witness() {
+        let agreement = signed_agreement();
+        let request = WitnessRequest {
+            identity: String::from("alice"),
+            role: WitnessRole::Witness,
+        };
+        assert!(from_requests(vec![request], &agreement).is_err());
+    }
+}